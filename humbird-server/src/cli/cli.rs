@@ -4,6 +4,50 @@ use clap::*;
 #[command(name = "Humbird", author = "HappyBoy", version = "0.1.0",about="You Know, for Faster! ", long_about=None)]
 #[command(next_line_help = true)]
 pub struct Cli {
-    #[arg(long, short, help = "server port (default: 9999).")]
-    port: Option<String>,
+    #[arg(long, short, global = true, default_value = "config-template.toml", help = "configuration file.")]
+    pub config: String,
+    #[arg(
+        long,
+        short,
+        global = true,
+        value_name = "ADDRESS",
+        help = "listening address, an ip or ip:port, e.g. 127.0.0.1:8080 or [::]:8080."
+    )]
+    pub bind: Option<String>,
+    #[arg(long, short, global = true, value_name = "DIR", help = "directory of the static resources served, refused when the configuration has [[directory]] entries.")]
+    pub root: Option<String>,
+    #[arg(long, short, global = true, value_name = "N", help = "worker threads of the runtime.")]
+    pub workers: Option<u32>,
+    #[arg(
+        long,
+        short,
+        global = true,
+        value_name = "LEVEL",
+        value_parser = ["error", "warn", "info", "debug", "trace"],
+        help = "most verbose level logged."
+    )]
+    pub log_level: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "KEY=VALUE",
+        help = "setting applied over the configuration file and the HUMBIRD_* environment variables, e.g. server.port=8080, may be repeated."
+    )]
+    pub set: Vec<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// run the server, the default command
+    Serve,
+    /// validate the configuration file and report its problems with their line and column
+    CheckConfig,
+    /// print the configuration template with every setting documented
+    PrintDefaultConfig,
+    /// ask the running server named by the pid-file setting to reload its configuration
+    Reload,
+    /// ask the running server named by the pid-file setting to stop
+    Stop,
 }
@@ -1,27 +1,128 @@
 use clap::Parser;
-use cli::cli::Cli;
-use humbird::{
-    core::server::Server,
-    protocol::http::{Request, Response},
-    register_router_plugin,
+use cli::cli::{Cli, Command};
+use humbird::core::{
+    config::{read_config, set_overrides},
+    pid,
+    server::{Server, DEFAULT_PID_FILE},
 };
+use std::{net::IpAddr, net::SocketAddr, path::Path, process::ExitCode};
 
 mod cli;
 
-fn test(req: Request, mut res: Response) -> Response {
-    res
+/// the configuration template
+const DEFAULT_CONFIG: &str = include_str!("config-template.toml");
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match overrides(&cli) {
+        Ok(o) => set_overrides(o),
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            Server::config_run(&cli.config);
+            ExitCode::SUCCESS
+        }
+        Command::CheckConfig => check_config(&cli.config),
+        Command::PrintDefaultConfig => {
+            print!("{}", DEFAULT_CONFIG);
+            ExitCode::SUCCESS
+        }
+        Command::Reload => reload(&cli.config),
+        Command::Stop => stop(&cli.config),
+    }
 }
 
-fn main() {
-    // cli
-    //let cli = Cli::parse();
+/// the settings the command line options apply over the configuration, `--set` ones last
+fn overrides(cli: &Cli) -> Result<Vec<(String, String)>, String> {
+    let mut overrides = vec![];
+    if let Some(ref bind) = cli.bind {
+        match (bind.parse::<SocketAddr>(), bind.parse::<IpAddr>()) {
+            (Ok(a), _) => {
+                overrides.push(("server.address".to_string(), format!("{:?}", a.ip().to_string())));
+                overrides.push(("server.port".to_string(), a.port().to_string()));
+            }
+            (_, Ok(ip)) => overrides.push(("server.address".to_string(), format!("{:?}", ip.to_string()))),
+            _ => return Err(format!("--bind {}: expected an ip or ip:port", bind)),
+        }
+    }
+    if let Some(ref root) = cli.root {
+        overrides.push(("directory.root-path".to_string(), format!("{:?}", root)));
+    }
+    if let Some(workers) = cli.workers {
+        overrides.push(("server.workers".to_string(), workers.to_string()));
+    }
+    if let Some(ref level) = cli.log_level {
+        overrides.push(("log.level".to_string(), format!("{:?}", level)));
+    }
+    for s in cli.set.iter() {
+        match s.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => overrides.push((k.trim().to_string(), v.to_string())),
+            _ => return Err(format!("--set {}: expected KEY=VALUE", s)),
+        }
+    }
+    Ok(overrides)
+}
 
-    register_router_plugin!(
-        "/"=>test
-    );
+/// validate a configuration file, the exit code is 1 when it has problems
+fn check_config(path: &str) -> ExitCode {
+    match read_config(Path::new(path)) {
+        Ok(_) => {
+            println!("{}: configuration is valid", path);
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("{}", e);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// validate the configuration, then ask the running server to reload it
+fn reload(path: &str) -> ExitCode {
+    let config = match read_config(Path::new(path)) {
+        Ok(c) => c,
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("{}", e);
+            }
+            eprintln!("configuration not reloaded");
+            return ExitCode::FAILURE;
+        }
+    };
+    let pid_file = config.server.pid_file.unwrap_or(DEFAULT_PID_FILE.to_string());
+    match pid::reload(&pid_file) {
+        Ok(pid) => {
+            println!("humbird with pid {} asked to reload {}", pid, path);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    Server::config_run(
-        humbird::core::server::NetModel::EventPoll,
-        "config-template.toml",
-    );
+/// ask the running server to stop and wait for it to exit
+fn stop(path: &str) -> ExitCode {
+    // a server runs whatever problems its configuration file has since it started
+    let pid_file = match read_config(Path::new(path)) {
+        Ok(c) => c.server.pid_file.unwrap_or(DEFAULT_PID_FILE.to_string()),
+        Err(_) => DEFAULT_PID_FILE.to_string(),
+    };
+    match pid::stop(&pid_file) {
+        Ok(pid) => {
+            println!("humbird with pid {} stopped", pid);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,335 @@
+/// access log, one line per answered request, in common log format, combined log format,
+/// json or a template, written under the `access` target or to a file of its own
+use chrono::Local;
+use lazy_static::lazy_static;
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+use tracing_appender::non_blocking::NonBlocking;
+
+use crate::{
+    core::{
+        balancer::random_u64,
+        log::{self, LogSettings},
+        settings,
+    },
+    protocol::{autoindex::json_escape, http::Request},
+};
+
+/// template of the common log format
+pub const COMMON: &str = "$client - - [$time] \"$request\" $status $bytes";
+/// template of the combined log format
+pub const COMBINED: &str = "$client - - [$time] \"$request\" $status $bytes \"$referer\" \"$user_agent\"";
+
+lazy_static! {
+    /// writer of the access log file, `None` when the lines go to the log
+    static ref WRITER: Mutex<Option<NonBlocking>> = Mutex::new(None);
+}
+
+/// a field of an access log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// client address, the PROXY protocol source when there is one
+    CLIENT,
+    /// local time the line is written, `10/Oct/2000:13:55:36 +0200`
+    TIME,
+    METHOD,
+    /// path and query string
+    URI,
+    PROTOCOL,
+    /// request line, `GET /index.html HTTP/1.1`
+    REQUEST,
+    STATUS,
+    /// bytes sent to the client, head included
+    BYTES,
+    /// milliseconds from the request to the end of the response
+    LATENCY,
+    /// address of the upstream that answered a proxied request
+    UPSTREAM,
+    REFERER,
+    USERAGENT,
+    HOST,
+}
+
+impl Field {
+    /// fields by template variable name, in json object order
+    pub const ALL: [(&'static str, Field); 13] = [
+        ("time", Field::TIME),
+        ("client", Field::CLIENT),
+        ("host", Field::HOST),
+        ("method", Field::METHOD),
+        ("uri", Field::URI),
+        ("protocol", Field::PROTOCOL),
+        ("request", Field::REQUEST),
+        ("status", Field::STATUS),
+        ("bytes", Field::BYTES),
+        ("latency", Field::LATENCY),
+        ("upstream", Field::UPSTREAM),
+        ("referer", Field::REFERER),
+        ("user_agent", Field::USERAGENT),
+    ];
+}
+
+/// a part of a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    TEXT(String),
+    FIELD(Field),
+}
+
+/// access log line format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessFormat {
+    /// text with `$field` variables
+    TEMPLATE(Vec<Part>),
+    /// a json object of every field per line
+    JSON,
+}
+
+impl Default for AccessFormat {
+    fn default() -> Self {
+        AccessFormat::parse("common").unwrap()
+    }
+}
+
+impl AccessFormat {
+    /// `common`, `combined`, `json` or a template whose `$field` variables are replaced,
+    /// `$$` stands for `$`
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::access_log::AccessFormat;
+    /// assert!(AccessFormat::parse("combined").is_ok());
+    /// assert!(AccessFormat::parse("$client $status ${latency}ms").is_ok());
+    /// assert_eq!(AccessFormat::parse("$client $size").unwrap_err(), "unknown access log field $size");
+    /// ```
+    pub fn parse(format: &str) -> Result<AccessFormat, String> {
+        let template = match format.trim().to_ascii_lowercase().as_str() {
+            "json" => return Ok(AccessFormat::JSON),
+            "common" => COMMON,
+            "combined" => COMBINED,
+            _ => format,
+        };
+        let mut parts = vec![];
+        let mut text = String::default();
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            text.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(r) = rest.strip_prefix('$') {
+                text.push('$');
+                rest = r;
+                continue;
+            }
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(r) => match r.find('}') {
+                    Some(end) => (&r[..end], &r[end + 1..]),
+                    None => return Err("unclosed ${ in the access log format".to_string()),
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            let field = match Field::ALL.iter().find(|(n, _)| *n == name) {
+                Some((_, f)) => *f,
+                None => return Err(format!("unknown access log field ${}", name)),
+            };
+            if !text.is_empty() {
+                parts.push(Part::TEXT(std::mem::take(&mut text)));
+            }
+            parts.push(Part::FIELD(field));
+            rest = after;
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::TEXT(text));
+        }
+        Ok(AccessFormat::TEMPLATE(parts))
+    }
+}
+
+/// access log settings
+#[derive(Debug, Clone)]
+pub struct AccessLogSettings {
+    pub enable: bool,
+    pub format: AccessFormat,
+    /// percentage of the requests logged
+    pub percent: f64,
+    /// percentage of the requests logged by path prefix, the longest matching prefix applies
+    pub sample: Vec<(String, f64)>,
+    /// file of the access log, the lines go to the log under the `access` target when `None`
+    pub file: Option<LogSettings>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            enable: true,
+            format: AccessFormat::default(),
+            percent: 100.0,
+            sample: vec![],
+            file: None,
+        }
+    }
+}
+
+impl AccessLogSettings {
+    /// percentage of the requests of a path logged
+    pub fn percent(&self, path: &str) -> f64 {
+        self.sample
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.percent, |(_, p)| *p)
+    }
+}
+
+/// an answered request
+pub struct Entry<'a> {
+    pub request: &'a Request,
+    pub status: &'a str,
+    /// `None` when the size of the response is not known
+    pub bytes: Option<u64>,
+    pub upstream: Option<&'a str>,
+}
+
+impl Entry<'_> {
+    /// value of a field, `None` when it is unknown
+    fn value(&self, field: Field) -> Option<String> {
+        let r = self.request;
+        let uri = || match r.query() {
+            "" => r.path().to_string(),
+            q => format!("{}?{}", r.path(), q),
+        };
+        let value = match field {
+            Field::CLIENT => r.client()?.ip().to_string(),
+            Field::TIME => Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            Field::METHOD => r.method().as_str().to_string(),
+            Field::URI => uri(),
+            Field::PROTOCOL => r.protocol().to_string(),
+            Field::REQUEST => format!("{} {} {}", r.method().as_str(), uri(), r.protocol()),
+            Field::STATUS => self.status.to_string(),
+            Field::BYTES => self.bytes?.to_string(),
+            Field::LATENCY => format!("{:.3}", r.received().elapsed().as_secs_f64() * 1000.0),
+            Field::UPSTREAM => self.upstream?.to_string(),
+            Field::REFERER => r.head("Referer")?.to_string(),
+            Field::USERAGENT => r.head("User-Agent")?.to_string(),
+            Field::HOST => r.head("Host")?.to_string(),
+        };
+        Some(value)
+    }
+    /// the log line of the request
+    pub fn format(&self, format: &AccessFormat) -> String {
+        match format {
+            AccessFormat::TEMPLATE(parts) => parts
+                .iter()
+                .map(|p| match p {
+                    Part::TEXT(t) => t.to_string(),
+                    // quotes and backslashes are escaped so that quoted fields stay parseable
+                    Part::FIELD(f) => match self.value(*f) {
+                        Some(v) => v.replace('\\', "\\\\").replace('"', "\\\""),
+                        None => "-".to_string(),
+                    },
+                })
+                .collect(),
+            AccessFormat::JSON => {
+                let fields: Vec<String> = Field::ALL
+                    .iter()
+                    .map(|(name, f)| match (self.value(*f), f) {
+                        (None, _) => format!("\"{}\":null", name),
+                        (Some(v), Field::STATUS | Field::BYTES | Field::LATENCY) if v.parse::<f64>().is_ok() => {
+                            format!("\"{}\":{}", name, v)
+                        }
+                        (Some(v), _) => format!("\"{}\":\"{}\"", name, json_escape(&v)),
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    }
+}
+
+/// open the access log file of the settings, once the log system runs
+pub fn init() {
+    if let Some(settings) = settings::current().access_log.file.clone() {
+        match settings.appender() {
+            Ok(appender) => {
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                *WRITER.lock().unwrap() = Some(writer);
+                log::keep(guard);
+            }
+            Err(e) => eprintln!("access {}", e),
+        }
+    }
+}
+
+/// log an answered request when it is sampled, the client is the PROXY protocol source
+/// when there is one, `bytes` is `None` when the size of the response is not known and
+/// `upstream` the upstream that answered a proxied request
+pub fn log(request: &Request, status: &str, bytes: Option<u64>, upstream: Option<&str>) {
+    let settings = settings::current();
+    let s = &settings.access_log;
+    if !s.enable || (random_u64() % 10000) as f64 >= s.percent(request.path()) * 100.0 {
+        return;
+    }
+    let entry = Entry {
+        request,
+        status,
+        bytes,
+        upstream,
+    };
+    let line = entry.format(&s.format);
+    match WRITER.lock().unwrap().as_mut() {
+        Some(w) => {
+            let _ = w.write_all(format!("{}\n", line).as_bytes());
+        }
+        None => info!(target: "access", "{}", line),
+    }
+}
+
+/// a client connection counting the bytes written to it
+pub struct Counted<S> {
+    inner: S,
+    written: u64,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S) -> Self {
+        Counted { inner, written: 0 }
+    }
+    /// bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.written += n as u64;
+        }
+        poll
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
@@ -0,0 +1,446 @@
+/// load balancing over a group of upstream servers
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tracing::warn;
+
+use crate::{
+    core::{
+        breaker::{Admission, Circuit, CircuitBreaker},
+        health::{HealthCheck, OutlierDetection, UpstreamHealth},
+        proxy::BalancingMode,
+        sticky::{rendezvous, Affinity, StickySession},
+    },
+    protocol::http::Request,
+};
+
+/// virtual nodes per unit of weight on the consistent hash ring
+const HASH_RING_REPLICAS: u32 = 160;
+/// largest number of points on the consistent hash ring, the virtual nodes of the
+/// upstreams are scaled down in proportion beyond it
+const HASH_RING_MAX_POINTS: u64 = 1 << 20;
+
+/// largest weight of an upstream
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// upstream server abstract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    /// `host:port`
+    pub address: String,
+    /// relative weight, used by the weight, random, least connections and hash modes
+    pub weight: u32,
+}
+
+impl Upstream {
+    pub fn new(address: &str, weight: u32) -> Self {
+        Upstream {
+            address: address.to_string(),
+            weight: weight.max(1),
+        }
+    }
+    /// host and port of the address
+    pub fn host_port(&self) -> (&str, &str) {
+        match self.address.rsplit_once(':') {
+            Some((h, p)) => (h, p),
+            None => (self.address.as_str(), "80"),
+        }
+    }
+}
+
+/// request attribute hashed by the consistent hash mode
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum HashKey {
+    /// request path
+    #[default]
+    PATH,
+    /// value of a request header
+    HEADER(String),
+}
+
+impl HashKey {
+    pub fn new(m: &str) -> Self {
+        match m.split_once(':') {
+            Some((k, h)) if k.trim().eq_ignore_ascii_case("header") => {
+                HashKey::HEADER(h.trim().to_string())
+            }
+            _ => HashKey::PATH,
+        }
+    }
+    /// the hashed value of a request
+    pub fn value(&self, request: &Request) -> String {
+        match self {
+            HashKey::PATH => request.path().to_string(),
+            HashKey::HEADER(h) => request.head(h).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// a group of upstream servers and the state of its balancing mode
+#[derive(Debug, Default)]
+pub struct UpstreamGroup {
+    pub mode: BalancingMode,
+    pub upstreams: Vec<Upstream>,
+    pub hash_key: HashKey,
+    /// active health check, `None` disables it
+    pub health_check: Option<HealthCheck>,
+    /// passive outlier detection, `None` disables it
+    pub outlier: Option<OutlierDetection>,
+    /// circuit breaking, `None` disables it
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// session affinity, `None` disables it
+    pub sticky: Option<StickySession>,
+    /// round robin cursor
+    cursor: AtomicUsize,
+    /// current weights of smooth weighted round robin
+    current_weights: Mutex<Vec<i64>>,
+    /// in-flight requests per upstream
+    active: Vec<AtomicUsize>,
+    /// consistent hash ring, sorted by point
+    ring: Vec<(u64, usize)>,
+    /// health state per upstream
+    health: Vec<UpstreamHealth>,
+    /// circuit per upstream
+    circuits: Vec<Circuit>,
+}
+
+/// an upstream chosen for a request, counted as active until dropped
+#[derive(Debug)]
+pub struct Lease {
+    group: Arc<UpstreamGroup>,
+    index: usize,
+    /// a probe request of a half open circuit
+    probe: bool,
+    /// `Set-Cookie` value pinning the client to the upstream
+    affinity_cookie: Option<String>,
+}
+
+impl Lease {
+    /// index of the upstream in its group
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// the chosen upstream
+    pub fn upstream(&self) -> &Upstream {
+        &self.group.upstreams[self.index]
+    }
+    /// affinity cookie to send with the response
+    pub fn affinity_cookie(&self) -> Option<&str> {
+        self.affinity_cookie.as_deref()
+    }
+    /// report the outcome of the request to the outlier detection and the circuit
+    /// breaker of the group
+    pub fn report(&self, ok: bool) {
+        if let Some(ref outlier) = self.group.outlier {
+            if let Some(t) = self.group.health[self.index].request_result(ok, outlier) {
+                warn!("upstream {} ejected for {:?}", self.upstream().address, t);
+            }
+        }
+        if let Some(ref breaker) = self.group.circuit_breaker {
+            if let Some(state) = self.group.circuits[self.index].record(ok, breaker) {
+                warn!("circuit of upstream {} is {:?}", self.upstream().address, state);
+            }
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.group.active[self.index].fetch_sub(1, Ordering::Relaxed);
+        if self.probe {
+            self.group.circuits[self.index].release();
+        }
+    }
+}
+
+impl UpstreamGroup {
+    pub fn new(mode: BalancingMode, upstreams: Vec<Upstream>, hash_key: HashKey) -> Self {
+        let mut ring = vec![];
+        if let BalancingMode::HASH = mode {
+            let replicas = |u: &Upstream| HASH_RING_REPLICAS.saturating_mul(u.weight) as u64;
+            let total: u64 = upstreams.iter().map(replicas).sum();
+            for (i, u) in upstreams.iter().enumerate() {
+                let points = match total > HASH_RING_MAX_POINTS {
+                    true => (replicas(u) * HASH_RING_MAX_POINTS / total).max(1),
+                    false => replicas(u),
+                };
+                for v in 0..points {
+                    ring.push((mix(fnv1a(format!("{}#{}", u.address, v).as_bytes())), i));
+                }
+            }
+            ring.sort();
+        }
+        UpstreamGroup {
+            mode,
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            active: upstreams.iter().map(|_| AtomicUsize::new(0)).collect(),
+            health: upstreams.iter().map(|_| UpstreamHealth::default()).collect(),
+            circuits: upstreams.iter().map(|_| Circuit::default()).collect(),
+            upstreams,
+            hash_key,
+            health_check: None,
+            outlier: None,
+            circuit_breaker: None,
+            sticky: None,
+            cursor: AtomicUsize::new(0),
+            ring,
+        }
+    }
+    /// whether the group has no upstream
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+    /// number of in-flight requests of an upstream
+    pub fn active(&self, index: usize) -> usize {
+        self.active[index].load(Ordering::Relaxed)
+    }
+    /// health state of an upstream
+    pub fn health(&self, index: usize) -> &UpstreamHealth {
+        &self.health[index]
+    }
+    /// circuit of an upstream
+    pub fn circuit(&self, index: usize) -> &Circuit {
+        &self.circuits[index]
+    }
+    /// whether an upstream passes its health check, is not ejected and its circuit lets
+    /// requests through
+    pub fn is_available(&self, index: usize) -> bool {
+        self.health[index].is_available()
+            && match self.circuit_breaker {
+                Some(ref b) => self.circuits[index].allows(b),
+                None => true,
+            }
+    }
+    /// choose an upstream for a request, the one it is pinned to by its session affinity
+    /// if that is available, otherwise according to the balancing mode
+    pub fn acquire(self: &Arc<Self>, request: &Request) -> Option<Lease> {
+        let pinned = self.sticky.as_ref().and_then(|s| s.pinned(self, request));
+        let mut lease = self.admit(pinned, &self.hash_key.value(request))?;
+        if let Some(ref s) = self.sticky {
+            lease.affinity_cookie = s.cookie(request, &self.upstreams[lease.index].address);
+        }
+        Some(lease)
+    }
+    /// choose an upstream for a connection of a stream proxy, the client address is the
+    /// hash key and the only session affinity available
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::{
+    ///     balancer::{HashKey, Upstream, UpstreamGroup},
+    ///     proxy::BalancingMode,
+    /// };
+    /// use std::sync::Arc;
+    /// let upstreams = vec![Upstream::new("10.0.0.1:5432", 1), Upstream::new("10.0.0.2:5432", 1)];
+    /// let group = Arc::new(UpstreamGroup::new(BalancingMode::LEAST, upstreams, HashKey::PATH));
+    /// let client = "192.168.1.7:40000".parse().unwrap();
+    /// // the busy upstream is left alone until its connection is closed
+    /// let first = group.acquire_for(client).unwrap();
+    /// let second = group.acquire_for(client).unwrap();
+    /// assert_ne!(first.index(), second.index());
+    /// let busy = first.index();
+    /// drop(first);
+    /// assert_eq!(group.acquire_for(client).unwrap().index(), busy);
+    /// ```
+    ///
+    /// A half open circuit admits no more concurrent requests than its probe limit
+    /// ```rust
+    /// use humbird::core::{
+    ///     balancer::{HashKey, Upstream, UpstreamGroup},
+    ///     breaker::CircuitBreaker,
+    ///     proxy::BalancingMode,
+    /// };
+    /// use std::{sync::{Arc, Barrier}, time::Duration};
+    /// let breaker = CircuitBreaker {
+    ///     failure_threshold: 1,
+    ///     open_duration: Duration::ZERO,
+    ///     half_open_requests: 1,
+    /// };
+    /// let mut group = UpstreamGroup::new(BalancingMode::POLLING, vec![Upstream::new("10.0.0.1:5432", 1)], HashKey::PATH);
+    /// group.circuit_breaker = Some(breaker.clone());
+    /// group.circuit(0).record(false, &breaker);
+    /// let group = Arc::new(group);
+    /// let barrier = Barrier::new(2);
+    /// let leases: Vec<_> = std::thread::scope(|s| {
+    ///     let acquire = || {
+    ///         barrier.wait();
+    ///         group.acquire_for("192.168.1.7:40000".parse().unwrap())
+    ///     };
+    ///     let (a, b) = (s.spawn(acquire), s.spawn(acquire));
+    ///     vec![a.join().unwrap(), b.join().unwrap()]
+    /// });
+    /// assert_eq!(leases.iter().filter(|l| l.is_some()).count(), 1);
+    /// // the probe slot is free again once the probe has finished
+    /// drop(leases);
+    /// assert!(group.acquire_for("192.168.1.7:40000".parse().unwrap()).is_some());
+    /// ```
+    pub fn acquire_for(self: &Arc<Self>, client: SocketAddr) -> Option<Lease> {
+        let ip = client.ip().to_string();
+        let pinned = match self.sticky {
+            Some(ref s) if s.affinity == Affinity::IP => rendezvous(self, &ip),
+            _ => None,
+        };
+        self.admit(pinned, &ip)
+    }
+    /// lease the pinned upstream or the one selected for the key, an upstream whose circuit
+    /// refuses the request once chosen is no longer available and the choice is made again
+    fn admit(self: &Arc<Self>, pinned: Option<usize>, key: &str) -> Option<Lease> {
+        if let Some(lease) = pinned.and_then(|i| self.lease(i)) {
+            return Some(lease);
+        }
+        for _ in 0..self.upstreams.len() {
+            if let Some(lease) = self.lease(self.select(key)?) {
+                return Some(lease);
+            }
+        }
+        None
+    }
+    /// count a request of an upstream until the lease is dropped, `None` when the circuit
+    /// of the upstream refuses it
+    fn lease(self: &Arc<Self>, index: usize) -> Option<Lease> {
+        let probe = match self.circuit_breaker {
+            Some(ref b) => match self.circuits[index].on_acquire(b) {
+                Admission::REFUSED => return None,
+                Admission::ADMITTED => false,
+                Admission::PROBE => true,
+            },
+            None => false,
+        };
+        self.active[index].fetch_add(1, Ordering::Relaxed);
+        Some(Lease {
+            group: self.clone(),
+            index,
+            probe,
+            affinity_cookie: None,
+        })
+    }
+    /// index of the upstream chosen for a hash key, unavailable upstreams are skipped
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::{
+    ///     balancer::{HashKey, Upstream, UpstreamGroup},
+    ///     breaker::CircuitBreaker,
+    ///     proxy::BalancingMode,
+    /// };
+    /// let upstreams = || vec![Upstream::new("a:80", 5), Upstream::new("b:80", 1), Upstream::new("c:80", 1)];
+    /// // smooth weighted round robin spreads the heavy upstream over the cycle
+    /// let weighted = UpstreamGroup::new(BalancingMode::WEIGHT, upstreams(), HashKey::PATH);
+    /// let cycle: Vec<usize> = (0..7).filter_map(|_| weighted.select("")).collect();
+    /// assert_eq!(cycle, [0, 0, 1, 0, 2, 0, 0]);
+    /// // consistent hashing keeps a key on its upstream and spreads keys by weight
+    /// let mut hashed = UpstreamGroup::new(BalancingMode::HASH, upstreams(), HashKey::PATH);
+    /// hashed.circuit_breaker = Some(CircuitBreaker { failure_threshold: 1, ..Default::default() });
+    /// let keys: Vec<String> = (0..1400).map(|i| format!("/item/{}", i)).collect();
+    /// let before: Vec<usize> = keys.iter().filter_map(|k| hashed.select(k)).collect();
+    /// assert_eq!(keys.iter().filter_map(|k| hashed.select(k)).collect::<Vec<_>>(), before);
+    /// let share = |u: usize| before.iter().filter(|&&i| i == u).count();
+    /// assert!(share(0) > 800 && share(1) > 100 && share(2) > 100);
+    /// // only the keys of an unavailable upstream move
+    /// let breaker = hashed.circuit_breaker.clone().unwrap();
+    /// hashed.circuit(1).record(false, &breaker);
+    /// for (k, &u) in keys.iter().zip(before.iter()) {
+    ///     let after = hashed.select(k).unwrap();
+    ///     assert!(if u == 1 { after != 1 } else { after == u });
+    /// }
+    /// // the ring of huge weights is scaled down to its largest size
+    /// let huge = vec![Upstream::new("a:80", u32::MAX), Upstream::new("b:80", 1)];
+    /// assert!(UpstreamGroup::new(BalancingMode::HASH, huge, HashKey::PATH).select("/").is_some());
+    /// ```
+    pub fn select(&self, key: &str) -> Option<usize> {
+        let available: Vec<usize> = (0..self.upstreams.len())
+            .filter(|&i| self.is_available(i))
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+        match self.mode {
+            BalancingMode::WEIGHT => self.smooth_weighted(&available),
+            BalancingMode::RANDOM => self.random(&available),
+            BalancingMode::POLLING => {
+                Some(available[self.cursor.fetch_add(1, Ordering::Relaxed) % available.len()])
+            }
+            BalancingMode::LEAST => self.least_connections(&available),
+            BalancingMode::HASH => self.consistent_hash(key),
+        }
+    }
+    /// smooth weighted round robin, spreads heavy upstreams evenly over the cycle
+    fn smooth_weighted(&self, available: &[usize]) -> Option<usize> {
+        let mut current = self.current_weights.lock().ok()?;
+        let total: i64 = available.iter().map(|&i| self.upstreams[i].weight as i64).sum();
+        let mut best = available[0];
+        for &i in available {
+            current[i] += self.upstreams[i].weight as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        Some(best)
+    }
+    /// weighted random
+    fn random(&self, available: &[usize]) -> Option<usize> {
+        let total: u64 = available.iter().map(|&i| self.upstreams[i].weight as u64).sum();
+        let mut r = random_u64() % total;
+        for &i in available {
+            let weight = self.upstreams[i].weight as u64;
+            if r < weight {
+                return Some(i);
+            }
+            r -= weight;
+        }
+        available.last().copied()
+    }
+    /// fewest in-flight requests relative to weight, ties are taken in turn
+    fn least_connections(&self, available: &[usize]) -> Option<usize> {
+        let n = available.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        (0..n).map(|o| available[(start + o) % n]).min_by(|&a, &b| {
+            // compare active(a) / weight(a) with active(b) / weight(b)
+            let la = self.active(a) as u64 * self.upstreams[b].weight as u64;
+            let lb = self.active(b) as u64 * self.upstreams[a].weight as u64;
+            la.cmp(&lb)
+        })
+    }
+    /// consistent hashing, a key keeps its upstream while the group is unchanged, the keys
+    /// of an unavailable upstream move on to the next available one on the ring
+    fn consistent_hash(&self, key: &str) -> Option<usize> {
+        let h = mix(fnv1a(key.as_bytes()));
+        let start = self.ring.partition_point(|(p, _)| *p < h);
+        let n = self.ring.len();
+        (0..n)
+            .map(|o| self.ring[(start + o) % n].1)
+            .find(|&u| self.is_available(u))
+    }
+}
+
+/// 64 bit fnv-1a hash, stable across processes
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// splitmix64 finalizer, spreads the bits of a hash so that similar keys land far apart
+pub fn mix(mut h: u64) -> u64 {
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+/// a random number from the randomly keyed std hasher
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
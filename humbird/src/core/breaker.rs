@@ -0,0 +1,195 @@
+/// circuit breaking per upstream, stops sending requests to a failing upstream
+/// and lets a few probe requests through once it has cooled down
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// settings of the `circuit-breaker` table of an upstream group
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    /// consecutive failed requests opening the circuit
+    pub failure_threshold: u32,
+    /// time an open circuit rejects requests before probing the upstream
+    pub open_duration: Duration,
+    /// concurrent probe requests of a half open circuit
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(10),
+            half_open_requests: 1,
+        }
+    }
+}
+
+/// circuit state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// requests flow normally
+    CLOSED,
+    /// requests are rejected
+    OPEN,
+    /// probe requests decide whether the circuit closes again
+    HALFOPEN,
+}
+
+/// whether a circuit lets a request through, decided as the request is counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// the circuit is open or its probe requests are all taken, the request is not sent
+    REFUSED,
+    /// a request of a closed circuit
+    ADMITTED,
+    /// a probe request of a half open circuit, released once finished
+    PROBE,
+}
+
+#[derive(Debug)]
+struct CircuitState {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Instant,
+    probes: u32,
+}
+
+impl CircuitState {
+    /// count a probe request while the half open limit allows one, the probes still
+    /// outstanding are counted until they are released
+    fn probe(&mut self, settings: &CircuitBreaker) -> Admission {
+        if self.probes >= settings.half_open_requests.max(1) {
+            return Admission::REFUSED;
+        }
+        self.probes += 1;
+        Admission::PROBE
+    }
+}
+
+/// circuit of an upstream
+#[derive(Debug)]
+pub struct Circuit {
+    inner: Mutex<CircuitState>,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit {
+            inner: Mutex::new(CircuitState {
+                state: BreakerState::CLOSED,
+                failures: 0,
+                opened_at: Instant::now(),
+                probes: 0,
+            }),
+        }
+    }
+}
+
+impl Circuit {
+    /// current state
+    pub fn state(&self) -> BreakerState {
+        match self.inner.lock() {
+            Ok(c) => c.state,
+            Err(_) => BreakerState::CLOSED,
+        }
+    }
+    /// whether a request may be sent now
+    pub fn allows(&self, settings: &CircuitBreaker) -> bool {
+        let c = match self.inner.lock() {
+            Ok(c) => c,
+            Err(_) => return true,
+        };
+        match c.state {
+            BreakerState::CLOSED => true,
+            // probes of the previous half open period may still be outstanding
+            BreakerState::OPEN => {
+                c.opened_at.elapsed() >= settings.open_duration
+                    && c.probes < settings.half_open_requests.max(1)
+            }
+            BreakerState::HALFOPEN => c.probes < settings.half_open_requests.max(1),
+        }
+    }
+    /// admit a request to the upstream under the lock deciding it, a probe request has to
+    /// be released once finished and a refused one must not be sent
+    pub fn on_acquire(&self, settings: &CircuitBreaker) -> Admission {
+        let mut c = match self.inner.lock() {
+            Ok(c) => c,
+            Err(_) => return Admission::ADMITTED,
+        };
+        match c.state {
+            BreakerState::CLOSED => Admission::ADMITTED,
+            BreakerState::OPEN if c.opened_at.elapsed() >= settings.open_duration => {
+                c.state = BreakerState::HALFOPEN;
+                c.probe(settings)
+            }
+            BreakerState::OPEN => Admission::REFUSED,
+            BreakerState::HALFOPEN => c.probe(settings),
+        }
+    }
+    /// a probe request has finished
+    pub fn release(&self) {
+        if let Ok(mut c) = self.inner.lock() {
+            c.probes = c.probes.saturating_sub(1);
+        }
+    }
+    /// record the outcome of a request, returns the new state when it changed
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::breaker::{Admission, BreakerState, Circuit, CircuitBreaker};
+    /// use std::time::Duration;
+    /// let settings = CircuitBreaker {
+    ///     failure_threshold: 2,
+    ///     open_duration: Duration::ZERO,
+    ///     half_open_requests: 1,
+    /// };
+    /// let circuit = Circuit::default();
+    /// assert_eq!(circuit.record(false, &settings), None);
+    /// assert_eq!(circuit.record(false, &settings), Some(BreakerState::OPEN));
+    /// // once the open duration has passed a probe request is let through
+    /// assert_eq!(circuit.on_acquire(&settings), Admission::PROBE);
+    /// assert_eq!(circuit.state(), BreakerState::HALFOPEN);
+    /// assert!(!circuit.allows(&settings));
+    /// // a failed probe opens the circuit again, a successful one closes it
+    /// assert_eq!(circuit.record(false, &settings), Some(BreakerState::OPEN));
+    /// // the failed probe is still outstanding until it is released
+    /// assert_eq!(circuit.on_acquire(&settings), Admission::REFUSED);
+    /// circuit.release();
+    /// assert_eq!(circuit.on_acquire(&settings), Admission::PROBE);
+    /// assert_eq!(circuit.record(true, &settings), Some(BreakerState::CLOSED));
+    /// circuit.release();
+    /// assert!(circuit.allows(&settings));
+    /// ```
+    pub fn record(&self, ok: bool, settings: &CircuitBreaker) -> Option<BreakerState> {
+        let mut c = self.inner.lock().ok()?;
+        match (c.state, ok) {
+            (BreakerState::CLOSED, true) => {
+                c.failures = 0;
+                None
+            }
+            (BreakerState::CLOSED, false) => {
+                c.failures += 1;
+                if c.failures < settings.failure_threshold.max(1) {
+                    return None;
+                }
+                c.state = BreakerState::OPEN;
+                c.opened_at = Instant::now();
+                Some(BreakerState::OPEN)
+            }
+            (BreakerState::HALFOPEN, true) => {
+                c.state = BreakerState::CLOSED;
+                c.failures = 0;
+                Some(BreakerState::CLOSED)
+            }
+            (BreakerState::HALFOPEN, false) => {
+                c.state = BreakerState::OPEN;
+                c.opened_at = Instant::now();
+                Some(BreakerState::OPEN)
+            }
+            // requests sent before the circuit opened
+            (BreakerState::OPEN, _) => None,
+        }
+    }
+}
@@ -0,0 +1,833 @@
+/// http cache of proxied responses, fresh responses are answered without asking the
+/// upstream and stale ones are revalidated with their validators, rfc 9111
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{io::AsyncWrite, sync::watch};
+use tracing::warn;
+
+use crate::{
+    core::settings,
+    protocol::http::{parse_http_date, Request},
+};
+
+/// extension of the files of the disk tier
+const DISK_EXTENSION: &str = "cache";
+/// status codes whose responses are stored
+const CACHEABLE_STATUS: [&str; 8] = ["200", "203", "204", "300", "301", "308", "404", "410"];
+/// headers of a `304` answer that replace the stored ones, rfc 9111 section 4.3.4
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+lazy_static! {
+    /// responses of the proxy routes enabling the cache
+    pub static ref PROXY_CACHE: ProxyCache = ProxyCache::default();
+}
+
+/// cache settings
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    /// bytes of responses held in memory
+    pub memory_size: usize,
+    /// directory of the disk tier, which takes the responses evicted from memory
+    pub disk_path: Option<PathBuf>,
+    /// bytes of responses held on disk
+    pub disk_size: usize,
+    /// larger responses are not stored
+    pub max_object_size: usize,
+    /// freshness of `200` responses without explicit expiration, they are not stored when zero
+    pub default_ttl: Duration,
+    /// client addresses allowed to send `PURGE` requests
+    pub purge_allow: Vec<String>,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            memory_size: 64 << 20,
+            disk_path: None,
+            disk_size: 1 << 30,
+            max_object_size: 8 << 20,
+            default_ttl: Duration::ZERO,
+            purge_allow: vec!["127.0.0.1".to_string(), "::1".to_string()],
+        }
+    }
+}
+
+/// create the directory of the disk tier and remove the responses a previous run left there
+pub fn prepare_disk(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().is_some_and(|e| e == DISK_EXTENSION) {
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// how long a response may be used
+#[derive(Debug, Clone, Default)]
+pub struct Freshness {
+    /// time the response is fresh after it was generated
+    pub lifetime: Duration,
+    /// time a stale response is still answered while it is revalidated in the background
+    pub stale_while_revalidate: Duration,
+    /// time a stale response is still answered when its revalidation fails
+    pub stale_if_error: Duration,
+    /// a stale response is never answered
+    pub must_revalidate: bool,
+}
+
+impl Freshness {
+    /// freshness of a response, `None` when it may not be stored
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::cache::{CacheSettings, Freshness};
+    /// use std::time::Duration;
+    /// let head = |h: &[(&str, &str)]| -> Vec<(String, String)> {
+    ///     h.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    /// };
+    /// let settings = CacheSettings::default();
+    /// let cc = head(&[("Cache-Control", "max-age=60, stale-while-revalidate=30")]);
+    /// let f = Freshness::new("HTTP/1.1 200 OK", &cc, &settings).unwrap();
+    /// assert_eq!(f.lifetime, Duration::from_secs(60));
+    /// assert_eq!(f.stale_while_revalidate, Duration::from_secs(30));
+    /// assert!(!f.must_revalidate);
+    /// // no-cache responses are stored only when they can be revalidated
+    /// let nc = head(&[("Cache-Control", "no-cache"), ("ETag", "\"v1\"")]);
+    /// assert!(Freshness::new("HTTP/1.1 200 OK", &nc, &settings).unwrap().must_revalidate);
+    /// assert!(Freshness::new("HTTP/1.1 200 OK", &nc[..1], &settings).is_none());
+    /// assert!(Freshness::new("HTTP/1.1 500 Internal Server Error", &cc, &settings).is_none());
+    /// let private = head(&[("Cache-Control", "private, max-age=60")]);
+    /// assert!(Freshness::new("HTTP/1.1 200 OK", &private, &settings).is_none());
+    /// ```
+    pub fn new(status_line: &str, head: &[(String, String)], settings: &CacheSettings) -> Option<Self> {
+        let status = status_line.split(' ').nth(1).unwrap_or_default();
+        if !CACHEABLE_STATUS.contains(&status) || value(head, "Set-Cookie").is_some() {
+            return None;
+        }
+        let cc = directives(value(head, "Cache-Control").unwrap_or_default());
+        if cc.contains_key("no-store") || cc.contains_key("private") {
+            return None;
+        }
+        let seconds = |k: &str| {
+            cc.get(k)
+                .and_then(|v| v.as_ref())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs)
+        };
+        let expires = || {
+            let expires = parse_http_date(value(head, "Expires")?)?;
+            let date = value(head, "Date").and_then(parse_http_date).unwrap_or_else(SystemTime::now);
+            Some(expires.duration_since(date).unwrap_or_default())
+        };
+        let lifetime = if cc.contains_key("no-cache") {
+            Duration::ZERO
+        } else if let Some(s) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+            s
+        } else if value(head, "Expires").is_some() {
+            expires().unwrap_or_default()
+        } else if status == "200" {
+            settings.default_ttl
+        } else {
+            Duration::ZERO
+        };
+        // a response that is never fresh is only worth storing when it can be revalidated
+        let validated = value(head, "ETag").is_some() || value(head, "Last-Modified").is_some();
+        if lifetime.is_zero() && !validated {
+            return None;
+        }
+        Some(Freshness {
+            lifetime,
+            stale_while_revalidate: seconds("stale-while-revalidate").unwrap_or_default(),
+            stale_if_error: seconds("stale-if-error").unwrap_or_default(),
+            must_revalidate: cc.contains_key("must-revalidate")
+                || cc.contains_key("proxy-revalidate")
+                || cc.contains_key("no-cache"),
+        })
+    }
+}
+
+/// where the body of a cached response is kept
+#[derive(Debug, Clone)]
+pub enum CachedBody {
+    MEMORY(Arc<Vec<u8>>),
+    DISK(PathBuf),
+}
+
+/// a cached response
+#[derive(Debug)]
+pub struct CacheEntry {
+    id: u64,
+    /// status line without its line break
+    pub status_line: String,
+    pub head: Vec<(String, String)>,
+    body: CachedBody,
+    /// bytes taken by the response
+    size: usize,
+    /// request header values of the `Vary` header names the response was selected by
+    vary: Vec<(String, Option<String>)>,
+    stored: Instant,
+    /// age the response already had when it was stored
+    initial_age: Duration,
+    pub freshness: Freshness,
+    last_used: AtomicU64,
+}
+
+impl CacheEntry {
+    /// status code
+    pub fn status(&self) -> &str {
+        self.status_line.split(' ').nth(1).unwrap_or_default()
+    }
+    /// time since the response was generated
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed()
+    }
+    /// whether the response may be answered without revalidation
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.freshness.lifetime
+    }
+    /// whether the stale response may be answered while it is revalidated in the background
+    pub fn serves_stale_while_revalidate(&self) -> bool {
+        !self.freshness.must_revalidate
+            && self.age() < self.freshness.lifetime + self.freshness.stale_while_revalidate
+    }
+    /// whether the stale response may be answered when its revalidation failed
+    pub fn serves_stale_if_error(&self) -> bool {
+        !self.freshness.must_revalidate
+            && self.age() < self.freshness.lifetime + self.freshness.stale_if_error
+    }
+    /// entity tag validator
+    pub fn etag(&self) -> Option<&str> {
+        value(&self.head, "ETag")
+    }
+    /// modification date validator
+    pub fn last_modified(&self) -> Option<&str> {
+        value(&self.head, "Last-Modified")
+    }
+    /// whether the request selects this response of its url
+    fn matches(&self, request: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, v)| request_value(request, name) == v.as_deref())
+    }
+    /// whether a conditional request is answered with `304` by this response
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::{core::cache::PROXY_CACHE, protocol::http::Request};
+    /// use std::{io::Write, net::{Shutdown, TcpListener, TcpStream}};
+    /// let request = |head: &str| {
+    ///     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    ///     let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    ///     client.write_all(format!("GET /logo.png HTTP/1.1\r\n{}\r\n", head).as_bytes()).unwrap();
+    ///     client.shutdown(Shutdown::Write).unwrap();
+    ///     let (server, _) = listener.accept().unwrap();
+    ///     Request::decode(&mio::net::TcpStream::from_std(server)).unwrap()
+    /// };
+    /// let raw = "HTTP/1.1 200 OK\r\nETag: W/\"v1\"\r\nContent-Length: 2\r\n\r\nok";
+    /// let rt = tokio::runtime::Runtime::new().unwrap();
+    /// let entry = rt
+    ///     .block_on(PROXY_CACHE.store("/logo.png", &request(""), raw.as_bytes()))
+    ///     .unwrap();
+    /// assert!(entry.not_modified(&request("If-None-Match: \"v1\"\r\n")));
+    /// assert!(entry.not_modified(&request("If-None-Match: \"v0\", *\r\n")));
+    /// assert!(!entry.not_modified(&request("If-None-Match: \"v2\"\r\n")));
+    /// assert!(!entry.not_modified(&request("")));
+    /// ```
+    pub fn not_modified(&self, request: &Request) -> bool {
+        if let Some(tags) = request_value(request, "If-None-Match") {
+            // weak comparison, rfc 9110 section 13.1.2
+            let weak = |t: &str| t.trim().trim_start_matches("W/").to_string();
+            return match self.etag() {
+                Some(etag) => tags.split(',').any(|t| t.trim() == "*" || weak(t) == weak(etag)),
+                None => false,
+            };
+        }
+        let since = request_value(request, "If-Modified-Since").and_then(parse_http_date);
+        match (since, self.last_modified().and_then(parse_http_date)) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+    /// head of the response answered from the cache, `x_cache` tells how it was obtained
+    pub fn response_head(&self, x_cache: &str) -> String {
+        let mut h = format!("{}\r\n", self.status_line);
+        for (k, v) in self.head.iter() {
+            if k.eq_ignore_ascii_case("Age") || k.eq_ignore_ascii_case("X-Cache") {
+                continue;
+            }
+            h.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        h.push_str(&format!(
+            "Age: {}\r\nX-Cache: {}\r\nConnection: close\r\n\r\n",
+            self.age().as_secs(),
+            x_cache
+        ));
+        h
+    }
+    /// head of a `304` answer to a conditional request
+    pub fn not_modified_head(&self, x_cache: &str) -> String {
+        let mut h = "HTTP/1.1 304 Not Modified\r\n".to_string();
+        for (k, v) in self.head.iter() {
+            if NOT_MODIFIED_HEADERS.iter().any(|n| n.eq_ignore_ascii_case(k)) {
+                h.push_str(&format!("{}: {}\r\n", k, v));
+            }
+        }
+        h.push_str(&format!(
+            "Age: {}\r\nX-Cache: {}\r\nConnection: close\r\n\r\n",
+            self.age().as_secs(),
+            x_cache
+        ));
+        h
+    }
+    /// the same response with its body kept elsewhere
+    fn with_body(&self, body: CachedBody) -> Self {
+        CacheEntry {
+            id: self.id,
+            status_line: self.status_line.clone(),
+            head: self.head.clone(),
+            body,
+            size: self.size,
+            vary: self.vary.clone(),
+            stored: self.stored,
+            initial_age: self.initial_age,
+            freshness: self.freshness.clone(),
+            last_used: AtomicU64::new(self.last_used.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// the responses of the cache, variants of a url share its key
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<String, Vec<Arc<CacheEntry>>>,
+    /// bytes of the responses in memory
+    memory: usize,
+    /// bytes of the responses on disk
+    disk: usize,
+}
+
+impl Store {
+    /// add a response, replacing the variant it was selected as, returns the replaced one
+    fn insert(&mut self, key: &str, entry: Arc<CacheEntry>) -> Option<Arc<CacheEntry>> {
+        let variants = self.entries.entry(key.to_string()).or_default();
+        let replaced = variants
+            .iter()
+            .position(|e| e.vary == entry.vary)
+            .map(|i| variants.remove(i));
+        variants.push(entry.clone());
+        self.account(&entry, true);
+        if let Some(ref r) = replaced {
+            self.account(r, false);
+        }
+        replaced
+    }
+    /// remove a response, if it is still stored
+    fn remove(&mut self, key: &str, id: u64) -> Option<Arc<CacheEntry>> {
+        let variants = self.entries.get_mut(key)?;
+        let i = variants.iter().position(|e| e.id == id)?;
+        let removed = variants.remove(i);
+        if variants.is_empty() {
+            self.entries.remove(key);
+        }
+        self.account(&removed, false);
+        Some(removed)
+    }
+    fn account(&mut self, entry: &CacheEntry, add: bool) {
+        let tier = match entry.body {
+            CachedBody::MEMORY(_) => &mut self.memory,
+            CachedBody::DISK(_) => &mut self.disk,
+        };
+        if add {
+            *tier += entry.size;
+        } else {
+            *tier = tier.saturating_sub(entry.size);
+        }
+    }
+    /// remove the least recently used responses of a tier until it fits its size
+    fn evict(&mut self, disk: bool, size: usize) -> Vec<(String, Arc<CacheEntry>)> {
+        let mut evicted = vec![];
+        while if disk { self.disk } else { self.memory } > size {
+            let victim = self
+                .entries
+                .iter()
+                .flat_map(|(k, v)| v.iter().map(move |e| (k, e)))
+                .filter(|(_, e)| matches!(e.body, CachedBody::DISK(_)) == disk)
+                .min_by_key(|(_, e)| e.last_used.load(Ordering::Relaxed))
+                .map(|(k, e)| (k.clone(), e.id));
+            match victim {
+                Some((key, id)) => {
+                    if let Some(e) = self.remove(&key, id) {
+                        evicted.push((key, e));
+                    }
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+}
+
+/// the shared cache of the proxy routes
+#[derive(Debug, Default)]
+pub struct ProxyCache {
+    store: Mutex<Store>,
+    /// requests filling the cache, others missing the same url wait for them
+    inflight: Mutex<HashMap<String, watch::Receiver<()>>>,
+    ids: AtomicU64,
+    /// use counter ordering the responses for eviction
+    ticks: AtomicU64,
+}
+
+/// the outcome of joining the requests filling the cache for a url
+#[derive(Debug)]
+pub enum Flight {
+    /// no other request fills the cache, this one does until the guard is dropped
+    LEADER(FlightGuard),
+    /// another request fills the cache, the receiver changes once it is done
+    FOLLOWER(watch::Receiver<()>),
+}
+
+/// marks a request filling the cache for a url
+#[derive(Debug)]
+pub struct FlightGuard {
+    cache: &'static ProxyCache,
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut inflight) = self.cache.inflight.lock() {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+impl ProxyCache {
+    /// the stored response a request selects
+    pub fn lookup(&self, key: &str, request: &Request) -> Option<Arc<CacheEntry>> {
+        let store = self.store.lock().ok()?;
+        let entry = store.entries.get(key)?.iter().find(|e| e.matches(request))?.clone();
+        entry
+            .last_used
+            .store(self.ticks.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        Some(entry)
+    }
+    /// body of a stored response, `None` when it could not be read from disk
+    pub async fn body(&self, key: &str, entry: &CacheEntry) -> Option<Arc<Vec<u8>>> {
+        match entry.body {
+            CachedBody::MEMORY(ref b) => Some(b.clone()),
+            CachedBody::DISK(ref path) => match tokio::fs::read(path).await {
+                Ok(b) => Some(Arc::new(b)),
+                Err(e) => {
+                    warn!("cached response {} unreadable: {}", path.display(), e);
+                    if let Ok(mut store) = self.store.lock() {
+                        store.remove(key, entry.id);
+                    }
+                    None
+                }
+            },
+        }
+    }
+    /// join the requests filling the cache for a url
+    pub fn join(&'static self, key: &str) -> Flight {
+        let mut inflight = match self.inflight.lock() {
+            Ok(i) => i,
+            Err(e) => e.into_inner(),
+        };
+        if let Some(done) = inflight.get(key) {
+            return Flight::FOLLOWER(done.clone());
+        }
+        let (done, waiting) = watch::channel(());
+        inflight.insert(key.to_string(), waiting);
+        Flight::LEADER(FlightGuard {
+            cache: self,
+            key: key.to_string(),
+            _done: done,
+        })
+    }
+    /// store a response as written to the client, returns it when it may be cached
+    pub async fn store(&self, key: &str, request: &Request, raw: &[u8]) -> Option<Arc<CacheEntry>> {
+        let settings = settings::current().cache.clone();
+        let (status_line, head, offset) = parse(raw)?;
+        let freshness = Freshness::new(&status_line, &head, &settings)?;
+        let mut vary = vec![];
+        for name in value(&head, "Vary").unwrap_or_default().split(',') {
+            match name.trim() {
+                "" => {}
+                "*" => return None,
+                n => vary.push((n.to_string(), request_value(request, n).map(|v| v.to_string()))),
+            }
+        }
+        let initial_age = value(&head, "Age")
+            .and_then(|a| a.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let entry = Arc::new(CacheEntry {
+            id: self.ids.fetch_add(1, Ordering::Relaxed),
+            status_line,
+            head,
+            body: CachedBody::MEMORY(Arc::new(raw[offset..].to_vec())),
+            size: raw.len(),
+            vary,
+            stored: Instant::now(),
+            initial_age,
+            freshness,
+            last_used: AtomicU64::new(self.ticks.fetch_add(1, Ordering::Relaxed)),
+        });
+        let (replaced, evicted) = {
+            let mut store = self.store.lock().ok()?;
+            let replaced = store.insert(key, entry.clone());
+            (replaced, store.evict(false, settings.memory_size))
+        };
+        if let Some(r) = replaced {
+            discard(&r).await;
+        }
+        self.demote(evicted, &settings).await;
+        Some(entry)
+    }
+    /// update a stored response with the head of a `304` answer to its revalidation
+    pub async fn refresh(&self, key: &str, entry: &CacheEntry, raw: &[u8]) -> Arc<CacheEntry> {
+        let mut refreshed = entry.with_body(entry.body.clone());
+        refreshed.stored = Instant::now();
+        refreshed.initial_age = Duration::ZERO;
+        if let Some((_, head, _)) = parse(raw) {
+            for (k, v) in head {
+                if !NOT_MODIFIED_HEADERS.iter().any(|n| n.eq_ignore_ascii_case(&k)) {
+                    continue;
+                }
+                refreshed.head.retain(|(name, _)| !name.eq_ignore_ascii_case(&k));
+                refreshed.head.push((k, v));
+            }
+        }
+        let settings = settings::current().cache.clone();
+        let freshness = Freshness::new(&refreshed.status_line, &refreshed.head, &settings);
+        let storable = freshness.is_some();
+        refreshed.freshness = freshness.unwrap_or_default();
+        let current = match self.store.lock() {
+            Ok(mut store) => match store.remove(key, entry.id) {
+                // the body, and its file, stay with the refreshed response
+                Some(current) if storable => {
+                    let refreshed = Arc::new(refreshed.with_body(current.body.clone()));
+                    store.insert(key, refreshed.clone());
+                    return refreshed;
+                }
+                current => current,
+            },
+            Err(_) => None,
+        };
+        if let Some(c) = current {
+            discard(&c).await;
+        }
+        Arc::new(refreshed)
+    }
+    /// remove every variant of a url, returns how many were stored
+    pub async fn purge(&self, key: &str) -> usize {
+        let removed = match self.store.lock() {
+            Ok(mut store) => {
+                let variants = store.entries.remove(key).unwrap_or_default();
+                for e in variants.iter() {
+                    store.account(e, false);
+                }
+                variants
+            }
+            Err(_) => vec![],
+        };
+        for e in removed.iter() {
+            discard(e).await;
+        }
+        removed.len()
+    }
+    /// move responses evicted from memory to the disk tier, they are dropped without one
+    async fn demote(&self, evicted: Vec<(String, Arc<CacheEntry>)>, settings: &CacheSettings) {
+        let dir = match settings.disk_path {
+            Some(ref d) => d,
+            None => return,
+        };
+        for (key, entry) in evicted {
+            let body = match entry.body {
+                CachedBody::MEMORY(ref b) => b.clone(),
+                CachedBody::DISK(_) => continue,
+            };
+            let path = dir.join(format!("{:016x}.{}", entry.id, DISK_EXTENSION));
+            if let Err(e) = tokio::fs::write(&path, body.as_slice()).await {
+                warn!("cached response {} not written: {}", path.display(), e);
+                continue;
+            }
+            let demoted = Arc::new(entry.with_body(CachedBody::DISK(path)));
+            let evicted = match self.store.lock() {
+                Ok(mut store) => {
+                    // a newer response of the same variant was stored meanwhile
+                    let stored = store
+                        .entries
+                        .get(&key)
+                        .is_some_and(|v| v.iter().any(|e| e.vary == demoted.vary));
+                    if stored {
+                        vec![(key, demoted)]
+                    } else {
+                        store.insert(&key, demoted);
+                        store.evict(true, settings.disk_size)
+                    }
+                }
+                Err(_) => vec![(key, demoted)],
+            };
+            for (_, e) in evicted {
+                discard(&e).await;
+            }
+        }
+    }
+}
+
+/// delete the file of a response removed from the disk tier
+async fn discard(entry: &CacheEntry) {
+    if let CachedBody::DISK(ref path) = entry.body {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+/// cache key of a request, its host and url
+pub fn key(request: &Request) -> String {
+    let host = request_value(request, "Host").unwrap_or_default().to_ascii_lowercase();
+    match request.query() {
+        "" => format!("{}{}", host, request.path()),
+        q => format!("{}{}?{}", host, request.path(), q),
+    }
+}
+
+/// whether a request goes to the upstream without the cache
+pub fn bypass(request: &Request) -> bool {
+    request_value(request, "Authorization").is_some()
+        || request_value(request, "Range").is_some()
+        || directives(request_value(request, "Cache-Control").unwrap_or_default()).contains_key("no-store")
+}
+
+/// whether a request asks for a response revalidated by the upstream
+pub fn revalidate(request: &Request) -> bool {
+    let cc = directives(request_value(request, "Cache-Control").unwrap_or_default());
+    cc.contains_key("no-cache")
+        || cc.get("max-age").is_some_and(|v| v.as_deref() == Some("0"))
+        || request_value(request, "Pragma").is_some_and(|p| p.eq_ignore_ascii_case("no-cache"))
+}
+
+/// whether a client address may send `PURGE` requests
+pub fn purge_allowed(request: &Request) -> bool {
+    let client = match request.client() {
+        Some(c) => c.ip().to_string(),
+        None => return false,
+    };
+    settings::current().cache.purge_allow.contains(&client)
+}
+
+/// `Cache-Control` directives with their optional arguments, names lowercased
+fn directives(v: &str) -> HashMap<String, Option<String>> {
+    v.split(',')
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((k, a)) => (k.trim().to_ascii_lowercase(), Some(a.trim().trim_matches('"').to_string())),
+            None => (d.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// header value of a response head, the name is compared case insensitively
+fn value<'a>(head: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    head.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// header value of a request, the name is compared case insensitively
+fn request_value<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .heads()
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// status line, head and body offset of a response
+type Parts = (String, Vec<(String, String)>, usize);
+
+/// split a response into its status line, its head and the offset of its body
+fn parse(raw: &[u8]) -> Option<Parts> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let text = std::str::from_utf8(&raw[..end]).ok()?;
+    let mut lines = text.split("\r\n");
+    let status_line = lines.next()?.trim_end().to_string();
+    let head = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    Some((status_line, head, end + 4))
+}
+
+/// a response relayed to the client without the headers the proxy added to its head, the
+/// `Connection` header and the affinity cookie, which are not the upstream's to store
+///
+/// Example
+/// ```rust
+/// use humbird::core::cache::upstream_response;
+/// let relayed = b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nSet-Cookie: hb=x; Path=/\r\nConnection: close\r\n\r\nbody";
+/// assert_eq!(
+///     upstream_response(relayed, Some("hb=x; Path=/")),
+///     b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\n\r\nbody"
+/// );
+/// ```
+pub fn upstream_response(raw: &[u8], affinity_cookie: Option<&str>) -> Vec<u8> {
+    let end = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(e) => e,
+        None => return raw.to_vec(),
+    };
+    let mut cookie = affinity_cookie;
+    let mut response = vec![];
+    for (i, line) in raw[..end].split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if i > 0 {
+            let text = String::from_utf8_lossy(line);
+            let (k, v) = text.split_once(':').map_or((text.as_ref(), ""), |(k, v)| (k.trim(), v.trim()));
+            if k.eq_ignore_ascii_case("Connection") {
+                continue;
+            }
+            if k.eq_ignore_ascii_case("Set-Cookie") && cookie == Some(v) {
+                cookie = None;
+                continue;
+            }
+        }
+        response.extend_from_slice(line);
+        response.extend_from_slice(b"\r\n");
+    }
+    response.extend_from_slice(b"\r\n");
+    response.extend_from_slice(&raw[end + 4..]);
+    response
+}
+
+/// writes a response to the client and keeps a copy of it for the cache, a `304` answer
+/// to a revalidation is held back as the client gets the cached response instead
+#[derive(Debug)]
+pub struct Tee<'a, W> {
+    inner: &'a mut W,
+    /// the head as written so far
+    head: Vec<u8>,
+    head_done: bool,
+    /// copy of the response, dropped once it exceeds the limit
+    copy: Option<Vec<u8>>,
+    limit: usize,
+    hold_not_modified: bool,
+    not_modified: bool,
+    /// bytes accepted but not yet written to the client
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<'a, W> Tee<'a, W> {
+    pub fn new(inner: &'a mut W, limit: usize, hold_not_modified: bool) -> Self {
+        Tee {
+            inner,
+            head: vec![],
+            head_done: false,
+            copy: Some(vec![]),
+            limit,
+            hold_not_modified,
+            not_modified: false,
+            pending: vec![],
+            written: 0,
+        }
+    }
+    /// whether a `304` answer has been held back
+    pub fn not_modified(&self) -> bool {
+        self.not_modified
+    }
+    /// the response head
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+    /// the whole response, `None` when it exceeded the limit
+    pub fn copy(self) -> Option<Vec<u8>> {
+        self.copy
+    }
+    fn keep(&mut self, buf: &[u8]) {
+        if let Some(ref mut c) = self.copy {
+            if c.len() + buf.len() > self.limit {
+                self.copy = None;
+            } else {
+                c.extend_from_slice(buf);
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Tee<'_, W> {
+    /// write the pending bytes to the client
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            match ready!(Pin::new(&mut *self.inner).poll_write(cx, &self.pending[self.written..])) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(n) => self.written += n,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Tee<'_, W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if !this.head_done {
+            // the head is held until it is complete, its status decides where it goes
+            this.head.extend_from_slice(buf);
+            this.keep(buf);
+            if let Some(end) = this.head.windows(4).position(|w| w == b"\r\n\r\n") {
+                this.head_done = true;
+                let status = this.head.split(|b| *b == b' ').nth(1).unwrap_or_default();
+                if this.hold_not_modified && status == b"304" {
+                    this.not_modified = true;
+                } else {
+                    this.pending = this.head.clone();
+                }
+                this.head.truncate(end + 4);
+            }
+            return Poll::Ready(Ok(buf.len()));
+        }
+        if this.not_modified {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let n = ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        this.keep(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut *this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut *this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,94 @@
+/// response compression settings
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// compress responses on the fly
+    pub enable: bool,
+    /// enabled codings in order of preference
+    pub algorithms: Vec<Encoding>,
+    /// smallest body worth compressing, in bytes
+    pub min_size: usize,
+    /// compressible media types, `text/*` matches a whole top level type
+    pub mime_types: Vec<String>,
+    /// compression level, `None` uses the default of each coding
+    pub level: Option<u32>,
+    /// serve `.br`, `.zst` and `.gz` siblings of static files
+    pub precompressed: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            enable: false,
+            algorithms: vec![
+                Encoding::BROTLI,
+                Encoding::ZSTD,
+                Encoding::GZIP,
+                Encoding::DEFLATE,
+            ],
+            min_size: 1024,
+            mime_types: vec![
+                "text/*".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "application/xml".to_string(),
+                "application/wasm".to_string(),
+                "image/svg+xml".to_string(),
+            ],
+            level: None,
+            precompressed: false,
+        }
+    }
+}
+
+impl Compression {
+    /// whether a `Content-Type` value is in the media type allowlist
+    pub fn compressible(&self, content_type: &str) -> bool {
+        let mime = match content_type.split(';').next() {
+            Some(m) => m.trim().to_ascii_lowercase(),
+            None => return false,
+        };
+        self.mime_types.iter().any(|t| match t.strip_suffix("/*") {
+            Some(top) => mime.split('/').next() == Some(top),
+            None => t.eq_ignore_ascii_case(&mime),
+        })
+    }
+}
+
+/// content coding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    BROTLI,
+    ZSTD,
+    GZIP,
+    DEFLATE,
+}
+
+impl Encoding {
+    pub fn new(m: &str) -> Option<Self> {
+        match m.trim().to_ascii_lowercase().as_str() {
+            "br" | "brotli" => Some(Encoding::BROTLI),
+            "zstd" => Some(Encoding::ZSTD),
+            "gzip" | "x-gzip" => Some(Encoding::GZIP),
+            "deflate" => Some(Encoding::DEFLATE),
+            _ => None,
+        }
+    }
+    /// `Content-Encoding` token
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::BROTLI => "br",
+            Encoding::ZSTD => "zstd",
+            Encoding::GZIP => "gzip",
+            Encoding::DEFLATE => "deflate",
+        }
+    }
+    /// file extension of precompressed static files
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::BROTLI => Some("br"),
+            Encoding::ZSTD => Some("zst"),
+            Encoding::GZIP => Some("gz"),
+            Encoding::DEFLATE => None,
+        }
+    }
+}
@@ -1,65 +1,1883 @@
-use std::{fs, io::Read};
+use regex::Regex;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        Error, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer,
+};
+use lazy_static::lazy_static;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    marker::PhantomData,
+    net::IpAddr,
+    num::NonZeroU32,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use toml::{Spanned, Table, Value};
+use tracing::Level;
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::EnvFilter;
 
 use crate::{
-    core::proxy::PROXY_TARGET,
-    core::server::{ROOT_PATH, SERVER_LISTENING_PORT},
+    core::access_log::{AccessFormat, AccessLogSettings},
+    core::balancer::{HashKey, Upstream, UpstreamGroup, MAX_WEIGHT},
+    core::compression::{Compression, Encoding},
+    core::directory::{AutoIndex, Directory, IndexSort},
+    core::breaker::CircuitBreaker,
+    core::cache::{self, CacheSettings},
+    core::forward_proxy::{ForwardProxy, HostRule},
+    core::health::{CheckKind, HealthCheck, OutlierDetection},
+    core::log::{LogFormat, LogOutput, LogSettings, LOG_SETTINGS},
+    core::mirror::Mirror,
+    core::pool::PoolSettings,
+    core::rewrite::{HeaderRules, RedirectRule, RewriteRule, RewriteRules, REDIRECT_STATUS},
+    core::reload,
+    core::proxy::{
+        BalancingMode, ProxyRoute, ProxyTimeout, RetryBudget, RetryPolicy, DEFAULT_UPSTREAM,
+    },
+    core::server::{
+        DEFAULT_MAX_BODY_SIZE, DEFAULT_PID_FILE, DEFAULT_SERVER_LISTENING_ADDR, DEFAULT_SERVER_LISTENING_PORT, DEFAULT_WORKERS, PID_FILE, PROXY_PROTOCOL, SERVER_LISTENING_ADDR, SERVER_LISTENING_PORT, WORKERS,
+    },
+    core::settings::{self, Settings},
+    core::split::{SplitGroup, TrafficSplit},
+    core::sticky::{Affinity, StickySession},
+    core::stream::{StreamProtocol, StreamProxy},
+    core::vhost::VirtualHost,
+    protocol::proxy_protocol::ProxyProtocol,
 };
 
-/// load confin file
-pub fn load_config(config_file_path: Option<String>) {
+/// a problem found in a configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// the configuration file, `None` for a configuration given as a string
+    pub file: Option<PathBuf>,
+    /// line and column of the problem, both starting at 1
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl ConfigError {
+    /// a problem at a byte range of the source
+    fn at(source: &str, span: Range<usize>, message: String) -> Self {
+        ConfigError {
+            file: None,
+            position: Some(position(source, span.start)),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if let Some((line, column)) = self.position {
+            write!(f, "{}:{}:", line, column)?;
+        }
+        if self.file.is_some() || self.position.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// line and column of a byte offset of the source
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+/// load confin file, an empty path or `None` keeps the built in defaults
+pub fn load_config(config_file_path: Option<String>) -> Result<(), Vec<ConfigError>> {
     match config_file_path {
-        Some(path) => {
-            if path.is_empty() {
-                return;
-            }
-            let file = fs::File::open(path);
-            match file {
-                Ok(mut f) => unsafe {
-                    let mut s_buf = String::default();
-                    let _ = f.read_to_string(&mut s_buf);
-                    let config = s_buf.parse::<toml::Table>().unwrap().clone();
-                    // server
-                    if config.contains_key("server") {
-                        match config["server"].get("port") {
-                            Some(p) => {
-                                SERVER_LISTENING_PORT.lock().unwrap().clear();
-                                SERVER_LISTENING_PORT
-                                    .lock()
-                                    .unwrap()
-                                    .push_str(&p.to_string());
-                            }
-                            None => {}
-                        }
-                    }
-                    // directory
-                    if config.contains_key("directory") {
-                        match config["directory"].get("root-path") {
-                            Some(p) => {
-                                ROOT_PATH.lock().unwrap().clear();
-                                ROOT_PATH.lock().unwrap().push_str(&p.to_string());
-                            }
-                            None => {}
-                        }
-                    }
-                    // porxy
-                    if config.contains_key("proxy") {
-                        match config["proxy"].get("target") {
-                            Some(p) => p
-                                .as_array()
-                                .unwrap()
-                                .iter()
-                                .for_each(|e| PROXY_TARGET.push(e.to_string())),
-                            None => todo!(),
-                        }
-                    }
+        Some(path) if !path.is_empty() => {
+            let config = read_config(Path::new(&path))?;
+            apply_config(&config, None);
+            reload::loaded(Path::new(&path), config);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+lazy_static! {
+    /// command line settings, key path and value pairs applied over the files and the environment
+    static ref OVERRIDES: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+}
+
+/// prefix of the environment variables overriding settings, `HUMBIRD_<TABLE>__<KEY>`
+pub const ENV_PREFIX: &str = "HUMBIRD_";
+
+/// set the command line settings, e.g. `("server.port", "8080")`, applied by every
+/// later `read_config`
+pub fn set_overrides(overrides: Vec<(String, String)>) {
+    *OVERRIDES.lock().unwrap() = overrides;
+}
+
+/// a file of a configuration
+struct ConfigFile {
+    path: PathBuf,
+    /// content, variables substituted
+    source: String,
+    /// content on its own, to report problems at their place in the file
+    config: Config,
+    /// content merged into the configuration
+    table: Table,
+}
+
+/// read, parse and validate a configuration file with the files it includes, then apply
+/// the `HUMBIRD_*` environment variables and the command line settings over them
+pub fn read_config(path: &Path) -> Result<Config, Vec<ConfigError>> {
+    let mut files = vec![];
+    let mut sources = vec![];
+    let mut errors = vec![];
+    read_file(path, &mut files, &mut sources, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut table = Table::new();
+    for f in files.iter_mut() {
+        merge(&mut table, std::mem::take(&mut f.table));
+    }
+    for (origin, key, value) in overrides() {
+        // the layer would replace every entry of an array of tables
+        if let Some(array) = array_of_tables(&table, &key) {
+            errors.push(ConfigError {
+                file: None,
+                position: None,
+                message: format!(
+                    "{}: the configuration has [[{}]] entries, set the key in the configuration file",
+                    origin, array
+                ),
+            });
+            continue;
+        }
+        let layer = override_table(&key, &value);
+        match typed(&layer) {
+            Ok(_) => merge(&mut table, layer),
+            Err(e) => errors.push(ConfigError {
+                file: None,
+                position: None,
+                message: format!("{}: {}", origin, e.message()),
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut config = match typed(&table) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(vec![ConfigError {
+                file: Some(path.to_path_buf()),
+                position: None,
+                message: e.message().to_string(),
+            }])
+        }
+    };
+    for f in files.iter() {
+        errors.extend(validate(&f.config, &f.source, &config.upstream).into_iter().map(|e| ConfigError {
+            file: Some(f.path.clone()),
+            ..e
+        }));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    config.include.clear();
+    config.sources = sources;
+    Ok(config)
+}
+
+/// read a file of the configuration, then the files it includes
+fn read_file(path: &Path, files: &mut Vec<ConfigFile>, sources: &mut Vec<PathBuf>, errors: &mut Vec<ConfigError>) {
+    let located = |e: ConfigError| ConfigError {
+        file: Some(path.to_path_buf()),
+        ..e
+    };
+    let source = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            errors.push(located(ConfigError {
+                file: None,
+                position: None,
+                message: format!("cannot read the configuration: {}", e),
+            }));
+            return;
+        }
+    };
+    sources.push(path.to_path_buf());
+    let source = match interpolate(&source) {
+        Ok(s) => s,
+        Err(e) => {
+            errors.extend(e.into_iter().map(located));
+            return;
+        }
+    };
+    let parsed = toml::from_str::<Config>(&source).and_then(|c| Ok((c, toml::from_str::<Table>(&source)?)));
+    let (config, mut table) = match parsed {
+        Ok(p) => p,
+        Err(e) => {
+            errors.push(located(ConfigError {
+                file: None,
+                position: e.span().map(|s| position(&source, s.start)),
+                message: e.message().to_string(),
+            }));
+            return;
+        }
+    };
+    table.remove("include");
+    let mut included = vec![];
+    for pattern in config.include.iter() {
+        match include(path, pattern.get_ref()) {
+            Ok((paths, dir)) => {
+                sources.extend(dir);
+                included.extend(paths.into_iter().map(|p| (p, pattern.span())));
+            }
+            Err(message) => errors.push(located(ConfigError::at(&source, pattern.span(), message))),
+        }
+    }
+    let position = |span: Range<usize>| position(&source, span.start);
+    files.push(ConfigFile {
+        path: path.to_path_buf(),
+        source: source.clone(),
+        config,
+        table,
+    });
+    for (p, span) in included {
+        let canonical = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+        if files.iter().any(|f| canonical(&f.path) == canonical(&p)) {
+            errors.push(ConfigError {
+                file: Some(path.to_path_buf()),
+                position: Some(position(span)),
+                message: format!("{} is included more than once", p.display()),
+            });
+            continue;
+        }
+        read_file(&p, files, sources, errors);
+    }
+}
+
+/// files an `include` entry names, relative to the including file, in name order, and the
+/// directory looked into when the file name has the wildcards `*` or `?`
+fn include(from: &Path, pattern: &str) -> Result<(Vec<PathBuf>, Option<PathBuf>), String> {
+    let pattern = from.parent().unwrap_or(Path::new("")).join(pattern);
+    let name = match pattern.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return Err(format!("include {} does not name a file", pattern.display())),
+    };
+    let dir = match pattern.parent() {
+        Some(d) if d.as_os_str().is_empty() => Path::new("."),
+        Some(d) => d,
+        None => Path::new("."),
+    };
+    if dir.to_string_lossy().contains(['*', '?']) {
+        return Err(format!("include {}: wildcards are only allowed in the file name", pattern.display()));
+    }
+    if !name.contains(['*', '?']) {
+        return match pattern.is_file() {
+            true => Ok((vec![pattern], None)),
+            false => Err(format!("included file {} does not exist", pattern.display())),
+        };
+    }
+    let matcher = Regex::new(&format!("^{}$", regex::escape(name).replace(r"\*", ".*").replace(r"\?", "."))).unwrap();
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("include {}: {}", pattern.display(), e)),
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| matcher.is_match(n) && (!n.starts_with('.') || name.starts_with('.')))
+        })
+        .collect();
+    paths.sort();
+    Ok((paths, Some(dir.to_path_buf())))
+}
+
+/// substitute `${NAME}` and `${NAME:-default}` with environment variables outside comments,
+/// the default applies to unset and empty variables, names are in capitals so that lower case
+/// ones such as the `${name}` capture groups of rewrite rules are left, `$${` stands for `${`
+///
+/// Example
+/// ```rust
+/// use humbird::core::config::interpolate;
+/// std::env::set_var("HUMBIRD_DOC_PORT", "8080");
+/// assert_eq!(interpolate("port = ${HUMBIRD_DOC_PORT:-9999}").unwrap(), "port = 8080");
+/// assert_eq!(interpolate("root-path = \"${HUMBIRD_DOC_ROOT:-html}\"").unwrap(), "root-path = \"html\"");
+/// assert_eq!(interpolate("replace = \"/${name}\" # ${NAME}").unwrap(), "replace = \"/${name}\" # ${NAME}");
+/// assert_eq!(interpolate("value = \"$${HOME}\"").unwrap(), "value = \"${HOME}\"");
+/// assert_eq!(interpolate("\nport = ${HUMBIRD_DOC_UNSET}").unwrap_err()[0].position, Some((2, 8)));
+/// ```
+pub fn interpolate(source: &str) -> Result<String, Vec<ConfigError>> {
+    lazy_static! {
+        static ref VARIABLE: Regex = Regex::new(r"\$(\$)?\{([A-Z_][A-Z0-9_]*)(:-([^}]*))?\}").unwrap();
+    }
+    let mut errors = vec![];
+    let mut result = String::with_capacity(source.len());
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let (code, comment) = line.split_at(comment_start(line));
+        let replaced = VARIABLE.replace_all(code, |c: &regex::Captures| {
+            let whole = c.get(0).unwrap();
+            if c.get(1).is_some() {
+                return whole.as_str()[1..].to_string();
+            }
+            let name = &c[2];
+            match (std::env::var(name), c.get(4)) {
+                (Ok(v), None) => v,
+                (Ok(v), Some(_)) if !v.is_empty() => v,
+                (_, Some(default)) => default.as_str().to_string(),
+                (Err(_), None) => {
+                    errors.push(ConfigError::at(
+                        source,
+                        offset + whole.start()..offset + whole.end(),
+                        format!("environment variable {} is not set and has no default", name),
+                    ));
+                    String::default()
+                }
+            }
+        });
+        result.push_str(&replaced);
+        result.push_str(comment);
+        offset += line.len();
+    }
+    match errors.is_empty() {
+        true => Ok(result),
+        false => Err(errors),
+    }
+}
+
+/// byte offset of the comment of a line, the line length when it has none
+fn comment_start(line: &str) -> usize {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, ch) in line.char_indices() {
+        match (quote, ch) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '#') => return i,
+            _ => {}
+        }
+        escaped = false;
+    }
+    line.len()
+}
+
+/// the environment variables then the command line settings, as origin, key path and value
+fn overrides() -> Vec<(String, String, String)> {
+    let mut env: Vec<(String, String, String)> = std::env::vars()
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX) && k.contains("__"))
+        .map(|(k, v)| {
+            let key = k[ENV_PREFIX.len()..]
+                .split("__")
+                .map(|s| s.to_lowercase().replace('_', "-"))
+                .collect::<Vec<String>>()
+                .join(".");
+            (k, key, v)
+        })
+        .collect();
+    env.sort();
+    let cli = OVERRIDES.lock().unwrap().clone();
+    env.into_iter()
+        .chain(cli.into_iter().map(|(k, v)| (format!("--set {}", k), k, v)))
+        .collect()
+}
+
+/// a table setting one key path, a value that is not a toml value is taken as a string
+fn override_table(key: &str, value: &str) -> Table {
+    let value = match toml::from_str::<Table>(&format!("v = {}", value)) {
+        Ok(mut t) => t.remove("v").unwrap_or_else(|| Value::String(value.to_string())),
+        Err(_) => Value::String(value.to_string()),
+    };
+    let mut keys = key.split('.').rev();
+    let mut table = Table::new();
+    table.insert(keys.next().unwrap_or_default().to_string(), value);
+    for k in keys {
+        let mut outer = Table::new();
+        outer.insert(k.to_string(), Value::Table(table));
+        table = outer;
+    }
+    table
+}
+
+/// the key path of the array of tables a key path goes through, if any
+fn array_of_tables(table: &Table, key: &str) -> Option<String> {
+    let keys: Vec<&str> = key.split('.').collect();
+    let mut current = table;
+    for (i, k) in keys.iter().enumerate().take(keys.len().saturating_sub(1)) {
+        match current.get(*k)? {
+            Value::Table(t) => current = t,
+            Value::Array(_) => return Some(keys[..=i].join(".")),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// merge a layer into a configuration, tables are merged, arrays of tables such as
+/// `[[proxy.route]]` are appended, other values are replaced
+fn merge(base: &mut Table, layer: Table) {
+    let tables = |a: &Vec<Value>| a.iter().all(|v| v.is_table());
+    for (k, v) in layer {
+        match (base.get_mut(&k), v) {
+            (Some(Value::Table(b)), Value::Table(l)) => merge(b, l),
+            (Some(Value::Array(b)), Value::Array(l)) if tables(b) && tables(&l) => b.extend(l),
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+/// the configuration a table holds, through its text so that spanned values deserialize
+fn typed(table: &Table) -> Result<Config, toml::de::Error> {
+    toml::from_str(&toml::to_string(table).unwrap_or_default())
+}
+
+/// parse and validate a configuration, variables are substituted, `include` entries are
+/// left to `read_config`
+///
+/// Example
+/// ```rust
+/// use humbird::core::config::parse_config;
+/// let config = parse_config("[server]\nport = 8080\n").unwrap();
+/// assert_eq!(config.server.port.map(|p| p.0), Some(8080));
+/// let errors = parse_config("[proxy]\nmode = \"FASTEST\"\n").unwrap_err();
+/// assert_eq!(errors[0].position, Some((2, 8)));
+/// ```
+pub fn parse_config(source: &str) -> Result<Config, Vec<ConfigError>> {
+    let source = &interpolate(source)?;
+    let config: Config = match toml::from_str(source) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(vec![ConfigError {
+                file: None,
+                position: e.span().map(|s| position(source, s.start)),
+                message: e.message().to_string(),
+            }])
+        }
+    };
+    let errors = validate(&config, source, &config.upstream);
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(errors)
+    }
+}
+
+/// store a configuration in the global settings, sections it lacks get their defaults,
+/// the hot reloadable settings are built first and put in effect at once, the upstream
+/// groups and stream proxies the `previous` configuration in effect configured the same
+/// way are kept with their state
+pub fn apply_config(config: &Config, previous: Option<&Config>) {
+    // server
+    *SERVER_LISTENING_ADDR.lock().unwrap() = match config.server.address {
+        Some(a) => a.to_string(),
+        None => DEFAULT_SERVER_LISTENING_ADDR.to_string(),
+    };
+    *SERVER_LISTENING_PORT.lock().unwrap() = match config.server.port {
+        Some(p) => p.0.to_string(),
+        None => DEFAULT_SERVER_LISTENING_PORT.to_string(),
+    };
+    *PROXY_PROTOCOL.lock().unwrap() = config.server.proxy_protocol;
+    *WORKERS.lock().unwrap() = config.server.workers.map_or(DEFAULT_WORKERS, |w| w.get() as usize);
+    *PID_FILE.lock().unwrap() = match config.server.pid_file {
+        Some(ref f) => f.to_string(),
+        None => DEFAULT_PID_FILE.to_string(),
+    };
+    // log
+    *LOG_SETTINGS.write().unwrap() = config.log.build();
+    settings::publish(build_settings(config, previous));
+}
+
+/// the hot reloadable settings of a configuration
+fn build_settings(config: &Config, previous: Option<&Config>) -> Settings {
+    let current = settings::current();
+    let proxy = config.proxy.clone().unwrap_or_default();
+    // upstream
+    let proxy_target = match previous {
+        Some(p) => keep_group(&proxy, &p.proxy.clone().unwrap_or_default(), &current.proxy_target),
+        None => Arc::new(proxy.build()),
+    };
+    let upstream_groups: HashMap<String, Arc<UpstreamGroup>> = config
+        .upstream
+        .iter()
+        .map(|(name, g)| {
+            let kept = previous
+                .and_then(|p| p.upstream.get(name))
+                .zip(current.upstream_groups.get(name.as_str()));
+            let group = match kept {
+                Some((p, current)) => keep_group(g, p, current),
+                None => Arc::new(g.build()),
+            };
+            (name.to_string(), group)
+        })
+        .collect();
+    // virtual host, matched with the previous one of the same server names
+    let virtual_hosts = config
+        .vhost
+        .iter()
+        .map(|h| {
+            let mut vhost = h.build();
+            let kept = previous.and_then(|p| {
+                let i = p.vhost.iter().position(|v| v.server_name == h.server_name)?;
+                Some((p.vhost[i].proxy.as_ref()?, current.virtual_hosts.get(i)?.proxy.as_ref()?))
+            });
+            if let (Some(p), Some((before, group))) = (h.proxy.as_ref(), kept) {
+                vhost.proxy = Some(keep_group(p, before, group));
+            }
+            vhost
+        })
+        .collect();
+    // stream, after the upstream groups it refers to, matched with the previous one of the
+    // same listen address
+    let streams = config
+        .stream
+        .iter()
+        .map(|s| {
+            let before = previous.and_then(|p| {
+                let i = p.stream.iter().position(|b| b.listen == s.listen)?;
+                Some((&p.stream[i], current.streams.get(i)?))
+            });
+            let group = match s.upstream {
+                Some(ref name) if name.get_ref() == DEFAULT_UPSTREAM => proxy_target.clone(),
+                Some(ref name) => upstream_groups.get(name.get_ref()).cloned().unwrap_or_default(),
+                None => match before {
+                    Some((b, stream)) if b.upstream.is_none() => keep_group(&s.group(), &b.group(), &stream.group),
+                    _ => Arc::new(s.group().build()),
                 },
-                Err(_) => {
-                    // TODO
+            };
+            match before {
+                // the listener of an unchanged stream proxy keeps running
+                Some((b, stream)) if b == s && Arc::ptr_eq(&stream.group, &group) => stream.clone(),
+                _ => Arc::new(s.build(group)),
+            }
+        })
+        .collect();
+    Settings {
+        max_body_size: config.server.max_body_size.map_or(DEFAULT_MAX_BODY_SIZE, |s| s.0 as u64),
+        directories: match config.directory.is_empty() {
+            true => vec![Directory::default()],
+            false => config.directory.iter().map(DirectoryConfig::build).collect(),
+        },
+        compression: match config.compression {
+            Some(ref c) => c.build(),
+            None => Compression::default(),
+        },
+        error_pages: config
+            .error_page
+            .iter()
+            .map(|(code, f)| (code.0.to_string(), f.to_string()))
+            .collect(),
+        proxy_target,
+        upstream_groups,
+        proxy_routes: proxy.route.iter().map(RouteConfig::build).collect(),
+        virtual_hosts,
+        pool: proxy.pool.map(|p| p.build()).unwrap_or_default(),
+        cache: proxy.cache.map(|c| c.build()).unwrap_or_default(),
+        streams,
+        forward_proxy: config
+            .forward_proxy
+            .as_ref()
+            .filter(|p| p.enable)
+            .map(|p| Arc::new(p.build())),
+        access_log: config.access_log.build(),
+    }
+}
+
+/// the upstream group of a table, the group in effect is kept with its health, ejection
+/// and breaker state when the table configures it the same way as `before`
+fn keep_group(config: &ProxyConfig, before: &ProxyConfig, current: &Arc<UpstreamGroup>) -> Arc<UpstreamGroup> {
+    match config.same_group(before) {
+        true => current.clone(),
+        false => Arc::new(config.build()),
+    }
+}
+
+/// the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// files merged into the configuration, relative to the including file, e.g. "conf.d/*.toml"
+    #[serde(default)]
+    pub include: Vec<Spanned<String>>,
+    /// files and include directories the configuration was read from, the file given first
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// `[directory]` or `[[directory]]` entries
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub directory: Vec<DirectoryConfig>,
+    pub compression: Option<CompressionConfig>,
+    /// error page file per status code
+    #[serde(default)]
+    pub error_page: BTreeMap<StatusCode, String>,
+    pub proxy: Option<ProxyConfig>,
+    /// named upstream groups
+    #[serde(default)]
+    pub upstream: BTreeMap<String, ProxyConfig>,
+    #[serde(default)]
+    pub stream: Vec<StreamConfig>,
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    #[serde(default)]
+    pub vhost: Vec<VirtualHostConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+}
+
+/// the `[server]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServerConfig {
+    /// listening address, an ipv4 or ipv6 address
+    pub address: Option<IpAddr>,
+    pub port: Option<Port>,
+    /// connections open with a PROXY protocol header
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// reload the configuration when its file changes
+    #[serde(default)]
+    pub watch_config: bool,
+    /// worker threads of the runtime
+    pub workers: Option<NonZeroU32>,
+    /// file the process id is written to, "" for none
+    pub pid_file: Option<String>,
+    /// largest request body accepted
+    pub max_body_size: Option<Size>,
+    pub event_poll: Option<EventPollConfig>,
+}
+
+/// the `[log]` table
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LogConfig {
+    /// most verbose level logged
+    pub level: Option<LogLevel>,
+    /// filter directives in `RUST_LOG` syntax
+    pub filter: Option<LogFilter>,
+    pub output: Option<LogOutput>,
+    pub format: Option<LogFormat>,
+    pub directory: Option<String>,
+    pub file: Option<String>,
+    pub rotation: Option<LogRotation>,
+    /// rotated files kept
+    pub max_files: Option<NonZeroU32>,
+}
+
+/// the `[access-log]` table
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub enable: Option<bool>,
+    /// `common`, `combined`, `json` or a template of `$field` variables
+    pub format: Option<AccessFormat>,
+    /// percentage of the requests logged
+    pub percent: Option<Percent>,
+    /// percentage of the requests logged by path prefix
+    #[serde(default)]
+    pub sample: Vec<SampleConfig>,
+    /// file of the access log, the lines go to the log when it is not set
+    pub file: Option<String>,
+    pub directory: Option<String>,
+    pub rotation: Option<LogRotation>,
+    /// rotated files kept
+    pub max_files: Option<NonZeroU32>,
+}
+
+/// a `[[access-log.sample]]` entry
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SampleConfig {
+    pub prefix: String,
+    pub percent: Percent,
+}
+
+/// the `event-poll` table of `[server]`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EventPollConfig {
+    pub size: Option<usize>,
+    pub life_cycle: Option<u64>,
+}
+
+/// a `[directory]` entry
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DirectoryConfig {
+    pub mount: Option<String>,
+    pub root_path: Option<String>,
+    pub autoindex: Option<AutoIndex>,
+    pub autoindex_sort: Option<IndexSort>,
+    /// `true` for a descending order
+    #[serde(default, deserialize_with = "descending")]
+    pub autoindex_order: Option<bool>,
+    pub hide_dotfiles: Option<bool>,
+    pub fallback: Option<String>,
+}
+
+/// the `[compression]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enable: Option<bool>,
+    pub algorithms: Option<Vec<Encoding>>,
+    pub min_size: Option<usize>,
+    pub mime_types: Option<Vec<String>>,
+    pub level: Option<u32>,
+    pub precompressed: Option<bool>,
+}
+
+/// a `[proxy]` table, an `[upstream.<name>]` group or the `proxy` table of a virtual host,
+/// `route`, `pool` and `cache` are only read in `[proxy]` and routes in virtual hosts too
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProxyConfig {
+    #[serde(default)]
+    pub target: Vec<Upstream>,
+    pub mode: Option<BalancingMode>,
+    pub hash_key: Option<HashKey>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier: Option<OutlierConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub sticky: Option<StickyConfig>,
+    #[serde(default)]
+    pub route: Vec<RouteConfig>,
+    pub pool: Option<PoolConfig>,
+    pub cache: Option<CacheConfig>,
+}
+
+/// the `health-check` table of an upstream group
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "type")]
+    pub kind: Option<CheckKind>,
+    pub path: Option<String>,
+    pub interval: Option<Seconds>,
+    pub timeout: Option<Seconds>,
+    pub expected_status: Option<ExpectedStatus>,
+    pub rise: Option<NonZeroU32>,
+    pub fall: Option<NonZeroU32>,
+}
+
+/// the `outlier` table of an upstream group
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OutlierConfig {
+    pub consecutive_failures: Option<NonZeroU32>,
+    pub base_ejection: Option<Seconds>,
+    pub max_ejection: Option<Seconds>,
+}
+
+/// the `circuit-breaker` table of an upstream group
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: Option<NonZeroU32>,
+    pub open_duration: Option<Seconds>,
+    pub half_open_requests: Option<NonZeroU32>,
+}
+
+/// the `sticky` table of an upstream group
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StickyConfig {
+    pub mode: Affinity,
+    pub cookie_name: Option<String>,
+    pub cookie_path: Option<String>,
+    pub cookie_max_age: Option<Seconds>,
+}
+
+/// a `[[proxy.route]]` entry
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RouteConfig {
+    pub prefix: Spanned<String>,
+    pub upstream: Option<Spanned<String>>,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    pub connect_timeout: Option<Seconds>,
+    pub first_byte_timeout: Option<Seconds>,
+    pub total_timeout: Option<Seconds>,
+    pub retries: Option<u32>,
+    pub retry_on: Option<Vec<StatusCode>>,
+    pub retry_budget: Option<f64>,
+    pub retry_budget_min: Option<u32>,
+    #[serde(default)]
+    pub cache: bool,
+    #[serde(default)]
+    pub rewrite: Vec<RewriteConfig>,
+    #[serde(default)]
+    pub redirect: Vec<RedirectConfig>,
+    pub request_headers: Option<HeaderRulesConfig>,
+    pub response_headers: Option<HeaderRulesConfig>,
+    pub mirror: Option<MirrorConfig>,
+    #[serde(default)]
+    pub split: Vec<SplitConfig>,
+    pub split_header: Option<String>,
+    pub split_cookie: Option<String>,
+}
+
+/// a `rewrite` rule of a route
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    #[serde(rename = "match")]
+    pub pattern: Pattern,
+    pub replace: String,
+}
+
+/// a `redirect` rule of a route
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    #[serde(rename = "match")]
+    pub pattern: Pattern,
+    pub to: String,
+    pub status: Option<RedirectStatus>,
+}
+
+/// a `request-headers` or `response-headers` table of a route
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRulesConfig {
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// the `mirror` table of a route
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub upstream: Spanned<String>,
+    pub percent: Option<Percent>,
+    pub max_body_size: Option<Size>,
+    pub timeout: Option<Seconds>,
+}
+
+/// an entry of the `split` list of a route
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    pub upstream: Spanned<String>,
+    pub weight: Option<u32>,
+}
+
+/// the `[proxy.pool]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_idle: Option<usize>,
+    pub idle_timeout: Option<Seconds>,
+    pub max_per_host: Option<NonZeroU32>,
+    pub connect_timeout: Option<Seconds>,
+}
+
+/// the `[proxy.cache]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CacheConfig {
+    pub memory_size: Option<Size>,
+    pub disk_path: Option<String>,
+    pub disk_size: Option<Size>,
+    pub max_object_size: Option<Size>,
+    pub default_ttl: Option<Seconds>,
+    pub purge_allow: Option<Vec<String>>,
+}
+
+/// a `[[stream]]` entry, its upstream group is either a named `[upstream.<name>]`
+/// group or given inline with the keys of `[proxy]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StreamConfig {
+    pub listen: Spanned<Listen>,
+    pub protocol: Option<StreamProtocol>,
+    pub upstream: Option<Spanned<String>>,
+    pub proxy_protocol: Option<Spanned<ProxyProtocol>>,
+    pub connect_timeout: Option<Seconds>,
+    pub idle_timeout: Option<Seconds>,
+    pub max_sessions: Option<NonZeroU32>,
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub target: Vec<Upstream>,
+    pub mode: Option<BalancingMode>,
+    pub hash_key: Option<HashKey>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier: Option<OutlierConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub sticky: Option<StickyConfig>,
+}
+
+/// the `[forward-proxy]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ForwardProxyConfig {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub allow_private: bool,
+    pub connect_ports: Option<Vec<u16>>,
+    pub connect_timeout: Option<Seconds>,
+    #[serde(default)]
+    pub users: Vec<Credentials>,
+    pub realm: Option<String>,
+}
+
+/// a `[[vhost]]` entry
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub server_name: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub directory: Vec<DirectoryConfig>,
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// a duration in seconds, integer or fractional
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Seconds(pub Duration);
+
+impl<'de> Deserialize<'de> for Seconds {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match toml::Value::deserialize(d)? {
+            toml::Value::Integer(i) if i >= 0 => Ok(Seconds(Duration::from_secs(i as u64))),
+            toml::Value::Float(f) if f >= 0.0 && f.is_finite() => Ok(Seconds(Duration::from_secs_f64(f))),
+            v => Err(D::Error::custom(format!("expected a number of seconds, found {}", v))),
+        }
+    }
+}
+
+/// a size in bytes, an integer or a string with a `k`, `m` or `g` suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub usize);
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let size = match toml::Value::deserialize(d)? {
+            toml::Value::Integer(i) => usize::try_from(i).ok(),
+            toml::Value::String(s) => {
+                let s = s.trim().to_ascii_lowercase();
+                let (n, shift) = match s.char_indices().last() {
+                    Some((i, 'k')) => (&s[..i], 10),
+                    Some((i, 'm')) => (&s[..i], 20),
+                    Some((i, 'g')) => (&s[..i], 30),
+                    _ => (s.as_str(), 0),
+                };
+                n.trim().parse::<usize>().ok().and_then(|n| n.checked_mul(1 << shift))
+            }
+            _ => None,
+        };
+        match size {
+            Some(s) => Ok(Size(s)),
+            None => Err(D::Error::custom("expected a size in bytes such as 1024 or \"64m\"")),
+        }
+    }
+}
+
+/// a listening port, an integer or a string of digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port(pub u16);
+
+impl<'de> Deserialize<'de> for Port {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let port = match toml::Value::deserialize(d)? {
+            toml::Value::Integer(i) => u16::try_from(i).ok(),
+            toml::Value::String(s) => s.trim().parse::<u16>().ok(),
+            _ => None,
+        };
+        match port {
+            Some(p) if p > 0 => Ok(Port(p)),
+            _ => Err(D::Error::custom("expected a port between 1 and 65535")),
+        }
+    }
+}
+
+/// a log level, error / warn / info / debug / trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLevel(pub Level);
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "error, warn, info, debug or trace", |l| l.parse::<Level>().ok().map(LogLevel))
+    }
+}
+
+/// log filter directives in `RUST_LOG` syntax, e.g. "humbird::core::proxy=debug,warn"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter(pub String);
+
+impl<'de> Deserialize<'de> for LogFilter {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        match EnvFilter::try_new(&s) {
+            Ok(_) => Ok(LogFilter(s)),
+            Err(e) => Err(D::Error::custom(format!("invalid log filter {:?}: {}", s, e))),
+        }
+    }
+}
+
+/// how often a new log file starts, minutely / hourly / daily / weekly / never
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRotation(pub Rotation);
+
+impl<'de> Deserialize<'de> for LogRotation {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "minutely, hourly, daily, weekly or never", |r| {
+            match r.to_ascii_lowercase().as_str() {
+                "minutely" => Some(Rotation::MINUTELY),
+                "hourly" => Some(Rotation::HOURLY),
+                "daily" => Some(Rotation::DAILY),
+                "weekly" => Some(Rotation::WEEKLY),
+                "never" => Some(Rotation::NEVER),
+                _ => None,
+            }
+            .map(LogRotation)
+        })
+    }
+}
+
+/// a status code, an integer or a string
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StatusCode(pub String);
+
+impl<'de> Deserialize<'de> for StatusCode {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let code = match toml::Value::deserialize(d)? {
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::String(s) => s.trim().to_string(),
+            v => return Err(D::Error::custom(format!("expected a status code, found {}", v))),
+        };
+        match code.parse::<u16>() {
+            Ok(100..=599) => Ok(StatusCode(code)),
+            _ => Err(D::Error::custom(format!("{} is not a status code", code))),
+        }
+    }
+}
+
+/// accepted status codes of a health check, a code, a `2xx` style class or a list of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedStatus(pub Vec<String>);
+
+impl<'de> Deserialize<'de> for ExpectedStatus {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let entries = match toml::Value::deserialize(d)? {
+            toml::Value::Array(a) => a,
+            v => vec![v],
+        };
+        let mut codes = vec![];
+        for e in entries {
+            let code = match e {
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::String(s) => s.trim().to_ascii_lowercase(),
+                v => return Err(D::Error::custom(format!("expected a status code, found {}", v))),
+            };
+            let valid = match code.strip_suffix("xx") {
+                Some(class) => matches!(class, "1" | "2" | "3" | "4" | "5"),
+                None => matches!(code.parse::<u16>(), Ok(100..=599)),
+            };
+            if !valid {
+                return Err(D::Error::custom(format!("{} is not a status code or a class such as 2xx", code)));
+            }
+            codes.push(code);
+        }
+        Ok(ExpectedStatus(codes))
+    }
+}
+
+/// a regular expression
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let p = String::deserialize(d)?;
+        match Regex::new(&p) {
+            Ok(re) => Ok(Pattern(re)),
+            Err(e) => Err(D::Error::custom(format!("invalid pattern: {}", e))),
+        }
+    }
+}
+
+/// status code of a redirect rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectStatus(pub &'static str);
+
+impl<'de> Deserialize<'de> for RedirectStatus {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let code = StatusCode::deserialize(d)?.0;
+        match REDIRECT_STATUS.iter().find(|c| **c == code) {
+            Some(c) => Ok(RedirectStatus(c)),
+            None => Err(D::Error::custom(format!("redirect status {} is not one of 301, 302, 307, 308", code))),
+        }
+    }
+}
+
+/// a percentage between 0 and 100
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percent(pub f64);
+
+impl<'de> Deserialize<'de> for Percent {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let p = f64::deserialize(d)?;
+        match (0.0..=100.0).contains(&p) {
+            true => Ok(Percent(p)),
+            false => Err(D::Error::custom(format!("{} is not a percentage between 0 and 100", p))),
+        }
+    }
+}
+
+/// listen address of a stream, `host:port` or a port of the server address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listen(pub String);
+
+impl<'de> Deserialize<'de> for Listen {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let listen = match toml::Value::deserialize(d)? {
+            toml::Value::Integer(p) => format!("{}:{}", DEFAULT_SERVER_LISTENING_ADDR, p),
+            toml::Value::String(l) => l.trim().to_string(),
+            v => return Err(D::Error::custom(format!("expected \"host:port\" or a port, found {}", v))),
+        };
+        match listen.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+            Some((h, Ok(_))) if !h.is_empty() => Ok(Listen(listen)),
+            _ => Err(D::Error::custom(format!("{} is not a \"host:port\" address", listen))),
+        }
+    }
+}
+
+/// forward proxy credentials, `user:password`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials(pub String);
+
+impl<'de> Deserialize<'de> for Credentials {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let c = String::deserialize(d)?;
+        match c.contains(':') {
+            true => Ok(Credentials(c)),
+            false => Err(D::Error::custom("expected \"user:password\" credentials")),
+        }
+    }
+}
+
+/// an upstream address, `"host:port"` or `{ address = "host:port", weight = 3 }`
+impl<'de> Deserialize<'de> for Upstream {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let (address, weight) = match toml::Value::deserialize(d)? {
+            toml::Value::String(a) => (a, 1),
+            toml::Value::Table(mut t) => {
+                if let Some(k) = t.keys().find(|k| *k != "address" && *k != "weight") {
+                    return Err(D::Error::custom(format!("unknown field `{}`, expected `address` or `weight`", k)));
+                }
+                let weight = match t.remove("weight") {
+                    Some(toml::Value::Integer(w)) if (1..=MAX_WEIGHT as i64).contains(&w) => w as u32,
+                    Some(w) => {
+                        return Err(D::Error::custom(format!(
+                            "weight {} is not an integer between 1 and {}",
+                            w, MAX_WEIGHT
+                        )))
+                    }
+                    None => 1,
+                };
+                match t.remove("address") {
+                    Some(toml::Value::String(a)) => (a, weight),
+                    _ => return Err(D::Error::custom("missing field `address`")),
                 }
             }
+            v => return Err(D::Error::custom(format!("expected \"host:port\" or a table, found {}", v))),
+        };
+        match address.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+            Some((h, Ok(_))) if !h.is_empty() => Ok(Upstream::new(&address, weight)),
+            _ => Err(D::Error::custom(format!("{} is not a \"host:port\" address", address))),
         }
-        None => {
-            return;
+    }
+}
+
+/// a keyword of a setting, `expected` lists the accepted ones
+fn keyword<'de, D: Deserializer<'de>, T>(
+    d: D,
+    expected: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Result<T, D::Error> {
+    let s = String::deserialize(d)?;
+    match parse(s.trim()) {
+        Some(t) => Ok(t),
+        None => Err(D::Error::custom(format!("unknown value {:?}, expected {}", s, expected))),
+    }
+}
+
+/// a header name of a `header:<name>` keyword
+fn header_name(m: &str) -> Option<&str> {
+    match m.split_once(':') {
+        Some((k, h)) if k.trim().eq_ignore_ascii_case("header") && !h.trim().is_empty() => Some(h.trim()),
+        _ => None,
+    }
+}
+
+impl<'de> Deserialize<'de> for LogOutput {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "stdout, file or both", |o| match o.to_ascii_lowercase().as_str() {
+            "stdout" => Some(LogOutput::STDOUT),
+            "file" => Some(LogOutput::FILE),
+            "both" => Some(LogOutput::BOTH),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for AccessFormat {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        AccessFormat::parse(&String::deserialize(d)?).map_err(D::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogFormat {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "text or json", |f| match f.to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::TEXT),
+            "json" => Some(LogFormat::JSON),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for BalancingMode {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "WEIGHT, RANDOM, POLLING, LEAST or HASH", |m| {
+            match m.to_ascii_uppercase().replace('-', "_").as_str() {
+                "WEIGHT" | "RANDOM" | "POLLING" | "LEAST" | "LEAST_CONN" | "LEAST_CONNECTIONS" | "HASH"
+                | "CONSISTENT_HASH" => Some(BalancingMode::new(m)),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for HashKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "path or header:<name>", |m| match header_name(m) {
+            Some(h) => Some(HashKey::HEADER(h.to_string())),
+            None if m.eq_ignore_ascii_case("path") => Some(HashKey::PATH),
+            None => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for CheckKind {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "http or tcp", |m| match m.to_ascii_lowercase().as_str() {
+            "http" | "tcp" => Some(CheckKind::new(m)),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Affinity {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "cookie, ip or header:<name>", |m| match header_name(m) {
+            Some(h) => Some(Affinity::HEADER(h.to_string())),
+            None => Affinity::new(m),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for StreamProtocol {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "tcp or udp", |m| match m.to_ascii_lowercase().as_str() {
+            "tcp" | "udp" => Some(StreamProtocol::new(m)),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ProxyProtocol {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "v1 or v2", ProxyProtocol::new)
+    }
+}
+
+impl<'de> Deserialize<'de> for Encoding {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "br, zstd, gzip or deflate", Encoding::new)
+    }
+}
+
+impl<'de> Deserialize<'de> for IndexSort {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        keyword(d, "name, size or mtime", |m| match m.to_ascii_lowercase().as_str() {
+            "name" | "size" | "mtime" | "time" => Some(IndexSort::new(m)),
+            _ => None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for AutoIndex {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match toml::Value::deserialize(d)? {
+            toml::Value::Boolean(true) => Ok(AutoIndex::HTML),
+            toml::Value::Boolean(false) => Ok(AutoIndex::OFF),
+            toml::Value::String(m) => match m.trim().to_ascii_lowercase().as_str() {
+                "off" | "false" | "html" | "on" | "true" | "json" => Ok(AutoIndex::new(m.trim())),
+                _ => Err(D::Error::custom(format!("unknown value {:?}, expected off, html or json", m))),
+            },
+            v => Err(D::Error::custom(format!("expected off, html or json, found {}", v))),
+        }
+    }
+}
+
+/// an `autoindex-order`, `true` when descending
+fn descending<'de, D: Deserializer<'de>>(d: D) -> Result<Option<bool>, D::Error> {
+    keyword(d, "asc or desc", |m| match m.to_ascii_lowercase().as_str() {
+        "asc" => Some(false),
+        "desc" => Some(true),
+        _ => None,
+    })
+    .map(Some)
+}
+
+/// a single value or an array of them
+fn one_or_many<'de, D: Deserializer<'de>, T: Deserialize<'de>>(d: D) -> Result<Vec<T>, D::Error> {
+    struct OneOrMany<T>(PhantomData<T>);
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a value or an array of values")
+        }
+        fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+            T::deserialize(v.into_deserializer()).map(|t| vec![t])
+        }
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            T::deserialize(MapAccessDeserializer::new(map)).map(|t| vec![t])
+        }
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+    }
+    d.deserialize_any(OneOrMany(PhantomData))
+}
+
+/// check the references between the sections of a parsed configuration
+fn validate(config: &Config, source: &str, upstream: &BTreeMap<String, ProxyConfig>) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    let known = |name: &str| name == DEFAULT_UPSTREAM || upstream.contains_key(name);
+    let unknown = |errors: &mut Vec<ConfigError>, name: &Spanned<String>| {
+        if !known(name.get_ref()) {
+            errors.push(ConfigError::at(
+                source,
+                name.span(),
+                format!("unknown upstream group {:?}, no [upstream.{}] table", name.get_ref(), name.get_ref()),
+            ));
+        }
+    };
+    let routes = config
+        .proxy
+        .iter()
+        .chain(config.vhost.iter().filter_map(|h| h.proxy.as_ref()))
+        .flat_map(|p| p.route.iter());
+    for route in routes {
+        if !route.prefix.get_ref().starts_with('/') {
+            errors.push(ConfigError::at(
+                source,
+                route.prefix.span(),
+                format!("route prefix {:?} does not start with /", route.prefix.get_ref()),
+            ));
+        }
+        if let Some(ref u) = route.upstream {
+            unknown(&mut errors, u);
+        }
+        if let Some(ref m) = route.mirror {
+            unknown(&mut errors, &m.upstream);
+        }
+        for s in route.split.iter() {
+            unknown(&mut errors, &s.upstream);
+        }
+    }
+    for stream in config.stream.iter() {
+        match stream.upstream {
+            Some(ref u) if u.get_ref() == DEFAULT_UPSTREAM => {}
+            Some(ref u) => unknown(&mut errors, u),
+            None if stream.target.is_empty() => errors.push(ConfigError::at(
+                source,
+                stream.listen.span(),
+                format!("stream {} has neither an upstream nor a target", stream.listen.get_ref().0),
+            )),
+            None => {}
+        }
+        if let Some(ref p) = stream.proxy_protocol {
+            if *p.get_ref() == ProxyProtocol::V1 && stream.protocol == Some(StreamProtocol::UDP) {
+                errors.push(ConfigError::at(
+                    source,
+                    p.span(),
+                    "PROXY protocol v1 is tcp only, use v2 for udp".to_string(),
+                ));
+            }
+        }
+    }
+    errors
+}
+
+impl LogConfig {
+    fn build(&self) -> LogSettings {
+        let mut settings = LogSettings::default();
+        if let Some(l) = self.level {
+            settings.level = l.0;
+        }
+        if let Some(ref f) = self.filter {
+            settings.filter = f.0.to_string();
+        }
+        if let Some(o) = self.output {
+            settings.output = o;
+        }
+        if let Some(f) = self.format {
+            settings.format = f;
+        }
+        if let Some(ref d) = self.directory {
+            settings.directory = d.to_string();
+        }
+        if let Some(ref f) = self.file {
+            settings.file = f.to_string();
+        }
+        if let Some(ref r) = self.rotation {
+            settings.rotation = r.0.clone();
+        }
+        settings.max_files = self.max_files.map(|n| n.get() as usize);
+        settings
+    }
+}
+
+impl AccessLogConfig {
+    fn build(&self) -> AccessLogSettings {
+        let mut settings = AccessLogSettings::default();
+        if let Some(e) = self.enable {
+            settings.enable = e;
+        }
+        if let Some(ref f) = self.format {
+            settings.format = f.clone();
+        }
+        if let Some(p) = self.percent {
+            settings.percent = p.0;
+        }
+        settings.sample = self.sample.iter().map(|s| (s.prefix.to_string(), s.percent.0)).collect();
+        settings.file = self.file.as_ref().map(|f| {
+            let mut file = LogSettings {
+                file: f.to_string(),
+                ..Default::default()
+            };
+            if let Some(ref d) = self.directory {
+                file.directory = d.to_string();
+            }
+            if let Some(ref r) = self.rotation {
+                file.rotation = r.0.clone();
+            }
+            file.max_files = self.max_files.map(|n| n.get() as usize);
+            file
+        });
+        settings
+    }
+}
+
+impl DirectoryConfig {
+    fn build(&self) -> Directory {
+        let mut directory = Directory::default();
+        if let Some(ref m) = self.mount {
+            directory.mount = m.to_string();
+        }
+        if let Some(ref p) = self.root_path {
+            directory.root_path = p.to_string();
+        }
+        if let Some(a) = self.autoindex {
+            directory.autoindex = a;
+        }
+        if let Some(s) = self.autoindex_sort {
+            directory.autoindex_sort = s;
+        }
+        if let Some(d) = self.autoindex_order {
+            directory.autoindex_desc = d;
+        }
+        if let Some(h) = self.hide_dotfiles {
+            directory.hide_dotfiles = h;
+        }
+        if let Some(ref f) = self.fallback {
+            directory.fallback = f.trim_start_matches('/').to_string();
+        }
+        directory
+    }
+}
+
+impl CompressionConfig {
+    fn build(&self) -> Compression {
+        let mut compression = Compression::default();
+        if let Some(e) = self.enable {
+            compression.enable = e;
+        }
+        if let Some(ref a) = self.algorithms {
+            compression.algorithms = a.clone();
+        }
+        if let Some(m) = self.min_size {
+            compression.min_size = m;
+        }
+        if let Some(ref t) = self.mime_types {
+            compression.mime_types = t.clone();
+        }
+        if let Some(l) = self.level {
+            compression.level = Some(l);
+        }
+        if let Some(p) = self.precompressed {
+            compression.precompressed = p;
+        }
+        compression
+    }
+}
+
+impl PoolConfig {
+    fn build(&self) -> PoolSettings {
+        let mut settings = PoolSettings::default();
+        if let Some(m) = self.max_idle {
+            settings.max_idle = m;
+        }
+        if let Some(t) = self.idle_timeout {
+            settings.idle_timeout = t.0;
+        }
+        if let Some(m) = self.max_per_host {
+            settings.max_per_host = m.get() as usize;
+        }
+        if let Some(t) = self.connect_timeout {
+            settings.connect_timeout = t.0;
+        }
+        settings
+    }
+}
+
+impl CacheConfig {
+    /// the cache settings, the disk tier directory is prepared here
+    fn build(&self) -> CacheSettings {
+        let mut settings = CacheSettings::default();
+        if let Some(s) = self.memory_size {
+            settings.memory_size = s.0;
+        }
+        if let Some(ref p) = self.disk_path {
+            match cache::prepare_disk(Path::new(p)) {
+                Ok(_) => settings.disk_path = Some(PathBuf::from(p)),
+                Err(e) => tracing::error!("[proxy.cache] disk tier {} unusable: {}", p, e),
+            }
+        }
+        if let Some(s) = self.disk_size {
+            settings.disk_size = s.0;
+        }
+        if let Some(s) = self.max_object_size {
+            settings.max_object_size = s.0;
+        }
+        if let Some(t) = self.default_ttl {
+            settings.default_ttl = t.0;
+        }
+        if let Some(ref a) = self.purge_allow {
+            settings.purge_allow = a.clone();
+        }
+        settings
+    }
+}
+
+impl VirtualHostConfig {
+    fn build(&self) -> VirtualHost {
+        VirtualHost {
+            server_names: self.server_name.iter().map(|n| n.to_ascii_lowercase()).collect(),
+            directories: self.directory.iter().map(DirectoryConfig::build).collect(),
+            proxy: self.proxy.as_ref().map(|p| Arc::new(p.build())),
+            proxy_routes: match self.proxy {
+                Some(ref p) => p.route.iter().map(RouteConfig::build).collect(),
+                None => vec![],
+            },
+        }
+    }
+}
+
+impl RouteConfig {
+    fn build(&self) -> ProxyRoute {
+        ProxyRoute {
+            prefix: self.prefix.get_ref().to_string(),
+            upstream: match self.upstream {
+                Some(ref u) => u.get_ref().to_string(),
+                None => DEFAULT_UPSTREAM.to_string(),
+            },
+            strip_prefix: self.strip_prefix,
+            timeout: ProxyTimeout {
+                connect: self.connect_timeout.map(|t| t.0),
+                first_byte: self.first_byte_timeout.map(|t| t.0),
+                total: self.total_timeout.map(|t| t.0),
+            },
+            retry: self.retry_policy(),
+            cache: self.cache,
+            rewrite: self.rewrite_rules(),
+            mirror: self.mirror.as_ref().map(|m| {
+                let mut mirror = Mirror {
+                    upstream: m.upstream.get_ref().to_string(),
+                    ..Default::default()
+                };
+                if let Some(p) = m.percent {
+                    mirror.percent = p.0;
+                }
+                if let Some(s) = m.max_body_size {
+                    mirror.max_body_size = s.0;
+                }
+                if let Some(t) = m.timeout {
+                    mirror.timeout = t.0;
+                }
+                mirror
+            }),
+            split: self.traffic_split(),
+        }
+    }
+    fn retry_policy(&self) -> RetryPolicy {
+        let mut retry = RetryPolicy::default();
+        if let Some(r) = self.retries {
+            retry.retries = r;
+        }
+        if let Some(ref a) = self.retry_on {
+            retry.retry_on = a.iter().map(|s| s.0.to_string()).collect();
+        }
+        if self.retry_budget.is_some() || self.retry_budget_min.is_some() {
+            retry.budget = Arc::new(RetryBudget::new(
+                self.retry_budget.unwrap_or(retry.budget.ratio),
+                self.retry_budget_min.unwrap_or(retry.budget.min_retries),
+            ));
+        }
+        retry
+    }
+    fn rewrite_rules(&self) -> RewriteRules {
+        RewriteRules {
+            rewrite: self
+                .rewrite
+                .iter()
+                .map(|r| RewriteRule {
+                    pattern: r.pattern.0.clone(),
+                    replace: r.replace.to_string(),
+                })
+                .collect(),
+            redirect: self
+                .redirect
+                .iter()
+                .map(|r| RedirectRule {
+                    pattern: r.pattern.0.clone(),
+                    to: r.to.to_string(),
+                    status: r.status.map_or("302", |s| s.0),
+                })
+                .collect(),
+            request_headers: self.request_headers.as_ref().map(|h| h.build()).unwrap_or_default(),
+            response_headers: self.response_headers.as_ref().map(|h| h.build()).unwrap_or_default(),
+        }
+    }
+    fn traffic_split(&self) -> Option<TrafficSplit> {
+        if self.split.is_empty() {
+            return None;
+        }
+        Some(TrafficSplit {
+            groups: Arc::new(
+                self.split
+                    .iter()
+                    .map(|s| SplitGroup {
+                        upstream: s.upstream.get_ref().to_string(),
+                        weight: s.weight.unwrap_or(1),
+                    })
+                    .collect(),
+            ),
+            header: self.split_header.clone(),
+            cookie: self.split_cookie.clone(),
+        })
+    }
+}
+
+impl HeaderRulesConfig {
+    fn build(&self) -> HeaderRules {
+        let pairs = |m: &BTreeMap<String, String>| m.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        HeaderRules {
+            remove: self.remove.clone(),
+            set: pairs(&self.set),
+            add: pairs(&self.add),
+        }
+    }
+}
+
+impl StreamConfig {
+    /// the table of the inline upstream group
+    fn group(&self) -> ProxyConfig {
+        ProxyConfig {
+            target: self.target.clone(),
+            mode: self.mode.clone(),
+            hash_key: self.hash_key.clone(),
+            health_check: self.health_check.clone(),
+            outlier: self.outlier.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            sticky: self.sticky.clone(),
+            ..Default::default()
+        }
+    }
+    /// the stream proxy relaying to an upstream group
+    fn build(&self, group: Arc<UpstreamGroup>) -> StreamProxy {
+        let mut stream = StreamProxy::new(&self.listen.get_ref().0, self.protocol.unwrap_or_default(), group);
+        stream.proxy_protocol = self.proxy_protocol.as_ref().map(|p| *p.get_ref());
+        if let Some(t) = self.connect_timeout {
+            stream.connect_timeout = t.0;
+        }
+        if let Some(t) = self.idle_timeout {
+            stream.idle_timeout = t.0;
+        }
+        if let Some(m) = self.max_sessions {
+            stream.max_sessions = m.get() as usize;
+        }
+        if let Some(r) = self.retries {
+            stream.retries = r;
+        }
+        stream
+    }
+}
+
+impl ForwardProxyConfig {
+    fn build(&self) -> ForwardProxy {
+        let mut proxy = ForwardProxy {
+            allow: self.allow.iter().map(|r| HostRule::new(r)).collect(),
+            deny: self.deny.iter().map(|r| HostRule::new(r)).collect(),
+            allow_private: self.allow_private,
+            users: self.users.iter().map(|u| u.0.to_string()).collect(),
+            ..ForwardProxy::default()
+        };
+        if let Some(ref ports) = self.connect_ports {
+            proxy.connect_ports = ports.clone();
+        }
+        if let Some(t) = self.connect_timeout {
+            proxy.connect_timeout = t.0;
+        }
+        if let Some(ref r) = self.realm {
+            proxy.realm = r.to_string();
+        }
+        proxy
+    }
+}
+
+impl ProxyConfig {
+    /// whether both tables configure the same upstream group, routes, pool and cache aside
+    fn same_group(&self, other: &ProxyConfig) -> bool {
+        self.target == other.target
+            && self.mode == other.mode
+            && self.hash_key == other.hash_key
+            && self.health_check == other.health_check
+            && self.outlier == other.outlier
+            && self.circuit_breaker == other.circuit_breaker
+            && self.sticky == other.sticky
+    }
+    /// the upstream group of the table
+    fn build(&self) -> UpstreamGroup {
+        let mut group = UpstreamGroup::new(
+            self.mode.clone().unwrap_or_default(),
+            self.target.clone(),
+            self.hash_key.clone().unwrap_or_default(),
+        );
+        group.health_check = self.health_check.as_ref().map(|h| h.build());
+        group.outlier = self.outlier.as_ref().map(|o| o.build());
+        group.circuit_breaker = self.circuit_breaker.as_ref().map(|b| b.build());
+        group.sticky = self.sticky.as_ref().map(|s| s.build());
+        group
+    }
+}
+
+impl HealthCheckConfig {
+    fn build(&self) -> HealthCheck {
+        let mut check = HealthCheck::default();
+        if let Some(k) = self.kind {
+            check.kind = k;
+        }
+        if let Some(ref p) = self.path {
+            check.path = p.to_string();
+        }
+        if let Some(i) = self.interval {
+            check.interval = i.0;
+        }
+        if let Some(t) = self.timeout {
+            check.timeout = t.0;
+        }
+        if let Some(ref s) = self.expected_status {
+            check.expected_status = s.0.clone();
+        }
+        if let Some(r) = self.rise {
+            check.rise = r.get();
+        }
+        if let Some(f) = self.fall {
+            check.fall = f.get();
+        }
+        check
+    }
+}
+
+impl StickyConfig {
+    fn build(&self) -> StickySession {
+        let mut sticky = StickySession::new(self.mode.clone());
+        if let Some(ref n) = self.cookie_name {
+            sticky.cookie_name = n.to_string();
+        }
+        if let Some(ref p) = self.cookie_path {
+            sticky.cookie_path = p.to_string();
+        }
+        if let Some(a) = self.cookie_max_age {
+            sticky.cookie_max_age = Some(a.0);
+        }
+        sticky
+    }
+}
+
+impl CircuitBreakerConfig {
+    fn build(&self) -> CircuitBreaker {
+        let mut breaker = CircuitBreaker::default();
+        if let Some(f) = self.failure_threshold {
+            breaker.failure_threshold = f.get();
+        }
+        if let Some(d) = self.open_duration {
+            breaker.open_duration = d.0;
+        }
+        if let Some(h) = self.half_open_requests {
+            breaker.half_open_requests = h.get();
+        }
+        breaker
+    }
+}
+
+impl OutlierConfig {
+    fn build(&self) -> OutlierDetection {
+        let mut outlier = OutlierDetection::default();
+        if let Some(c) = self.consecutive_failures {
+            outlier.consecutive_failures = c.get();
+        }
+        if let Some(b) = self.base_ejection {
+            outlier.base_ejection = b.0;
+        }
+        if let Some(m) = self.max_ejection {
+            outlier.max_ejection = m.0;
         }
+        outlier
     }
 }
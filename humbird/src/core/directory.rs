@@ -0,0 +1,115 @@
+/// local static resource directory settings
+use std::path::PathBuf;
+
+/// default mount point of a directory
+pub const DEFAULT_MOUNT: &str = "/";
+
+/// directory listing output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AutoIndex {
+    /// directory requests are answered with 404
+    #[default]
+    OFF,
+    /// html page
+    HTML,
+    /// json document
+    JSON,
+}
+
+impl AutoIndex {
+    pub fn new(m: &str) -> Self {
+        match m.to_ascii_lowercase().as_str() {
+            "html" | "on" | "true" => AutoIndex::HTML,
+            "json" => AutoIndex::JSON,
+            _ => AutoIndex::OFF,
+        }
+    }
+}
+
+/// directory listing sort key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexSort {
+    #[default]
+    NAME,
+    SIZE,
+    MTIME,
+}
+
+impl IndexSort {
+    pub fn new(m: &str) -> Self {
+        match m.to_ascii_lowercase().as_str() {
+            "size" => IndexSort::SIZE,
+            "mtime" | "time" => IndexSort::MTIME,
+            _ => IndexSort::NAME,
+        }
+    }
+}
+
+/// static resource directory abstract
+#[derive(Debug, Clone)]
+pub struct Directory {
+    /// url path prefix the directory is served under
+    pub mount: String,
+    /// local static resources root path
+    pub root_path: String,
+    /// directory listing mode
+    pub autoindex: AutoIndex,
+    /// default listing sort key, can be overridden with the `sort` query parameter
+    pub autoindex_sort: IndexSort,
+    /// default listing order is descending, can be overridden with the `order` query parameter
+    pub autoindex_desc: bool,
+    /// leave entries starting with `.` out of listings and refuse to serve them
+    pub hide_dotfiles: bool,
+    /// file relative to the root served for unknown paths, e.g. `index.html` of a single page application
+    pub fallback: String,
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Directory {
+            mount: DEFAULT_MOUNT.to_string(),
+            root_path: String::default(),
+            autoindex: AutoIndex::default(),
+            autoindex_sort: IndexSort::default(),
+            autoindex_desc: false,
+            hide_dotfiles: false,
+            fallback: String::default(),
+        }
+    }
+}
+
+impl Directory {
+    /// path of the request relative to the mount point, `None` if it is not under the mount
+    pub fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let mount = self.mount.trim_end_matches('/');
+        let rest = path.strip_prefix(mount)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest.trim_start_matches('/'))
+        } else {
+            None
+        }
+    }
+    /// local file path of a relative request path, parent directory segments are refused
+    pub fn local_path(&self, relative: &str) -> Option<PathBuf> {
+        if relative.split('/').any(|s| s == "..") {
+            return None;
+        }
+        if self.hide_dotfiles && relative.split('/').any(|s| s.starts_with('.')) {
+            return None;
+        }
+        let root = if self.root_path.is_empty() {
+            "."
+        } else {
+            self.root_path.as_str()
+        };
+        Some(PathBuf::from(root).join(relative))
+    }
+}
+
+/// the directory with the longest mount point that contains the path
+pub fn find<'a>(directories: &'a [Directory], path: &str) -> Option<&'a Directory> {
+    directories
+        .iter()
+        .filter(|d| d.relative(path).is_some())
+        .max_by_key(|d| d.mount.trim_end_matches('/').len())
+}
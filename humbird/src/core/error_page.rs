@@ -0,0 +1,68 @@
+/// custom error pages, a static file or a handler per status code
+use lazy_static::lazy_static;
+use std::{collections::HashMap, fs, path::Path, sync::Mutex};
+
+use crate::{
+    core::settings,
+    protocol::{
+        http::{HttpRequestProcess, Request, Response},
+        mime,
+    },
+};
+
+lazy_static! {
+    /// error page handlers, keyed by status code
+    pub static ref ERROR_PAGE_TABLE: Mutex<HashMap<String, HttpRequestProcess>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+/// macro for registering error page handlers, a handler takes precedence over
+/// an `[error-page]` file of the same status code
+///
+/// Example
+/// ```rust
+/// use humbird::{error_page, protocol::http::{Request, Response}};
+///
+/// fn not_found(_req: Request, mut res: Response) -> Response {
+///     res.set_body("nothing here");
+///     res
+/// }
+/// error_page!(404 => not_found);
+/// ```
+#[macro_export]
+macro_rules! error_page {
+    ($($code:expr => $process:expr),*) => {
+        $(
+            $crate::core::error_page::ERROR_PAGE_TABLE.lock().unwrap().insert($code.to_string(), $process);
+        )*;
+    };
+}
+
+/// replace the default body of an error response with the configured error page
+pub fn apply(request: &Request, mut response: Response) -> Response {
+    let code = response.status_code().to_string();
+    // the page replaces the body, the status stays
+    response.error_handled();
+    let handler = match ERROR_PAGE_TABLE.lock() {
+        Ok(t) => t.get(&code).copied(),
+        Err(_) => None,
+    };
+    if let Some(process) = handler {
+        return process(request.clone(), response);
+    }
+    let file = settings::current().error_pages.get(&code).cloned();
+    if let Some(f) = file {
+        match fs::read(&f) {
+            Ok(body) => {
+                response.set_head("Content-Type", mime::from_path(Path::new(&f)));
+                response.set_body_bytes(body);
+            }
+            Err(e) => {
+                tracing::error!("error page {} of status {} unreadable: {}", f, code, e);
+            }
+        }
+    }
+    response
+}
@@ -0,0 +1,425 @@
+/// forward proxy, relays absolute form requests and tunnels `CONNECT` requests to
+/// the targets they name, for clients using the server as their http proxy
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::error;
+
+use crate::{
+    core::{access_log, error_page, proxy::is_hop_by_hop, settings},
+    protocol::http::{Method, Request, Response},
+};
+
+/// forward proxy settings
+#[derive(Debug, Clone)]
+pub struct ForwardProxy {
+    /// targets that may be reached, every target when empty
+    pub allow: Vec<HostRule>,
+    /// targets that may not be reached, checked before the allow list
+    pub deny: Vec<HostRule>,
+    /// whether loopback, link-local and private addresses may be reached, they are checked
+    /// once the target is resolved
+    pub allow_private: bool,
+    /// ports a tunnel may be opened to, any port when empty
+    pub connect_ports: Vec<u16>,
+    /// time allowed to connect to a target
+    pub connect_timeout: Duration,
+    /// accepted basic credentials, `user:password`, authentication is off when empty
+    pub users: Vec<String>,
+    /// realm announced by the `Proxy-Authenticate` challenge
+    pub realm: String,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        ForwardProxy {
+            allow: vec![],
+            deny: vec![],
+            allow_private: false,
+            connect_ports: vec![443],
+            connect_timeout: Duration::from_secs(10),
+            users: vec![],
+            realm: "humbird".to_string(),
+        }
+    }
+}
+
+/// a target pattern of the allow and deny lists, `*` matches every host and `*.example.com`
+/// the subdomains of `example.com`, a `:port` suffix restricts the port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRule {
+    host: String,
+    port: Option<u16>,
+}
+
+impl HostRule {
+    /// parse a pattern
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::forward_proxy::HostRule;
+    /// let rule = HostRule::new("*.crates.io:443");
+    /// assert!(rule.matches("static.crates.io", 443));
+    /// assert!(!rule.matches("static.crates.io", 80));
+    /// assert!(!rule.matches("crates.io", 443));
+    /// assert!(HostRule::new("[::1]").matches("::1", 8080));
+    /// ```
+    pub fn new(m: &str) -> Self {
+        let m = m.trim();
+        match split_authority(m, None) {
+            Some((host, port)) => HostRule {
+                host: host.to_ascii_lowercase(),
+                port: Some(port),
+            },
+            None => HostRule {
+                host: m.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase(),
+                port: None,
+            },
+        }
+    }
+    /// whether a target matches the pattern
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+        let host = host.to_ascii_lowercase();
+        match self.host.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => self.host == host,
+        }
+    }
+}
+
+/// why a forward proxy request failed
+#[derive(Debug)]
+pub enum ForwardProxyError {
+    /// answered with the status code, nothing has been relayed
+    REJECTED(&'static str, String),
+    /// the exchange broke off after the response started
+    ABORTED(String),
+}
+
+impl ForwardProxyError {
+    /// status code answered to the client, `None` once the response has started
+    pub fn status_code(&self) -> Option<&'static str> {
+        match self {
+            ForwardProxyError::REJECTED(code, _) => Some(code),
+            ForwardProxyError::ABORTED(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ForwardProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardProxyError::REJECTED(code, e) => write!(f, "{} {}", code, e),
+            ForwardProxyError::ABORTED(e) => write!(f, "aborted, {}", e),
+        }
+    }
+}
+
+/// the forward proxy serving a request, `None` for requests to the server itself
+pub fn find(request: &Request) -> Option<Arc<ForwardProxy>> {
+    if request.method() != Method::CONNECT && !request.path().contains("://") {
+        return None;
+    }
+    settings::current().forward_proxy.clone()
+}
+
+impl ForwardProxy {
+    /// serve a proxy request, the client connection is closed afterwards
+    pub async fn serve(self: Arc<Self>, mut request: Request, client: TcpStream) -> Result<(), String> {
+        let mut client = access_log::Counted::new(client);
+        let mut upstream = None;
+        let result = match request.read_body(&mut client).await {
+            Ok(_) => self.relay(&request, &mut client, &mut upstream).await,
+            Err(code) => Err(ForwardProxyError::REJECTED(
+                code,
+                "request body too large or unreadable".to_string(),
+            )),
+        };
+        let status = match result {
+            Ok(ref s) => s.clone(),
+            Err(ref e) => e.status_code().unwrap_or("-").to_string(),
+        };
+        if let Err(ref e) = result {
+            error!("forward proxy {} failed: {}", request.path(), e);
+            if let Some(code) = e.status_code() {
+                let mut response = Response::blank(&request);
+                response.error(code);
+                let mut response = error_page::apply(&request, response);
+                if code == "407" {
+                    response.set_head("Proxy-Authenticate", &format!("Basic realm=\"{}\"", self.realm));
+                }
+                response.set_head("Connection", "close");
+                response.make_raw();
+                let _ = client.write_all(&response.raw()).await;
+            }
+        }
+        let _ = client.shutdown().await;
+        access_log::log(&request, &status, Some(client.written()), upstream.as_deref());
+        result.map(|_| ()).map_err(|e| e.to_string())
+    }
+    /// check the request and relay it to its target, returns the relayed status code,
+    /// `upstream` receives the address of the target once it is known
+    async fn relay<C: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        request: &Request,
+        client: &mut C,
+        upstream: &mut Option<String>,
+    ) -> Result<String, ForwardProxyError> {
+        if !self.authorized(request) {
+            return Err(ForwardProxyError::REJECTED("407", "missing or wrong credentials".to_string()));
+        }
+        let tunnel = request.method() == Method::CONNECT;
+        let target = if tunnel {
+            split_authority(request.path(), None).map(|(h, p)| (h, p, String::default()))
+        } else {
+            absolute_target(request.path())
+        };
+        let (host, port, path) = match target {
+            Some(t) => t,
+            None => {
+                return Err(ForwardProxyError::REJECTED(
+                    "400",
+                    format!("unsupported target {}", request.path()),
+                ))
+            }
+        };
+        if !self.allows(&host, port, tunnel) {
+            return Err(ForwardProxyError::REJECTED("403", format!("{}:{} is not allowed", host, port)));
+        }
+        let address = match host.contains(':') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        *upstream = Some(address.clone());
+        let connect = async {
+            // the resolved addresses are checked and connected to, a name resolving
+            // to a private address is refused whatever it resolved to before
+            let resolved: Vec<SocketAddr> = match tokio::net::lookup_host(&address).await {
+                Ok(r) => r.filter(|a| self.allow_private || is_public(a.ip())).collect(),
+                Err(e) => return Err(ForwardProxyError::REJECTED("502", format!("resolve {}: {}", address, e))),
+            };
+            if resolved.is_empty() {
+                return Err(ForwardProxyError::REJECTED(
+                    "403",
+                    format!("{} resolves to a private address", address),
+                ));
+            }
+            TcpStream::connect(&resolved[..])
+                .await
+                .map_err(|e| ForwardProxyError::REJECTED("502", format!("connect {}: {}", address, e)))
+        };
+        let mut upstream = match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                return Err(ForwardProxyError::REJECTED(
+                    "504",
+                    format!("connect {}: timed out after {:?}", address, self.connect_timeout),
+                ))
+            }
+        };
+        if tunnel {
+            if let Err(e) = client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await {
+                return Err(ForwardProxyError::ABORTED(format!("write to client: {}", e)));
+            }
+            return match tokio::io::copy_bidirectional(client, &mut upstream).await {
+                Ok(_) => Ok("200".to_string()),
+                Err(e) => Err(ForwardProxyError::ABORTED(format!("tunnel with {}: {}", address, e))),
+            };
+        }
+        if let Err(e) = upstream.write_all(&origin_request(request, &path)).await {
+            return Err(ForwardProxyError::REJECTED("502", format!("send to {}: {}", address, e)));
+        }
+        let mut r_buf = BufReader::new(upstream);
+        let mut status_line = String::default();
+        match r_buf.read_line(&mut status_line).await {
+            Ok(n) if n > 0 && status_line.starts_with("HTTP/") => {}
+            Ok(_) => return Err(ForwardProxyError::REJECTED("502", format!("invalid response of {}", address))),
+            Err(e) => return Err(ForwardProxyError::REJECTED("502", format!("read from {}: {}", address, e))),
+        }
+        if let Err(e) = client.write_all(status_line.as_bytes()).await {
+            return Err(ForwardProxyError::ABORTED(format!("write to client: {}", e)));
+        }
+        // the target was asked to close the connection, its response ends with it
+        if let Err(e) = tokio::io::copy_buf(&mut r_buf, client).await {
+            return Err(ForwardProxyError::ABORTED(format!("relay from {}: {}", address, e)));
+        }
+        Ok(status_line.split(' ').nth(1).unwrap_or("-").trim().to_string())
+    }
+    /// whether the request carries accepted credentials
+    fn authorized(&self, request: &Request) -> bool {
+        if self.users.is_empty() {
+            return true;
+        }
+        let credentials = match request.head("Proxy-Authorization").and_then(|v| v.split_once(' ')) {
+            Some((scheme, c)) if scheme.eq_ignore_ascii_case("Basic") => c.trim(),
+            _ => return false,
+        };
+        match STANDARD.decode(credentials) {
+            Ok(c) => self.users.iter().fold(false, |found, u| constant_time_eq(u.as_bytes(), &c) | found),
+            Err(_) => false,
+        }
+    }
+    /// whether a target may be reached
+    fn allows(&self, host: &str, port: u16, tunnel: bool) -> bool {
+        if tunnel && !self.connect_ports.is_empty() && !self.connect_ports.contains(&port) {
+            return false;
+        }
+        if self.deny.iter().any(|r| r.matches(host, port)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|r| r.matches(host, port))
+    }
+}
+
+/// whether an address may be reached by default, loopback, link-local, private, shared,
+/// reserved, multicast and unspecified addresses are not, nor the ipv6 addresses that
+/// embed one of them
+///
+/// Example
+/// ```rust
+/// use humbird::core::forward_proxy::is_public;
+/// assert!(is_public("93.184.216.34".parse().unwrap()));
+/// assert!(is_public("2606:2800:220:1::1".parse().unwrap()));
+/// assert!(!is_public("127.0.0.1".parse().unwrap()));
+/// assert!(!is_public("169.254.169.254".parse().unwrap()));
+/// assert!(!is_public("192.168.1.7".parse().unwrap()));
+/// assert!(!is_public("0.1.2.3".parse().unwrap()));
+/// assert!(!is_public("100.64.0.1".parse().unwrap()));
+/// assert!(!is_public("192.0.0.8".parse().unwrap()));
+/// assert!(!is_public("198.18.0.1".parse().unwrap()));
+/// assert!(!is_public("240.0.0.1".parse().unwrap()));
+/// assert!(!is_public("224.0.0.1".parse().unwrap()));
+/// assert!(!is_public("::ffff:10.0.0.1".parse().unwrap()));
+/// assert!(!is_public("64:ff9b::127.0.0.1".parse().unwrap()));
+/// assert!(!is_public("2002:a00:1::1".parse().unwrap()));
+/// assert!(!is_public("::127.0.0.1".parse().unwrap()));
+/// assert!(!is_public("fd00::1".parse().unwrap()));
+/// assert!(!is_public("fe80::1".parse().unwrap()));
+/// assert!(!is_public("ff02::1".parse().unwrap()));
+/// ```
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_private()
+                || v4.is_multicast()
+                || a == 0
+                // shared address space 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // protocol assignments 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18)
+                // reserved 240.0.0.0/4, the broadcast address included
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            let octets = v6.octets();
+            let embedded = |o: &[u8]| IpAddr::V4(Ipv4Addr::new(o[0], o[1], o[2], o[3]));
+            if v6.is_loopback() || v6.is_unspecified() {
+                return false;
+            }
+            // ipv4-mapped ::ffff:0:0/96 and ipv4-compatible ::/96
+            if let Some(v4) = v6.to_ipv4() {
+                return is_public(IpAddr::V4(v4));
+            }
+            // NAT64 64:ff9b::/96
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public(embedded(&octets[12..]));
+            }
+            // 6to4 2002::/16
+            if segments[0] == 0x2002 {
+                return is_public(embedded(&octets[2..6]));
+            }
+            // unique local fc00::/7, link-local fe80::/10 and multicast ff00::/8
+            !(segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80 || segments[0] & 0xff00 == 0xff00)
+        }
+    }
+}
+
+/// compare two byte strings in a time that depends on their lengths only
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// split `host:port` into its parts, ipv6 hosts are bracketed and returned without
+/// the brackets, `default_port` is taken when the port is missing
+fn split_authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(a) => {
+            let (host, rest) = a.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(p) => p.parse().ok()?,
+        None => default_port?,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+/// host, port and origin form path of an absolute form `http://` target
+fn absolute_target(target: &str) -> Option<(String, u16, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") {
+        return None;
+    }
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // user information is never sent on
+    let authority = authority.rsplit_once('@').map_or(authority, |a| a.1);
+    let (host, port) = split_authority(authority, Some(80))?;
+    Some((host, port, path.to_string()))
+}
+
+/// the request as sent to its target, in origin form without hop-by-hop and proxy headers
+fn origin_request(request: &Request, path: &str) -> Vec<u8> {
+    let mut target = path.to_string();
+    if !request.query().is_empty() {
+        target.push('?');
+        target.push_str(request.query());
+    }
+    let mut h = format!("{} {} HTTP/1.1\r\n", request.method().as_str(), target);
+    let connection = request.head("Connection");
+    for (k, v) in request.heads() {
+        if is_hop_by_hop(k, connection) {
+            continue;
+        }
+        h.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    if !request.body().is_empty() && request.head("Content-Length").is_none() {
+        h.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+    }
+    h.push_str("Connection: close\r\n\r\n");
+    let mut raw = h.into_bytes();
+    raw.extend(request.body());
+    raw
+}
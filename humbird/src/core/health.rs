@@ -0,0 +1,262 @@
+/// upstream health, active http checks and passive outlier detection
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{info, warn};
+
+use crate::core::balancer::UpstreamGroup;
+
+/// how an upstream is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CheckKind {
+    /// an http request whose status must be an expected one
+    #[default]
+    HTTP,
+    /// a tcp connection, for upstreams of stream proxies
+    TCP,
+}
+
+impl CheckKind {
+    pub fn new(m: &str) -> Self {
+        match m.trim().to_ascii_uppercase().as_str() {
+            "TCP" => CheckKind::TCP,
+            _ => CheckKind::HTTP,
+        }
+    }
+}
+
+/// settings of an active health check, the `health-check` table of an upstream group
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub kind: CheckKind,
+    /// path requested from every upstream
+    pub path: String,
+    /// time between two checks of an upstream
+    pub interval: Duration,
+    /// time allowed for a check to receive the status line
+    pub timeout: Duration,
+    /// accepted status codes, `2xx` style entries match a whole class
+    pub expected_status: Vec<String>,
+    /// consecutive passed checks marking an unhealthy upstream healthy
+    pub rise: u32,
+    /// consecutive failed checks marking a healthy upstream unhealthy
+    pub fall: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            kind: CheckKind::HTTP,
+            path: "/".to_string(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            expected_status: vec!["2xx".to_string(), "3xx".to_string()],
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+impl HealthCheck {
+    /// whether a status code is an expected one
+    pub fn expects(&self, status: &str) -> bool {
+        self.expected_status.iter().any(|e| {
+            match e.to_ascii_lowercase().strip_suffix("xx") {
+                Some(class) => status.len() == 3 && status.starts_with(class),
+                None => e == status,
+            }
+        })
+    }
+}
+
+/// settings of passive outlier detection, the `outlier` table of an upstream group
+#[derive(Debug, Clone)]
+pub struct OutlierDetection {
+    /// consecutive failed requests ejecting an upstream
+    pub consecutive_failures: u32,
+    /// ejection time of a first ejection, doubled by every following one
+    pub base_ejection: Duration,
+    /// longest ejection time
+    pub max_ejection: Duration,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            consecutive_failures: 5,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+        }
+    }
+}
+
+/// health state of an upstream
+#[derive(Debug)]
+pub struct UpstreamHealth {
+    /// verdict of the active check, healthy until a check says otherwise
+    healthy: AtomicBool,
+    /// consecutive passed active checks
+    passes: AtomicU32,
+    /// consecutive failed active checks
+    fails: AtomicU32,
+    /// consecutive failed requests
+    failures: AtomicU32,
+    /// ejections since the last successful request, grows the backoff
+    ejections: AtomicU32,
+    /// end of the current ejection
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Default for UpstreamHealth {
+    fn default() -> Self {
+        UpstreamHealth {
+            healthy: AtomicBool::new(true),
+            passes: AtomicU32::new(0),
+            fails: AtomicU32::new(0),
+            failures: AtomicU32::new(0),
+            ejections: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+}
+
+impl UpstreamHealth {
+    /// whether the upstream may receive requests
+    pub fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        match self.ejected_until.lock() {
+            Ok(u) => u.is_none_or(|u| Instant::now() >= u),
+            Err(_) => true,
+        }
+    }
+    /// whether the active check considers the upstream healthy
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+    /// record the result of an active check, returns the new verdict when it changed
+    pub fn check_result(&self, passed: bool, check: &HealthCheck) -> Option<bool> {
+        if passed {
+            self.fails.store(0, Ordering::Relaxed);
+            let passes = self.passes.fetch_add(1, Ordering::Relaxed) + 1;
+            if !self.is_healthy() && passes >= check.rise {
+                self.healthy.store(true, Ordering::Relaxed);
+                return Some(true);
+            }
+        } else {
+            self.passes.store(0, Ordering::Relaxed);
+            let fails = self.fails.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.is_healthy() && fails >= check.fall {
+                self.healthy.store(false, Ordering::Relaxed);
+                return Some(false);
+            }
+        }
+        None
+    }
+    /// record the outcome of a proxied request, returns the ejection time when the
+    /// upstream has just been ejected
+    pub fn request_result(&self, ok: bool, outlier: &OutlierDetection) -> Option<Duration> {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            self.ejections.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < outlier.consecutive_failures.max(1) {
+            return None;
+        }
+        self.failures.store(0, Ordering::Relaxed);
+        let ejections = self.ejections.fetch_add(1, Ordering::Relaxed);
+        let backoff = outlier
+            .base_ejection
+            .saturating_mul(1 << ejections.min(16))
+            .min(outlier.max_ejection);
+        if let Ok(mut u) = self.ejected_until.lock() {
+            *u = Some(Instant::now() + backoff);
+        }
+        Some(backoff)
+    }
+}
+
+/// check every upstream of a group at the configured interval, runs until the runtime stops
+pub async fn check_loop(group: Arc<UpstreamGroup>) {
+    let check = match group.health_check {
+        Some(ref c) => c.clone(),
+        None => return,
+    };
+    let mut ticker = tokio::time::interval(check.interval.max(Duration::from_millis(100)));
+    loop {
+        ticker.tick().await;
+        for (i, u) in group.upstreams.iter().enumerate() {
+            let result = match check.kind {
+                CheckKind::HTTP => probe(&u.address, &check).await,
+                CheckKind::TCP => connect(&u.address, &check).await.map(|_| String::default()),
+            };
+            let passed = match result {
+                Ok(_) if check.kind == CheckKind::TCP => true,
+                Ok(status) if check.expects(&status) => true,
+                Ok(status) => {
+                    warn!("health check of {} answered {}", u.address, status);
+                    false
+                }
+                Err(e) => {
+                    warn!("health check of {} failed: {}", u.address, e);
+                    false
+                }
+            };
+            match group.health(i).check_result(passed, &check) {
+                Some(true) => info!("upstream {} is healthy", u.address),
+                Some(false) => warn!("upstream {} is unhealthy", u.address),
+                None => {}
+            }
+        }
+    }
+}
+
+/// open a connection within the check timeout
+async fn connect(address: &str, check: &HealthCheck) -> Result<TcpStream, String> {
+    match tokio::time::timeout(check.timeout, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(format!("connect: {}", e)),
+        Err(_) => Err(format!("timed out after {:?}", check.timeout)),
+    }
+}
+
+/// request the check path and return the status code
+async fn probe(address: &str, check: &HealthCheck) -> Result<String, String> {
+    let request = async {
+        let mut stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("connect: {}", e))?;
+        let raw = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: humbird-health-check\r\nConnection: close\r\n\r\n",
+            check.path, address
+        );
+        stream
+            .write_all(raw.as_bytes())
+            .await
+            .map_err(|e| format!("send: {}", e))?;
+        let mut status_line = String::default();
+        BufReader::new(&mut stream)
+            .read_line(&mut status_line)
+            .await
+            .map_err(|e| format!("read: {}", e))?;
+        match status_line.split(' ').nth(1) {
+            Some(s) if status_line.starts_with("HTTP/") => Ok(s.trim().to_string()),
+            _ => Err("invalid response".to_string()),
+        }
+    };
+    match tokio::time::timeout(check.timeout, request).await {
+        Ok(r) => r,
+        Err(_) => Err(format!("timed out after {:?}", check.timeout)),
+    }
+}
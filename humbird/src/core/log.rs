@@ -0,0 +1,187 @@
+/// log pipeline, level filters per target, output to stdout, a rotated file or both, as
+/// text or json lines
+use chrono::Local;
+use lazy_static::lazy_static;
+use std::sync::{Mutex, Once, RwLock};
+use tracing::Level;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::{format::Writer, time::FormatTime, MakeWriter},
+    layer::SubscriberExt,
+    registry::Registry,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+lazy_static! {
+    /// log settings, read once when the log system starts
+    pub static ref LOG_SETTINGS: RwLock<LogSettings> = RwLock::new(LogSettings::default());
+    /// the writer threads of the log files flush their lines until these guards drop
+    static ref GUARDS: Mutex<Vec<WorkerGuard>> = Mutex::new(vec![]);
+}
+
+/// where the log lines go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogOutput {
+    STDOUT,
+    FILE,
+    #[default]
+    BOTH,
+}
+
+/// log line format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// time, level, target and message
+    #[default]
+    TEXT,
+    /// a json object per line
+    JSON,
+}
+
+/// log settings
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    /// most verbose level logged by targets without a filter directive
+    pub level: Level,
+    /// filter directives in `RUST_LOG` syntax, e.g. "humbird::core::proxy=debug"
+    pub filter: String,
+    pub output: LogOutput,
+    pub format: LogFormat,
+    /// directory of the log files, the working directory when empty
+    pub directory: String,
+    /// log file name, dated by the rotation
+    pub file: String,
+    /// how often a new log file starts
+    pub rotation: Rotation,
+    /// rotated files kept, the oldest ones are deleted, every file is kept when `None`
+    pub max_files: Option<usize>,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: Level::TRACE,
+            filter: String::default(),
+            output: LogOutput::default(),
+            format: LogFormat::default(),
+            directory: String::default(),
+            file: "humbird.log".to_string(),
+            rotation: Rotation::DAILY,
+            max_files: None,
+        }
+    }
+}
+
+impl LogSettings {
+    /// level filter, `RUST_LOG` replaces the level and the filter directives when it is set
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::log::LogSettings;
+    /// use tracing::Level;
+    /// let settings = LogSettings { level: Level::INFO, filter: "humbird::core::proxy=debug".to_string(), ..Default::default() };
+    /// assert_eq!(settings.directives(), "info,humbird::core::proxy=debug");
+    /// ```
+    pub fn directives(&self) -> String {
+        let level = self.level.to_string().to_lowercase();
+        match self.filter.trim() {
+            "" => level,
+            f => format!("{},{}", level, f),
+        }
+    }
+    fn env_filter(&self) -> EnvFilter {
+        match std::env::var("RUST_LOG") {
+            Ok(ref v) if !v.trim().is_empty() => match EnvFilter::try_new(v) {
+                Ok(f) => return f,
+                Err(e) => eprintln!("RUST_LOG ignored: {}", e),
+            },
+            _ => {}
+        }
+        EnvFilter::try_new(self.directives()).unwrap_or_else(|_| EnvFilter::new(self.level.to_string()))
+    }
+    /// rotated file appender of the settings
+    pub fn appender(&self) -> Result<RollingFileAppender, String> {
+        let mut builder = RollingFileAppender::builder()
+            .rotation(self.rotation.clone())
+            .filename_prefix(&self.file);
+        if let Some(n) = self.max_files {
+            builder = builder.max_log_files(n);
+        }
+        let directory = match self.directory.is_empty() {
+            true => ".",
+            false => &self.directory,
+        };
+        match builder.build(directory) {
+            Ok(a) => Ok(a),
+            Err(e) => Err(format!("log file {} in {} not opened: {}", self.file, directory, e)),
+        }
+    }
+}
+
+/// log time
+struct LocalTimer;
+
+impl FormatTime for LocalTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", Local::now().format("%FT%T%.3f"))
+    }
+}
+
+/// formatting layer writing to a writer
+fn layer<W>(format: LogFormat, writer: W) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_level(true)
+        .with_target(true)
+        .with_timer(LocalTimer);
+    match format {
+        LogFormat::TEXT => layer.boxed(),
+        LogFormat::JSON => layer.json().boxed(),
+    }
+}
+
+/// start the log system with the log settings, once, later calls do nothing
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(start);
+}
+
+fn start() {
+    let settings = LOG_SETTINGS.read().unwrap().clone();
+    let mut layers = vec![];
+    if settings.output != LogOutput::FILE {
+        layers.push(layer(settings.format, std::io::stdout));
+    }
+    if settings.output != LogOutput::STDOUT {
+        match settings.appender() {
+            Ok(appender) => {
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                layers.push(layer(settings.format, writer));
+                keep(guard);
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    let _ = tracing_subscriber::registry()
+        .with(layers)
+        .with(settings.env_filter())
+        .try_init();
+}
+
+/// keep the writer thread of a log file running for the lifetime of the server
+pub fn keep(guard: WorkerGuard) {
+    GUARDS.lock().unwrap().push(guard);
+}
+
+/// write the lines waiting for the log files, before the process exits
+pub fn flush() {
+    GUARDS.lock().unwrap().clear();
+}
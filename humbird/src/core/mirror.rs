@@ -0,0 +1,218 @@
+/// traffic mirroring, copies a sample of the requests of a proxy route to a shadow
+/// upstream group whose responses are discarded, the shadow status codes are compared
+/// to the ones the client got
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::oneshot,
+};
+use tracing::info;
+
+use crate::{
+    core::{balancer::random_u64, proxy, settings},
+    protocol::http::Request,
+};
+
+/// time between two looks at the mirror counters
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// mirror settings of a proxy route
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    /// shadow upstream group name
+    pub upstream: String,
+    /// percentage of the requests mirrored
+    pub percent: f64,
+    /// requests with a larger body are not mirrored
+    pub max_body_size: usize,
+    /// time allowed to the shadow upstream to connect and answer the response head
+    pub timeout: Duration,
+    /// counters, shared by the clones of the route
+    pub metrics: Arc<MirrorMetrics>,
+}
+
+impl Default for Mirror {
+    fn default() -> Self {
+        Mirror {
+            upstream: String::default(),
+            percent: 100.0,
+            max_body_size: 64 << 10,
+            timeout: Duration::from_secs(5),
+            metrics: Arc::new(MirrorMetrics::default()),
+        }
+    }
+}
+
+/// mirror counters
+#[derive(Debug, Default)]
+pub struct MirrorMetrics {
+    /// requests sent to the shadow upstream
+    pub mirrored: AtomicU64,
+    /// sampled requests left out for the size of their body
+    pub oversized: AtomicU64,
+    /// mirrored requests without a shadow response, for lack of an upstream,
+    /// a connection or a response within the timeout
+    pub failed: AtomicU64,
+    /// shadow responses with the status code of the client response
+    pub matched: AtomicU64,
+    /// shadow responses with another status code
+    pub mismatched: AtomicU64,
+}
+
+/// point in time copy of the mirror counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MirrorMetricsSnapshot {
+    pub mirrored: u64,
+    pub oversized: u64,
+    pub failed: u64,
+    pub matched: u64,
+    pub mismatched: u64,
+}
+
+impl Mirror {
+    /// mirror the request when it is sampled, `raw` is the request as sent to the primary
+    /// upstream, the returned sender takes the status code of the client response
+    pub fn send(&self, request: &Request, raw: &[u8]) -> Option<oneshot::Sender<String>> {
+        if (random_u64() % 10000) as f64 >= self.percent * 100.0 {
+            return None;
+        }
+        if request.body().len() > self.max_body_size {
+            self.metrics.oversized.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.metrics.mirrored.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let (mirror, request, raw) = (self.clone(), request.clone(), raw.to_vec());
+        tokio::spawn(async move {
+            let shadow = match tokio::time::timeout(mirror.timeout, mirror.exchange(&request, &raw)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => {
+                    mirror.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                    info!("mirror of {} to {} failed: {}", request.path(), mirror.upstream, e);
+                    return;
+                }
+                Err(_) => {
+                    mirror.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                    info!("mirror of {} to {} timed out", request.path(), mirror.upstream);
+                    return;
+                }
+            };
+            // the client response is unknown when the exchange was cut short
+            let primary: String = match rx.await {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            if primary == shadow {
+                mirror.metrics.matched.fetch_add(1, Ordering::Relaxed);
+            } else {
+                mirror.metrics.mismatched.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "mirror of {} answered {}, {} answered {}",
+                    request.path(),
+                    primary,
+                    mirror.upstream,
+                    shadow
+                );
+            }
+        });
+        Some(tx)
+    }
+    /// send the request to the shadow upstream, returns its status code, the rest of
+    /// the response is discarded
+    async fn exchange(&self, request: &Request, raw: &[u8]) -> Result<String, String> {
+        let group = match proxy::upstream_group(&self.upstream) {
+            Some(g) => g,
+            None => return Err(format!("unknown upstream group {}", self.upstream)),
+        };
+        let lease = match group.acquire(request) {
+            Some(l) => l,
+            None => return Err("no upstream available".to_string()),
+        };
+        let address = lease.upstream().address.clone();
+        let mut stream = match TcpStream::connect(&address).await {
+            Ok(s) => s,
+            Err(e) => {
+                lease.report(false);
+                return Err(format!("connect {}: {}", address, e));
+            }
+        };
+        if let Err(e) = stream.write_all(raw).await {
+            lease.report(false);
+            return Err(format!("send to {}: {}", address, e));
+        }
+        let mut status_line = String::default();
+        match BufReader::new(&mut stream).read_line(&mut status_line).await {
+            Ok(n) if n > 0 && status_line.starts_with("HTTP/") => {}
+            Ok(_) => {
+                lease.report(false);
+                return Err(format!("invalid response of {}", address));
+            }
+            Err(e) => {
+                lease.report(false);
+                return Err(format!("read from {}: {}", address, e));
+            }
+        }
+        let status = status_line.split(' ').nth(1).unwrap_or("-").trim().to_string();
+        lease.report(!matches!(status.as_str(), "502" | "503" | "504"));
+        Ok(status)
+    }
+    /// current counters
+    pub fn metrics(&self) -> MirrorMetricsSnapshot {
+        MirrorMetricsSnapshot {
+            mirrored: self.metrics.mirrored.load(Ordering::Relaxed),
+            oversized: self.metrics.oversized.load(Ordering::Relaxed),
+            failed: self.metrics.failed.load(Ordering::Relaxed),
+            matched: self.metrics.matched.load(Ordering::Relaxed),
+            mismatched: self.metrics.mismatched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// counters of the mirrored proxy routes, by route prefix, the ones of a virtual host
+/// prefixed with its first server name
+pub fn metrics() -> Vec<(String, MirrorMetricsSnapshot)> {
+    let settings = settings::current();
+    let mut metrics: Vec<(String, MirrorMetricsSnapshot)> = settings
+        .proxy_routes
+        .iter()
+        .filter_map(|r| Some((r.prefix.clone(), r.mirror.as_ref()?.metrics())))
+        .collect();
+    for h in settings.virtual_hosts.iter() {
+        let host = h.server_names.first().map_or("", |n| n.as_str());
+        metrics.extend(
+            h.proxy_routes
+                .iter()
+                .filter_map(|r| Some((format!("{}{}", host, r.prefix), r.mirror.as_ref()?.metrics()))),
+        );
+    }
+    metrics
+}
+
+/// log the counters of the mirrored proxy routes under the `metrics` target every minute,
+/// the ones that changed
+pub async fn log_metrics() {
+    let mut ticker = tokio::time::interval(METRICS_INTERVAL);
+    let mut last: Vec<(String, MirrorMetricsSnapshot)> = vec![];
+    loop {
+        ticker.tick().await;
+        let now = metrics();
+        for (route, m) in now.iter() {
+            if last.iter().any(|(r, l)| r == route && l == m) || *m == MirrorMetricsSnapshot::default() {
+                continue;
+            }
+            info!(
+                target: "metrics",
+                "mirror of {} mirrored={} oversized={} failed={} matched={} mismatched={}",
+                route, m.mirrored, m.oversized, m.failed, m.matched, m.mismatched
+            );
+        }
+        last = now;
+    }
+}
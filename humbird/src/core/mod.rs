@@ -1,5 +1,26 @@
+pub mod access_log;
+pub mod balancer;
+pub mod breaker;
+pub mod cache;
 pub mod proxy;
 pub mod server;
+pub mod settings;
+pub mod split;
+pub mod sticky;
+pub mod stream;
+pub mod compression;
 pub mod config;
+pub mod directory;
+pub mod error_page;
+pub mod forward_proxy;
+pub mod health;
 pub mod event;
-pub mod plugins;
\ No newline at end of file
+pub mod plugins;
+pub mod log;
+pub mod mirror;
+pub mod pid;
+pub mod pool;
+pub mod reload;
+pub mod rewrite;
+pub mod writer;
+pub mod vhost;
\ No newline at end of file
@@ -0,0 +1,132 @@
+/// process id file of a running server, used by the command line to signal it to reload its
+/// configuration or to stop
+use std::{fs, thread, time::Duration};
+use tracing::info;
+
+use crate::core::log;
+
+/// time allowed to a server to exit once asked to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// write the id of this process, an error when the file names another running server
+pub fn write(path: &str) -> Result<(), String> {
+    if let Ok(pid) = read(path) {
+        if pid != std::process::id() && alive(pid) {
+            return Err(format!("humbird is already running with pid {}, see {}", pid, path));
+        }
+    }
+    match fs::write(path, format!("{}\n", std::process::id())) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("cannot write pid file {}: {}", path, e)),
+    }
+}
+
+/// the process id a file holds
+pub fn read(path: &str) -> Result<u32, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("cannot read pid file {}: {}, is humbird running?", path, e)),
+    };
+    match content.trim().parse::<u32>() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(format!("pid file {} does not hold a process id", path)),
+    }
+}
+
+/// ask the running server to reload its configuration, returns its process id
+pub fn reload(path: &str) -> Result<u32, String> {
+    let pid = running(path)?;
+    signal(pid, Signal::Hangup)?;
+    Ok(pid)
+}
+
+/// ask the running server to stop and wait for it to exit, returns its process id
+pub fn stop(path: &str) -> Result<u32, String> {
+    let pid = running(path)?;
+    signal(pid, Signal::Terminate)?;
+    let mut waited = Duration::ZERO;
+    while alive(pid) {
+        if waited >= STOP_TIMEOUT {
+            return Err(format!("humbird with pid {} did not stop within {:?}", pid, STOP_TIMEOUT));
+        }
+        thread::sleep(Duration::from_millis(100));
+        waited += Duration::from_millis(100);
+    }
+    Ok(pid)
+}
+
+/// the process id of the running server, a stale file is removed
+fn running(path: &str) -> Result<u32, String> {
+    let pid = read(path)?;
+    if !alive(pid) {
+        let _ = fs::remove_file(path);
+        return Err(format!("humbird with pid {} is not running, stale pid file {} removed", pid, path));
+    }
+    Ok(pid)
+}
+
+/// signals understood by a running server
+enum Signal {
+    /// reload the configuration
+    Hangup,
+    /// stop
+    Terminate,
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, signal: Signal) -> Result<(), String> {
+    let number = match signal {
+        Signal::Hangup => libc::SIGHUP,
+        Signal::Terminate => libc::SIGTERM,
+    };
+    match unsafe { libc::kill(pid as libc::pid_t, number) } {
+        0 => Ok(()),
+        _ => Err(format!("cannot signal pid {}: {}", pid, std::io::Error::last_os_error())),
+    }
+}
+
+#[cfg(not(unix))]
+fn signal(pid: u32, _signal: Signal) -> Result<(), String> {
+    Err(format!("cannot signal pid {}: signals are not supported on this platform", pid))
+}
+
+/// whether a process runs
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    // signal 0 only checks that the process exists, EPERM means it runs under another user
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+    false
+}
+
+/// exit on `SIGTERM` or `SIGINT`, removing the pid file and flushing the log file
+pub async fn on_terminate(path: String) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (mut term, mut int) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+            (Ok(t), Ok(i)) => (t, i),
+            _ => return,
+        };
+        tokio::select! {
+            _ = term.recv() => info!("SIGTERM received, stopping"),
+            _ = int.recv() => info!("SIGINT received, stopping"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("interrupted, stopping");
+    }
+    if !path.is_empty() {
+        let _ = fs::remove_file(&path);
+    }
+    log::flush();
+    std::process::exit(0);
+}
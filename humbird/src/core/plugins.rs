@@ -1,4 +1,4 @@
-use crate::protocol::http::HttpRequestProcess;
+use crate::{core::vhost::VirtualHost, protocol::http::HttpRequestProcess};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::sync::Mutex;
@@ -8,20 +8,44 @@ lazy_static! {
         let map = HashMap::new();
         Mutex::new(map)
     };
+    /// routes of virtual hosts, keyed by server name
+    pub static ref VHOST_ROUTER_TABLE: Mutex<HashMap<String, HashMap<String, HttpRequestProcess>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+/// find the route of a path, routes of the virtual host take precedence over server wide ones
+pub fn route(vhost: Option<&VirtualHost>, path: &str) -> Option<HttpRequestProcess> {
+    if let Some(h) = vhost {
+        if let Ok(t) = VHOST_ROUTER_TABLE.lock() {
+            for name in h.server_names.iter() {
+                if let Some(p) = t.get(name).and_then(|r| r.get(path)) {
+                    return Some(*p);
+                }
+            }
+        }
+    }
+    match ROUTER_TABLE.lock() {
+        Ok(t) => t.get(path).copied(),
+        Err(_) => None,
+    }
 }
 
 /// macro for registering web routes,work before starting humbird service
 ///
 /// Example
-/// ```rust
+/// ```rust,no_run
+/// use humbird::{core::server::Server, protocol::http::{Request, Response}, router};
+///
 /// // register plugin
-/// fn router_function(req: Request, mut res: Response) -> Response {
-/// // ......
-/// res
+/// fn router_function(_req: Request, mut res: Response) -> Response {
+///     // ......
+///     res
 /// }
 /// router!("/path" => router_function);
 /// // run humbird server
-/// run!();
+/// Server::run();
 /// ```
 #[macro_export]
 macro_rules! router {
@@ -31,3 +55,30 @@ macro_rules! router {
         )*;
     };
 }
+
+/// macro for registering web routes of a virtual host, the host must be one of
+/// the `server-name` entries of a `[[vhost]]` in the configuration file
+///
+/// Example
+/// ```rust
+/// use humbird::{protocol::http::{Request, Response}, vhost_router};
+///
+/// fn search_function(_req: Request, mut res: Response) -> Response {
+///     res.set_body("results");
+///     res
+/// }
+/// vhost_router!("docs.example.com", "/search" => search_function);
+/// ```
+#[macro_export]
+macro_rules! vhost_router {
+    ($host:expr, $($path:expr => $process:expr),*) => {
+        $(
+            $crate::core::plugins::VHOST_ROUTER_TABLE
+                .lock()
+                .unwrap()
+                .entry($host.to_string())
+                .or_default()
+                .insert($path.to_string(), $process);
+        )*;
+    };
+}
@@ -0,0 +1,285 @@
+/// keep-alive connection pool for proxy upstreams
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::info;
+
+use crate::core::settings;
+
+/// time between two looks at the pool counters
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// upstream connections shared by all proxy routes, keyed by address
+    pub static ref CONNECTION_POOL: ConnectionPool = ConnectionPool::default();
+}
+
+/// connection pool settings
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    /// idle connections kept per upstream
+    pub max_idle: usize,
+    /// idle connections older than this are closed
+    pub idle_timeout: Duration,
+    /// open connections allowed per upstream, idle ones included
+    pub max_per_host: usize,
+    /// time allowed to establish a connection or to wait for a free slot
+    pub connect_timeout: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_idle: 32,
+            idle_timeout: Duration::from_secs(60),
+            max_per_host: 256,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// why no connection could be obtained
+#[derive(Debug)]
+pub enum PoolError {
+    /// the upstream refused the connection or is unreachable, answered with 502
+    CONNECT(String),
+    /// no connection within the connect timeout, answered with 504
+    TIMEOUT(String),
+}
+
+impl PoolError {
+    /// status code answered to the client
+    pub fn status_code(&self) -> &'static str {
+        match self {
+            PoolError::CONNECT(_) => "502",
+            PoolError::TIMEOUT(_) => "504",
+        }
+    }
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::CONNECT(e) => write!(f, "{}", e),
+            PoolError::TIMEOUT(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// pool counters
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    /// requests served by an idle connection
+    pub hits: AtomicU64,
+    /// requests that had to open a connection
+    pub misses: AtomicU64,
+    /// failed connection attempts
+    pub connect_errors: AtomicU64,
+    /// connection attempts or slot waits that timed out
+    pub timeouts: AtomicU64,
+    /// idle connections closed for exceeding the idle timeout
+    pub expired: AtomicU64,
+}
+
+/// point in time copy of the pool counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolMetricsSnapshot {
+    pub hits: u64,
+    pub misses: u64,
+    pub connect_errors: u64,
+    pub timeouts: u64,
+    pub expired: u64,
+}
+
+/// an upstream connection checked out of the pool
+#[derive(Debug)]
+pub struct Pooled {
+    pub stream: TcpStream,
+    /// the connection has served a request before, so the upstream may have closed it meanwhile
+    pub reused: bool,
+    address: String,
+    permit: OwnedSemaphorePermit,
+}
+
+/// an idle connection
+#[derive(Debug)]
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+    permit: OwnedSemaphorePermit,
+}
+
+/// connection pool abstract
+#[derive(Debug, Default)]
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<String, Vec<Idle>>>,
+    /// connection slots of an address and the `max-per-host` they were sized for
+    slots: Mutex<HashMap<String, (Arc<Semaphore>, usize)>>,
+    metrics: PoolMetrics,
+}
+
+impl ConnectionPool {
+    /// an idle connection to the address, or a new one, `connect_timeout` overrides the
+    /// one of the pool settings
+    pub async fn get(
+        &self,
+        address: &str,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Pooled, PoolError> {
+        let mut settings = settings::current().pool.clone();
+        if let Some(t) = connect_timeout {
+            settings.connect_timeout = t;
+        }
+        if let Some(idle) = self.take_idle(address, settings.idle_timeout) {
+            self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Pooled {
+                stream: idle.stream,
+                reused: true,
+                address: address.to_string(),
+                permit: idle.permit,
+            });
+        }
+        self.metrics.misses.fetch_add(1, Ordering::Relaxed);
+        self.connect(address, &settings).await
+    }
+    /// a new connection to the address, bypassing idle ones
+    pub async fn connect(&self, address: &str, settings: &PoolSettings) -> Result<Pooled, PoolError> {
+        let deadline = tokio::time::Instant::now() + settings.connect_timeout;
+        let slots = self.slots(address, settings.max_per_host);
+        let permit = match tokio::time::timeout_at(deadline, slots.acquire_owned()).await {
+            Ok(Ok(p)) => p,
+            Ok(Err(_)) => return Err(PoolError::CONNECT(format!("pool of {} closed", address))),
+            Err(_) => {
+                self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+                return Err(PoolError::TIMEOUT(format!(
+                    "no free connection slot for {}",
+                    address
+                )));
+            }
+        };
+        match tokio::time::timeout_at(deadline, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => {
+                let _ = stream.set_nodelay(true);
+                Ok(Pooled {
+                    stream,
+                    reused: false,
+                    address: address.to_string(),
+                    permit,
+                })
+            }
+            Ok(Err(e)) => {
+                self.metrics.connect_errors.fetch_add(1, Ordering::Relaxed);
+                Err(PoolError::CONNECT(format!("connect {}: {}", address, e)))
+            }
+            Err(_) => {
+                self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(PoolError::TIMEOUT(format!(
+                    "connect {}: timed out after {:?}",
+                    address, settings.connect_timeout
+                )))
+            }
+        }
+    }
+    /// return a connection whose last response was read completely
+    pub fn put(&self, pooled: Pooled) {
+        let max_idle = settings::current().pool.max_idle;
+        if let Ok(mut idle) = self.idle.lock() {
+            let list = idle.entry(pooled.address).or_default();
+            if list.len() < max_idle {
+                list.push(Idle {
+                    stream: pooled.stream,
+                    since: Instant::now(),
+                    permit: pooled.permit,
+                });
+            }
+        }
+    }
+    /// close the idle connections of an address, when one of them turned out to be closed
+    /// by the upstream the others, idle for longer, are likely closed as well
+    pub fn discard_idle(&self, address: &str) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.remove(address);
+        }
+    }
+    /// number of idle connections of an address
+    pub fn idle_count(&self, address: &str) -> usize {
+        match self.idle.lock() {
+            Ok(idle) => idle.get(address).map_or(0, |l| l.len()),
+            Err(_) => 0,
+        }
+    }
+    /// current counters
+    pub fn metrics(&self) -> PoolMetricsSnapshot {
+        PoolMetricsSnapshot {
+            hits: self.metrics.hits.load(Ordering::Relaxed),
+            misses: self.metrics.misses.load(Ordering::Relaxed),
+            connect_errors: self.metrics.connect_errors.load(Ordering::Relaxed),
+            timeouts: self.metrics.timeouts.load(Ordering::Relaxed),
+            expired: self.metrics.expired.load(Ordering::Relaxed),
+        }
+    }
+    /// most recently used idle connection that is still fresh, expired ones are closed
+    fn take_idle(&self, address: &str, idle_timeout: Duration) -> Option<Idle> {
+        let mut idle = self.idle.lock().ok()?;
+        let list = idle.get_mut(address)?;
+        let before = list.len();
+        list.retain(|i| i.since.elapsed() < idle_timeout);
+        self.metrics
+            .expired
+            .fetch_add((before - list.len()) as u64, Ordering::Relaxed);
+        list.pop()
+    }
+    /// connection slots of an address, resized when a reload changed `max-per-host`
+    fn slots(&self, address: &str, max_per_host: usize) -> Arc<Semaphore> {
+        let max_per_host = max_per_host.max(1);
+        let mut slots = match self.slots.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        let (semaphore, size) = slots
+            .entry(address.to_string())
+            .or_insert_with(|| (Arc::new(Semaphore::new(max_per_host)), max_per_host));
+        if max_per_host > *size {
+            semaphore.add_permits(max_per_host - *size);
+        } else if max_per_host < *size {
+            // slots in use are given up as their connections close
+            let surplus = (*size - max_per_host) as u32;
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                if let Ok(p) = semaphore.acquire_many_owned(surplus).await {
+                    p.forget();
+                }
+            });
+        }
+        *size = max_per_host;
+        semaphore.clone()
+    }
+}
+
+/// log the pool counters under the `metrics` target every minute, when they changed
+pub async fn log_metrics() {
+    let mut ticker = tokio::time::interval(METRICS_INTERVAL);
+    let mut last = PoolMetricsSnapshot::default();
+    loop {
+        ticker.tick().await;
+        let m = CONNECTION_POOL.metrics();
+        if m != last {
+            info!(
+                target: "metrics",
+                "connection pool hits={} misses={} connect_errors={} timeouts={} expired={}",
+                m.hits, m.misses, m.connect_errors, m.timeouts, m.expired
+            );
+            last = m;
+        }
+    }
+}
@@ -1,19 +1,427 @@
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use lazy_static::lazy_static;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{error, warn};
 
-use crate::protocol::http::{Request, Response};
+use crate::{
+    core::{
+        access_log,
+        balancer::UpstreamGroup,
+        cache::{self, CacheEntry, Flight, FlightGuard, Tee, PROXY_CACHE},
+        error_page,
+        mirror::Mirror,
+        pool::CONNECTION_POOL,
+        rewrite::RewriteRules,
+        split::TrafficSplit,
+        settings::{self, Settings},
+        vhost::VirtualHost,
+    },
+    protocol::http::{reason_phrase, Method, Request, Response},
+};
 
-/// proxy target host address list
-pub static mut PROXY_TARGET: Vec<String> = vec![];
+/// name of the upstream group of the `[proxy]` table
+pub const DEFAULT_UPSTREAM: &str = "default";
+/// length of a retry budget window
+const RETRY_BUDGET_WINDOW: Duration = Duration::from_secs(10);
+/// hop-by-hop headers, never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
 
-/// load balancing mode
+lazy_static! {
+    /// retry policy of requests forwarded without a proxy route
+    pub static ref DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy::default();
+}
+
+/// a path prefix forwarded to an upstream group
+#[derive(Debug, Clone)]
+pub struct ProxyRoute {
+    /// url path prefix
+    pub prefix: String,
+    /// upstream group name
+    pub upstream: String,
+    /// remove the prefix from the path sent upstream
+    pub strip_prefix: bool,
+    /// upstream timeouts
+    pub timeout: ProxyTimeout,
+    /// retry policy
+    pub retry: RetryPolicy,
+    /// answer from the proxy cache and store cacheable responses
+    pub cache: bool,
+    /// path rewrites, redirects and header changes
+    pub rewrite: RewriteRules,
+    /// copy of a sample of the requests sent to a shadow upstream group
+    pub mirror: Option<Mirror>,
+    /// split of the requests between upstream groups, `upstream` serves them when every
+    /// weight is 0
+    pub split: Option<TrafficSplit>,
+}
+
+impl ProxyRoute {
+    /// path of the request relative to the prefix, `None` if it is not under the prefix
+    pub fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let prefix = self.prefix.trim_end_matches('/');
+        let rest = path.strip_prefix(prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+    /// path sent to the upstream
+    pub fn upstream_path(&self, path: &str) -> String {
+        match self.relative(path) {
+            Some(rest) if self.strip_prefix => {
+                if rest.is_empty() {
+                    "/".to_string()
+                } else {
+                    rest.to_string()
+                }
+            }
+            _ => path.to_string(),
+        }
+    }
+}
+
+/// upstream timeouts of a proxy route
 #[derive(Debug, Clone)]
+pub struct ProxyTimeout {
+    /// time allowed to connect, `None` uses the `[proxy.pool]` setting
+    pub connect: Option<Duration>,
+    /// time allowed from sending the request to receiving the response head
+    pub first_byte: Option<Duration>,
+    /// time allowed for the whole exchange, retries included
+    pub total: Option<Duration>,
+}
+
+impl Default for ProxyTimeout {
+    fn default() -> Self {
+        ProxyTimeout {
+            connect: None,
+            first_byte: Some(Duration::from_secs(60)),
+            total: None,
+        }
+    }
+}
+
+/// retry policy of a proxy route, only idempotent requests are retried and only
+/// before anything of the response has been sent to the client
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// retries after the first attempt
+    pub retries: u32,
+    /// upstream status codes retried, connection failures and timeouts always are
+    pub retry_on: Vec<String>,
+    /// retries allowed relative to the requests of the route
+    pub budget: Arc<RetryBudget>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 1,
+            retry_on: vec!["502".to_string(), "503".to_string(), "504".to_string()],
+            budget: Arc::new(RetryBudget::new(0.2, 10)),
+        }
+    }
+}
+
+/// caps retries at a share of the requests of a time window, so retries do not
+/// multiply the load of an upstream that is already failing
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// retries allowed per request
+    pub ratio: f64,
+    /// retries allowed per window whatever the number of requests
+    pub min_retries: u32,
+    /// start of the window, requests and retries counted in it
+    window: Mutex<(Instant, u64, u64)>,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64, min_retries: u32) -> Self {
+        RetryBudget {
+            ratio: ratio.max(0.0),
+            min_retries,
+            window: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+    /// count a request
+    pub fn record_request(&self) {
+        if let Ok(mut w) = self.window.lock() {
+            RetryBudget::roll(&mut w);
+            w.1 += 1;
+        }
+    }
+    /// take a retry from the budget, `false` if it is exhausted
+    pub fn try_retry(&self) -> bool {
+        match self.window.lock() {
+            Ok(mut w) => {
+                RetryBudget::roll(&mut w);
+                if w.2 < self.allowed(w.1) {
+                    w.2 += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+            Err(_) => false,
+        }
+    }
+    /// give back a retry taken with `try_retry` that was not needed
+    pub fn release(&self) {
+        if let Ok(mut w) = self.window.lock() {
+            RetryBudget::roll(&mut w);
+            w.2 = w.2.saturating_sub(1);
+        }
+    }
+    fn allowed(&self, requests: u64) -> u64 {
+        ((requests as f64 * self.ratio) as u64).max(self.min_retries as u64)
+    }
+    fn roll(w: &mut (Instant, u64, u64)) {
+        if w.0.elapsed() >= RETRY_BUDGET_WINDOW {
+            *w = (Instant::now(), 0, 0);
+        }
+    }
+}
+
+/// why a proxied request failed
+#[derive(Debug)]
+pub enum ProxyError {
+    /// the request could not be read, answered with the status code
+    REQUEST(&'static str, String),
+    /// the upstream failed before anything was sent to the client, answered with the status code
+    UPSTREAM(&'static str, String),
+    /// no upstream of the group is available, answered with 503
+    UNAVAILABLE(String),
+    /// the upstream or the client failed after the response had started, the connection is closed
+    ABORTED(String),
+}
+
+impl ProxyError {
+    /// status code answered to the client, `None` once the response has started
+    pub fn status_code(&self) -> Option<&'static str> {
+        match self {
+            ProxyError::REQUEST(code, _) | ProxyError::UPSTREAM(code, _) => Some(code),
+            ProxyError::UNAVAILABLE(_) => Some("503"),
+            ProxyError::ABORTED(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::REQUEST(_, e)
+            | ProxyError::UPSTREAM(_, e)
+            | ProxyError::UNAVAILABLE(e)
+            | ProxyError::ABORTED(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// a request matched by a proxy route, with the upstream group serving it
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub route: ProxyRoute,
+    /// name of the upstream group, the one a split route picked for the request
+    pub upstream: String,
+    pub group: Arc<UpstreamGroup>,
+}
+
+impl Forward {
+    /// cache key of a request, the responses of the groups of a split route are kept apart
+    pub fn cache_key(&self, request: &Request) -> String {
+        match self.route.split {
+            Some(_) => format!("{}#{}", cache::key(request), self.upstream),
+            None => cache::key(request),
+        }
+    }
+    /// cache keys of the responses of a url, one per group of a split route
+    fn cache_keys(&self, request: &Request) -> Vec<String> {
+        match self.route.split {
+            Some(ref s) => {
+                let mut names: Vec<&str> = s.groups.iter().map(|g| g.upstream.as_str()).collect();
+                if !names.contains(&self.route.upstream.as_str()) {
+                    names.push(&self.route.upstream);
+                }
+                names.iter().map(|n| format!("{}#{}", cache::key(request), n)).collect()
+            }
+            None => vec![cache::key(request)],
+        }
+    }
+}
+
+/// find the proxy route of a request in the settings, routes of the virtual host take
+/// precedence over server wide ones and the longest prefix wins, a route splitting its
+/// traffic picks the upstream group of the request
+pub fn find(settings: &Settings, vhost: Option<&VirtualHost>, request: &Request) -> Option<Forward> {
+    let path = request.path();
+    let routes = match vhost {
+        Some(h) if !h.proxy_routes.is_empty() => &h.proxy_routes,
+        _ => &settings.proxy_routes,
+    };
+    let route = routes
+        .iter()
+        .filter(|r| r.relative(path).is_some())
+        .max_by_key(|r| r.prefix.trim_end_matches('/').len())?
+        .clone();
+    let upstream = match route.split {
+        Some(ref s) => s.choose_for(request).unwrap_or(&route.upstream).to_string(),
+        None => route.upstream.clone(),
+    };
+    let group = if upstream.is_empty() || upstream == DEFAULT_UPSTREAM {
+        match vhost.and_then(|h| h.proxy.clone()) {
+            Some(g) => g,
+            None => settings.upstream_group(DEFAULT_UPSTREAM)?,
+        }
+    } else {
+        settings.upstream_group(&upstream)?
+    };
+    Some(Forward { route, upstream, group })
+}
+
+/// the upstream group of a name in the settings in effect, `default` is the `[proxy]`
+/// target list
+pub fn upstream_group(name: &str) -> Option<Arc<UpstreamGroup>> {
+    settings::current().upstream_group(name)
+}
+
+/// whether a header is hop-by-hop, `connection` is the `Connection` header value
+pub fn is_hop_by_hop(name: &str, connection: Option<&String>) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+        || connection.is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case(name)))
+}
+
+/// the request as sent to an upstream, with hop-by-hop headers removed and
+/// `X-Forwarded-*` and `Forwarded` headers appended, a path containing `?` carries
+/// its own query string
+pub fn upstream_request(request: &Request, path: &str) -> Vec<u8> {
+    let mut target = path.to_string();
+    if !request.query().is_empty() && !path.contains('?') {
+        target.push('?');
+        target.push_str(request.query());
+    }
+    let mut h = format!("{} {} HTTP/1.1\r\n", request.method().as_str(), target);
+    let connection = request.head("Connection");
+    let mut forwarded_for: Option<String> = None;
+    let mut forwarded: Option<String> = None;
+    for (k, v) in request.heads().iter() {
+        if is_hop_by_hop(k, connection) {
+            continue;
+        }
+        if k.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = Some(v.to_string());
+            continue;
+        }
+        if k.eq_ignore_ascii_case("Forwarded") {
+            forwarded = Some(v.to_string());
+            continue;
+        }
+        if k.eq_ignore_ascii_case("X-Forwarded-Proto") || k.eq_ignore_ascii_case("X-Forwarded-Host") {
+            continue;
+        }
+        h.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    let host = request.head("Host").cloned().unwrap_or_default();
+    let client = request.client().map(|a| a.ip().to_string());
+    // X-Forwarded-For
+    match (forwarded_for, client.as_ref()) {
+        (Some(f), Some(c)) => h.push_str(&format!("X-Forwarded-For: {}, {}\r\n", f, c)),
+        (Some(f), None) => h.push_str(&format!("X-Forwarded-For: {}\r\n", f)),
+        (None, Some(c)) => h.push_str(&format!("X-Forwarded-For: {}\r\n", c)),
+        (None, None) => {}
+    }
+    h.push_str("X-Forwarded-Proto: http\r\n");
+    if !host.is_empty() {
+        h.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    // Forwarded, rfc 7239
+    let mut element = String::default();
+    if let Some(a) = request.client() {
+        element.push_str(&format!("for={};", forwarded_node(&a)));
+    }
+    element.push_str("proto=http");
+    if !host.is_empty() {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    match forwarded {
+        Some(f) => h.push_str(&format!("Forwarded: {}, {}\r\n", f, element)),
+        None => h.push_str(&format!("Forwarded: {}\r\n", element)),
+    }
+    if !request.body().is_empty() && request.head("Content-Length").is_none() {
+        h.push_str(&format!("Content-Length: {}\r\n", request.body().len()));
+    }
+    h.push_str("\r\n");
+    let mut raw = h.into_bytes();
+    raw.extend(request.body());
+    raw
+}
+
+/// node identifier of the `Forwarded` header, ipv6 addresses are quoted and bracketed
+fn forwarded_node(a: &SocketAddr) -> String {
+    match a {
+        SocketAddr::V4(v4) => v4.ip().to_string(),
+        SocketAddr::V6(v6) => format!("\"[{}]\"", v6.ip()),
+    }
+}
+
+/// load balancing mode
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum BalancingMode {
     /// weight mode
     WEIGHT,
     /// random mode
     RANDOM,
     /// polling mode
+    #[default]
     POLLING,
+    /// least connections mode
+    LEAST,
+    /// consistent hashing mode
+    HASH,
+}
+
+impl BalancingMode {
+    pub fn new(m: &str) -> Self {
+        match m.to_ascii_uppercase().replace('-', "_").as_str() {
+            "WEIGHT" => BalancingMode::WEIGHT,
+            "RANDOM" => BalancingMode::RANDOM,
+            "LEAST" | "LEAST_CONN" | "LEAST_CONNECTIONS" => BalancingMode::LEAST,
+            "HASH" | "CONSISTENT_HASH" => BalancingMode::HASH,
+            _ => BalancingMode::POLLING,
+        }
+    }
+}
+
+/// what the client of a proxy route got, for the access log
+#[derive(Debug, Default)]
+struct Responded {
+    /// status of the response head sent to the client
+    status: Option<String>,
+    /// address of the last upstream asked
+    upstream: Option<String>,
+    /// affinity cookie the proxy added to the response head
+    affinity_cookie: Option<String>,
 }
 
 /// network agent abstract structure
@@ -26,38 +434,633 @@ pub struct Proxy {
 }
 
 impl Proxy {
-    /// load balancing
-    pub async fn load_balancing(
-        host: &str,
-        port: &str,
-        request: Request,
-        mode: BalancingMode,
-    ) -> Result<Self, String> {
-        match mode {
-            BalancingMode::WEIGHT => Proxy::to(host, port, request).await,
-            BalancingMode::RANDOM => Proxy::to(host, port, request).await,
-            BalancingMode::POLLING => Proxy::to(host, port, request).await,
-            _ => Err("".to_string()),
-        }
-    }
-    /// forward the request to a third-party server
+    /// forward a request matched by a proxy route and stream the upstream response back to the client,
+    /// the client connection is closed afterwards
+    pub async fn forward(forward: Forward, mut request: Request, client: TcpStream) -> Result<(), String> {
+        let mut client = access_log::Counted::new(client);
+        let mut responded = Responded::default();
+        let result = match request.read_body(&mut client).await {
+            Err(code) => Err(ProxyError::REQUEST(code, "request body too large or unreadable".to_string())),
+            Ok(_) => match forward.route.rewrite.redirect(&request) {
+                Some((code, location)) => Proxy::redirect(&request, &mut client, code, &location, &mut responded).await,
+                None if forward.route.cache => Proxy::cached(&forward, &request, &mut client, &mut responded).await,
+                None => Proxy::relay(&forward, &request, &mut client, &mut responded).await,
+            },
+        };
+        let status = match (&result, responded.status) {
+            (_, Some(s)) => s,
+            (Err(e), None) => e.status_code().unwrap_or("-").to_string(),
+            (Ok(_), None) => "-".to_string(),
+        };
+        if let Err(ref e) = result {
+            error!("proxy {} failed: {}", request.path(), e);
+            if let Some(code) = e.status_code() {
+                let mut response = Response::blank(&request);
+                response.error(code);
+                let mut response = error_page::apply(&request, response);
+                response.set_head("Connection", "close");
+                response.make_raw();
+                let _ = client.write_all(&response.raw()).await;
+            }
+        }
+        let _ = client.shutdown().await;
+        access_log::log(&request, &status, Some(client.written()), responded.upstream.as_deref());
+        result.map_err(|e| e.to_string())
+    }
+    /// relay the request within the total timeout of its route, `responded` receives the
+    /// status once the response head has been sent to the client
+    async fn relay<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        client: &mut W,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let attempts = Proxy::attempts(forward, request, client, responded);
+        match forward.route.timeout.total {
+            Some(t) => match tokio::time::timeout(t, attempts).await {
+                Ok(r) => r,
+                Err(_) if responded.status.is_some() => Err(ProxyError::ABORTED(format!("not completed within {:?}", t))),
+                Err(_) => Err(ProxyError::UPSTREAM("504", format!("no response within {:?}", t))),
+            },
+            None => attempts.await,
+        }
+    }
+    /// try the request until it succeeds, fails after the response has started or runs out of
+    /// retries, only idempotent requests are retried and only within the retry budget of the route
+    async fn attempts<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        client: &mut W,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let retry = &forward.route.retry;
+        retry.budget.record_request();
+        let rules = &forward.route.rewrite;
+        let path = rules.path(&forward.route.upstream_path(request.path()));
+        let raw = match rules.request_headers.is_empty() {
+            true => upstream_request(request, &path),
+            false => {
+                let mut rewritten = request.clone();
+                rules.request_headers.apply_request(&mut rewritten);
+                upstream_request(&rewritten, &path)
+            }
+        };
+        let shadow = forward.route.mirror.as_ref().and_then(|m| m.send(request, &raw));
+        let mut attempt = 0;
+        let result = loop {
+            // the retry is taken from the budget before the attempt, a response held back
+            // for a retry is then always followed by one
+            let may_retry = request.method().is_idempotent()
+                && attempt < retry.retries
+                && retry.budget.try_retry();
+            match Proxy::attempt(forward, request, &raw, client, may_retry, responded).await {
+                Err(ProxyError::UPSTREAM(_, e)) if may_retry => {
+                    warn!("proxy {} attempt {} failed, retrying: {}", request.path(), attempt + 1, e);
+                    attempt += 1;
+                }
+                r => {
+                    if may_retry {
+                        retry.budget.release();
+                    }
+                    break r;
+                }
+            }
+        };
+        let status = match (&result, responded.status.as_ref()) {
+            (_, Some(s)) => Some(s.to_string()),
+            (Err(e), None) => e.status_code().map(|c| c.to_string()),
+            (Ok(_), None) => None,
+        };
+        if let (Some(tx), Some(s)) = (shadow, status) {
+            let _ = tx.send(s);
+        }
+        result
+    }
+    /// send the request to an upstream and copy the response to the client, an upstream status
+    /// of the retry list is not relayed when `may_retry` is set
+    async fn attempt<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        raw: &[u8],
+        client: &mut W,
+        may_retry: bool,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let lease = match forward.group.acquire(request) {
+            Some(l) => l,
+            None => return Err(ProxyError::UNAVAILABLE("no upstream available".to_string())),
+        };
+        let address = lease.upstream().address.clone();
+        responded.upstream = Some(address.clone());
+        let timeout = &forward.route.timeout;
+        let failed = |code: &'static str, e: String| {
+            lease.report(false);
+            ProxyError::UPSTREAM(code, format!("{}: {}", address, e))
+        };
+        let mut pooled = match CONNECTION_POOL.get(&address, timeout.connect).await {
+            Ok(p) => p,
+            Err(e) => return Err(failed(e.status_code(), e.to_string())),
+        };
+        // the first byte timeout covers the request and the response head
+        let deadline = timeout.first_byte.map(|t| tokio::time::Instant::now() + t);
+        let no_response = || format!("no response within {:?}", timeout.first_byte.unwrap_or_default());
+        let mut head = match before(deadline, Proxy::exchange(&mut pooled.stream, raw)).await {
+            Some(h) => h,
+            None => return Err(failed("504", no_response())),
+        };
+        let resend = match head {
+            Err((written, _)) => !written || request.method().is_idempotent(),
+            Ok(_) => false,
+        };
+        if resend && pooled.reused {
+            // the upstream closed the idle connection meanwhile, nothing of the response
+            // has been read so the request is sent once more on a new connection, unless
+            // the upstream may have received and acted on part of a non-idempotent one,
+            // the stale connection and the idle ones of the upstream give their slots back
+            drop(pooled);
+            CONNECTION_POOL.discard_idle(&address);
+            let mut settings = settings::current().pool.clone();
+            if let Some(t) = timeout.connect {
+                settings.connect_timeout = t;
+            }
+            pooled = match CONNECTION_POOL.connect(&address, &settings).await {
+                Ok(p) => p,
+                Err(e) => return Err(failed(e.status_code(), e.to_string())),
+            };
+            head = match before(deadline, Proxy::exchange(&mut pooled.stream, raw)).await {
+                Some(h) => h,
+                None => return Err(failed("504", no_response())),
+            };
+        }
+        if let Err((_, e)) = head {
+            return Err(failed("502", e));
+        }
+        let mut r_buf = BufReader::new(&mut pooled.stream);
+        let (status_line, head) = match before(deadline, read_head(&mut r_buf)).await {
+            Some(Ok(h)) => h,
+            Some(Err(e)) => return Err(failed("502", e)),
+            None => return Err(failed("504", no_response())),
+        };
+        let status = status_line.split(' ').nth(1).unwrap_or_default();
+        // gateway errors of the upstream count as failures of the outlier detection
+        // and the circuit breaker
+        lease.report(!matches!(status, "502" | "503" | "504"));
+        if may_retry && forward.route.retry.retry_on.iter().any(|s| s == status) {
+            return Err(ProxyError::UPSTREAM(
+                "502",
+                format!("{}: answered {}", address, status),
+            ));
+        }
+        let connection = head
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+            .map(|(_, v)| v.clone());
+        let mut h = format!("HTTP/1.1 {}\r\n", status_line.split_once(' ').map_or("", |s| s.1).trim_end());
+        // the body is relayed as it is, so its framing headers are kept
+        let mut sent: Vec<(String, String)> = head
+            .iter()
+            .filter(|(k, _)| !is_hop_by_hop(k, connection.as_ref()))
+            .cloned()
+            .collect();
+        forward.route.rewrite.response_headers.apply(&mut sent);
+        for (k, v) in sent.iter() {
+            h.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        if let Some(cookie) = lease.affinity_cookie() {
+            h.push_str(&format!("Set-Cookie: {}\r\n", cookie));
+        }
+        h.push_str("Connection: close\r\n\r\n");
+        responded.status = Some(status.to_string());
+        responded.affinity_cookie = lease.affinity_cookie().map(|c| c.to_string());
+        if let Err(e) = client.write_all(h.as_bytes()).await {
+            return Err(ProxyError::ABORTED(format!("write to client: {}", e)));
+        }
+        let framing = Framing::new(request.method().as_str(), &status_line, &head);
+        let relayed = match framing {
+            Framing::NONE => Ok(()),
+            Framing::LENGTH(n) => match tokio::io::copy(&mut (&mut r_buf).take(n), client).await {
+                Ok(c) if c == n => Ok(()),
+                Ok(c) => Err(format!("body truncated after {} of {} bytes", c, n)),
+                Err(e) => Err(e.to_string()),
+            },
+            Framing::CHUNKED => relay_chunked(&mut r_buf, client).await.map_err(|e| e.to_string()),
+            Framing::CLOSE => match tokio::io::copy_buf(&mut r_buf, client).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.to_string()),
+            },
+        };
+        if let Err(e) = relayed {
+            return Err(ProxyError::ABORTED(format!("relay from {}: {}", address, e)));
+        }
+        // keep the connection when the response was delimited and the upstream allows it
+        let keep_alive = match connection {
+            Some(c) => !c.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")),
+            None => status_line.starts_with("HTTP/1.1"),
+        };
+        if keep_alive && framing != Framing::CLOSE && r_buf.buffer().is_empty() {
+            drop(r_buf);
+            CONNECTION_POOL.put(pooled);
+        }
+        Ok(())
+    }
+    /// answer a request of a caching route, from the cache when it holds a usable response,
+    /// otherwise from the upstream, storing the response when it may be cached, concurrent
+    /// requests missing the same url wait for the first one instead of reaching the upstream
+    async fn cached<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        client: &mut W,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        if request.method() == Method::PURGE {
+            return Proxy::purge(forward, request, client, responded).await;
+        }
+        let get = request.method() == Method::GET;
+        if !(get || request.method() == Method::HEAD) || cache::bypass(request) {
+            let result = Proxy::relay(forward, request, client, responded).await;
+            // a successful unsafe request invalidates the stored responses of its url, rfc 9111
+            // section 4.4
+            let success = responded.status.as_ref().is_some_and(|s| s.starts_with('2') || s.starts_with('3'));
+            if success && !request.method().is_safe() {
+                for key in forward.cache_keys(request) {
+                    PROXY_CACHE.purge(&key).await;
+                }
+            }
+            return result;
+        }
+        let key = forward.cache_key(request);
+        let revalidate = cache::revalidate(request);
+        let mut coalesce = get;
+        loop {
+            let mut stale = None;
+            if let Some(entry) = PROXY_CACHE.lookup(&key, request) {
+                if let Some(body) = PROXY_CACHE.body(&key, &entry).await {
+                    if entry.is_fresh() && !revalidate {
+                        return Proxy::replay(&entry, &body, request, client, "HIT", responded).await;
+                    }
+                    if get && !revalidate && entry.serves_stale_while_revalidate() {
+                        if let Flight::LEADER(flight) = PROXY_CACHE.join(&key) {
+                            Proxy::revalidate_later(forward, request, &key, &entry, flight);
+                        }
+                        return Proxy::replay(&entry, &body, request, client, "STALE", responded).await;
+                    }
+                    stale = Some((entry, body));
+                }
+            }
+            if !get {
+                return Proxy::relay(forward, request, client, responded).await;
+            }
+            let flight = match coalesce {
+                true => match PROXY_CACHE.join(&key) {
+                    Flight::LEADER(f) => Some(f),
+                    Flight::FOLLOWER(mut done) => {
+                        // the other request is done once the sender is dropped
+                        let _ = done.changed().await;
+                        coalesce = false;
+                        continue;
+                    }
+                },
+                false => None,
+            };
+            let result = Proxy::fill(forward, request, &key, stale.as_ref().map(|s| &s.0), client, responded).await;
+            drop(flight);
+            return match (result, stale) {
+                (Ok(Some(refreshed)), Some((_, body))) => {
+                    Proxy::replay(&refreshed, &body, request, client, "REVALIDATED", responded).await
+                }
+                (Ok(_), _) => Ok(()),
+                (Err(e), Some((entry, body))) if e.status_code().is_some() && entry.serves_stale_if_error() => {
+                    warn!("proxy {} revalidation failed, answering stale: {}", request.path(), e);
+                    Proxy::replay(&entry, &body, request, client, "STALE", responded).await
+                }
+                (Err(e), _) => Err(e),
+            };
+        }
+    }
+    /// relay a request to the upstream and store the response, the validators of a stale
+    /// response are sent along and the response is returned refreshed when the upstream
+    /// answers `304`, which is then not relayed
+    async fn fill<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        key: &str,
+        stale: Option<&Arc<CacheEntry>>,
+        client: &mut W,
+        responded: &mut Responded,
+    ) -> Result<Option<Arc<CacheEntry>>, ProxyError> {
+        // the conditions of the client are answered from the stored response
+        let mut conditional = request.clone();
+        conditional.remove_head("If-None-Match");
+        conditional.remove_head("If-Modified-Since");
+        if let Some(entry) = stale {
+            if let Some(etag) = entry.etag() {
+                conditional.set_head("If-None-Match", etag);
+            }
+            if let Some(modified) = entry.last_modified() {
+                conditional.set_head("If-Modified-Since", modified);
+            }
+        }
+        let limit = settings::current().cache.max_object_size;
+        let mut tee = Tee::new(client, limit, stale.is_some());
+        Proxy::relay(forward, &conditional, &mut tee, responded).await?;
+        if let Err(e) = tee.flush().await {
+            return Err(ProxyError::ABORTED(format!("write to client: {}", e)));
+        }
+        if tee.not_modified() {
+            return Ok(match stale {
+                Some(entry) => Some(PROXY_CACHE.refresh(key, entry, tee.head()).await),
+                None => None,
+            });
+        }
+        // the response is stored as the upstream sent it
+        if let Some(raw) = tee.copy() {
+            let raw = cache::upstream_response(&raw, responded.affinity_cookie.as_deref());
+            PROXY_CACHE.store(key, request, &raw).await;
+        }
+        Ok(None)
+    }
+    /// revalidate a stale response in the background
+    fn revalidate_later(
+        forward: &Forward,
+        request: &Request,
+        key: &str,
+        entry: &Arc<CacheEntry>,
+        flight: FlightGuard,
+    ) {
+        let (forward, request, key, entry) = (forward.clone(), request.clone(), key.to_string(), entry.clone());
+        tokio::spawn(async move {
+            let mut responded = Responded::default();
+            let mut sink = tokio::io::sink();
+            if let Err(e) = Proxy::fill(&forward, &request, &key, Some(&entry), &mut sink, &mut responded).await {
+                warn!("cache revalidation of {} failed: {}", key, e);
+            }
+            drop(flight);
+        });
+    }
+    /// answer with a cached response, or with `304` when it satisfies the conditions of the request
+    async fn replay<W: AsyncWrite + Unpin>(
+        entry: &CacheEntry,
+        body: &[u8],
+        request: &Request,
+        client: &mut W,
+        x_cache: &str,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let (head, body) = if entry.not_modified(request) {
+            responded.status = Some("304".to_string());
+            (entry.not_modified_head(x_cache), &[][..])
+        } else {
+            responded.status = Some(entry.status().to_string());
+            match request.method() {
+                Method::HEAD => (entry.response_head(x_cache), &[][..]),
+                _ => (entry.response_head(x_cache), body),
+            }
+        };
+        if let Err(e) = client.write_all(head.as_bytes()).await {
+            return Err(ProxyError::ABORTED(format!("write to client: {}", e)));
+        }
+        if let Err(e) = client.write_all(body).await {
+            return Err(ProxyError::ABORTED(format!("write to client: {}", e)));
+        }
+        Ok(())
+    }
+    /// answer with a redirect rule of the route
+    async fn redirect<W: AsyncWrite + Unpin>(
+        request: &Request,
+        client: &mut W,
+        code: &str,
+        location: &str,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let mut response = Response::blank(request);
+        response.set_status(code, reason_phrase(code));
+        response.set_head("Location", location);
+        response.set_head("Connection", "close");
+        response.make_raw();
+        responded.status = Some(code.to_string());
+        match client.write_all(&response.raw()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ProxyError::ABORTED(format!("write to client: {}", e))),
+        }
+    }
+    /// answer a `PURGE` request, removing the cached responses of its url
+    async fn purge<W: AsyncWrite + Unpin>(
+        forward: &Forward,
+        request: &Request,
+        client: &mut W,
+        responded: &mut Responded,
+    ) -> Result<(), ProxyError> {
+        let mut response = Response::blank(request);
+        if !cache::purge_allowed(request) {
+            response.error("403");
+        } else {
+            let mut purged = 0;
+            for key in forward.cache_keys(request) {
+                purged += PROXY_CACHE.purge(&key).await;
+            }
+            match purged {
+                0 => response.error("404"),
+                n => {
+                    response.set_head("Content-Type", "application/json");
+                    response.set_body(&format!("{{\"purged\":{}}}", n));
+                }
+            }
+        }
+        responded.status = Some(response.status_code().to_string());
+        response.set_head("Connection", "close");
+        response.make_raw();
+        match client.write_all(&response.raw()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ProxyError::ABORTED(format!("write to client: {}", e))),
+        }
+    }
+    /// write a request and wait until the upstream starts answering, the error tells
+    /// whether part of the request was written
+    async fn exchange(stream: &mut TcpStream, raw: &[u8]) -> Result<(), (bool, String)> {
+        let mut sent = 0;
+        while sent < raw.len() {
+            match stream.write(&raw[sent..]).await {
+                Ok(0) => return Err((sent > 0, "send: connection closed by upstream".to_string())),
+                Ok(n) => sent += n,
+                Err(e) => return Err((sent > 0, format!("send: {}", e))),
+            }
+        }
+        let mut b = [0u8; 1];
+        match stream.peek(&mut b).await {
+            Ok(0) => Err((true, "connection closed by upstream".to_string())),
+            Ok(_) => Ok(()),
+            Err(e) => Err((true, format!("read: {}", e))),
+        }
+    }
+    /// load balancing, forward the request to an upstream of the group chosen by its balancing mode
+    pub async fn load_balancing(group: &Arc<UpstreamGroup>, request: Request) -> Result<Self, String> {
+        let lease = match group.acquire(&request) {
+            Some(l) => l,
+            None => return Err("no upstream available".to_string()),
+        };
+        let (host, port) = lease.upstream().host_port();
+        let mut result = Proxy::to(host, port, request).await;
+        lease.report(result.is_ok());
+        if let (Ok(ref mut proxy), Some(cookie)) = (&mut result, lease.affinity_cookie()) {
+            proxy.response.add_cookie(cookie);
+        }
+        result
+    }
+    /// forward the request to a third-party server, idempotent requests are retried
+    /// with the default retry policy
     pub async fn to(host: &str, port: &str, request: Request) -> Result<Self, String> {
-        let t = TcpStream::connect(format!("{}:{}", host, port))
-            .await
-            .unwrap();
-        let (r, mut w) = t.into_split();
-        let _ = w.write_all(&request.raw()).await;
-        match Response::async_decode(r).await {
-            Ok(response) => {
-                return Ok(Proxy {
-                    request: request,
-                    response: response,
-                });
+        let address = format!("{}:{}", host, port);
+        let timeout = ProxyTimeout::default();
+        let retry = &*DEFAULT_RETRY_POLICY;
+        retry.budget.record_request();
+        let mut attempt = 0;
+        loop {
+            match Proxy::fetch(&address, &request, &timeout).await {
+                Ok(response) => return Ok(Proxy { request, response }),
+                Err(e) if request.method().is_idempotent()
+                    && attempt < retry.retries
+                    && retry.budget.try_retry() =>
+                {
+                    warn!("proxy to {} attempt {} failed, retrying: {}", address, attempt + 1, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
             }
+        }
+    }
+    /// send the request and decode the whole response
+    async fn fetch(address: &str, request: &Request, timeout: &ProxyTimeout) -> Result<Response, String> {
+        let pooled = match CONNECTION_POOL.get(address, timeout.connect).await {
+            Ok(p) => p,
+            Err(e) => return Err(e.to_string()),
+        };
+        let (r, mut w) = pooled.stream.into_split();
+        if let Err(e) = w.write_all(&upstream_request(request, request.path())).await {
+            return Err(format!("send to {}: {}", address, e));
+        }
+        let deadline = timeout.first_byte.map(|t| tokio::time::Instant::now() + t);
+        match before(deadline, Response::async_decode(r)).await {
+            Some(Ok(response)) => Ok(response),
+            Some(Err(e)) => Err(format!("invalid response from {}: {}", address, e)),
+            None => Err(format!(
+                "no response from {} within {:?}",
+                address,
+                timeout.first_byte.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+/// run a future until an optional deadline, `None` if the deadline passed first
+async fn before<F: Future>(deadline: Option<tokio::time::Instant>, f: F) -> Option<F::Output> {
+    match deadline {
+        Some(d) => tokio::time::timeout_at(d, f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+/// how the end of a response body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// the response has no body
+    NONE,
+    /// `Content-Length` bytes
+    LENGTH(u64),
+    /// chunked transfer coding
+    CHUNKED,
+    /// until the upstream closes the connection
+    CLOSE,
+}
+
+impl Framing {
+    pub fn new(method: &str, status_line: &str, head: &[(String, String)]) -> Self {
+        let status = status_line.split(' ').nth(1).unwrap_or_default();
+        if method == "HEAD" || status.starts_with('1') || status == "204" || status == "304" {
+            return Framing::NONE;
+        }
+        let value = |name: &str| {
+            head.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        if let Some(te) = value("Transfer-Encoding") {
+            return match te.rsplit(',').next() {
+                Some(c) if c.trim().eq_ignore_ascii_case("chunked") => Framing::CHUNKED,
+                _ => Framing::CLOSE,
+            };
+        }
+        match value("Content-Length").map(|l| l.parse::<u64>()) {
+            Some(Ok(n)) => Framing::LENGTH(n),
+            _ => Framing::CLOSE,
+        }
+    }
+}
+
+/// read the status line and the head of a response
+async fn read_head<R: AsyncBufRead + Unpin>(
+    r_buf: &mut R,
+) -> Result<(String, Vec<(String, String)>), String> {
+    let mut status_line = String::default();
+    match r_buf.read_line(&mut status_line).await {
+        Ok(n) if n > 0 && status_line.starts_with("HTTP/") => {}
+        Ok(_) => return Err("invalid response".to_string()),
+        Err(e) => return Err(format!("read: {}", e)),
+    }
+    let mut head = vec![];
+    loop {
+        let mut line = String::default();
+        match r_buf.read_line(&mut line).await {
+            Ok(0) => return Err("truncated response".to_string()),
+            Ok(_) if line == "\r\n" || line == "\n" => break,
+            Ok(_) => {
+                if let Some((k, v)) = line.split_once(':') {
+                    head.push((k.trim().to_string(), v.trim().to_string()));
+                }
+            }
+            Err(e) => return Err(format!("read: {}", e)),
+        }
+    }
+    Ok((status_line, head))
+}
+
+/// copy a chunked body as it is, up to and including its trailer
+async fn relay_chunked<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    r_buf: &mut R,
+    client: &mut W,
+) -> std::io::Result<()> {
+    let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "chunked body truncated");
+    loop {
+        let mut line = String::default();
+        if r_buf.read_line(&mut line).await? == 0 {
+            return Err(truncated());
+        }
+        client.write_all(line.as_bytes()).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = match u64::from_str_radix(size, 16) {
+            Ok(s) => s,
             Err(_) => {
-                // TODO
-                return Err("".to_string());
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid chunk size {:?}", size),
+                ))
             }
+        };
+        if size == 0 {
+            // trailer fields up to the empty line
+            loop {
+                let mut line = String::default();
+                if r_buf.read_line(&mut line).await? == 0 {
+                    return Err(truncated());
+                }
+                client.write_all(line.as_bytes()).await?;
+                if line == "\r\n" || line == "\n" {
+                    return Ok(());
+                }
+            }
+        }
+        // chunk data and its line break
+        if tokio::io::copy(&mut (&mut *r_buf).take(size + 2), client).await? != size + 2 {
+            return Err(truncated());
         }
     }
 }
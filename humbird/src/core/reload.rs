@@ -0,0 +1,232 @@
+/// configuration reload while the server runs, on `SIGHUP` or when the configuration
+/// file changes, an invalid configuration is reported and the one in effect is kept
+use lazy_static::lazy_static;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{runtime::Handle, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::core::{
+    balancer::UpstreamGroup,
+    config::{apply_config, read_config, AccessLogConfig, Config, ConfigError, ServerConfig},
+    health, settings,
+    stream::StreamProxy,
+};
+
+/// time between two looks at the configuration file
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    /// the configuration file and the configuration in effect
+    static ref LOADED: Mutex<Option<(PathBuf, Config)>> = Mutex::new(None);
+    /// modification time and length of the configuration files and include directories
+    /// when they were last read
+    static ref FINGERPRINT: Mutex<Vec<Option<(SystemTime, u64)>>> = Mutex::new(vec![]);
+    /// health checks and stream proxies of the configuration in effect
+    static ref TASKS: Mutex<Vec<(Task, JoinHandle<()>)>> = Mutex::new(vec![]);
+    /// serializes the reloads
+    static ref RELOADING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// record the configuration the server started with
+pub fn loaded(path: &Path, config: Config) {
+    *FINGERPRINT.lock().unwrap() = fingerprint(&config.sources);
+    *LOADED.lock().unwrap() = Some((path.to_path_buf(), config));
+}
+
+/// the configuration file of the server, `None` when it runs without one
+pub fn config_file() -> Option<PathBuf> {
+    LOADED.lock().ok()?.as_ref().map(|(p, _)| p.clone())
+}
+
+/// whether the configuration in effect asks to watch its file
+pub fn watching() -> bool {
+    match LOADED.lock() {
+        Ok(l) => l.as_ref().is_some_and(|(_, c)| c.server.watch_config),
+        Err(_) => false,
+    }
+}
+
+/// a background task of the settings in effect
+enum Task {
+    /// health checks of an upstream group
+    Health(Arc<UpstreamGroup>),
+    /// listener of a stream proxy
+    Stream(Arc<StreamProxy>),
+}
+
+impl Task {
+    /// whether both tasks serve the same group or stream proxy
+    fn same(&self, other: &Task) -> bool {
+        match (self, other) {
+            (Task::Health(a), Task::Health(b)) => Arc::ptr_eq(a, b),
+            (Task::Stream(a), Task::Stream(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+    fn spawn(&self, rt: &Handle) -> JoinHandle<()> {
+        match self {
+            Task::Health(g) => rt.spawn(health::check_loop(g.clone())),
+            Task::Stream(s) => rt.spawn(s.clone().serve()),
+        }
+    }
+}
+
+/// the health checks and the stream proxies the settings in effect need
+fn wanted() -> Vec<Task> {
+    let settings = settings::current();
+    let mut tasks = vec![];
+    for group in settings.all_upstream_groups() {
+        if group.health_check.is_some() {
+            tasks.push(Task::Health(group));
+        }
+    }
+    for stream in settings.streams.iter() {
+        tasks.push(Task::Stream(stream.clone()));
+    }
+    tasks
+}
+
+/// start the health checks and the stream proxies of the settings in effect
+pub fn start_tasks(rt: &Handle) {
+    let tasks = wanted().into_iter().map(|t| {
+        let handle = t.spawn(rt);
+        (t, handle)
+    });
+    *TASKS.lock().unwrap() = tasks.collect();
+}
+
+/// stop the tasks the settings in effect no longer need and start the ones they add, the
+/// groups and stream proxies kept by a reload keep their tasks and listeners, connections
+/// a stopped stream proxy relays are served until they close
+async fn update_tasks(rt: &Handle) {
+    let wanted = wanted();
+    let running = std::mem::take(&mut *TASKS.lock().unwrap());
+    let (mut kept, stopped): (Vec<_>, Vec<_>) =
+        running.into_iter().partition(|(t, _)| wanted.iter().any(|w| w.same(t)));
+    for (_, handle) in stopped.iter() {
+        handle.abort();
+    }
+    // the listeners are closed once the aborted tasks are done, before new ones may bind
+    // the same addresses
+    for (_, handle) in stopped {
+        let _ = handle.await;
+    }
+    for t in wanted {
+        if !kept.iter().any(|(k, _)| k.same(&t)) {
+            let handle = t.spawn(rt);
+            kept.push((t, handle));
+        }
+    }
+    *TASKS.lock().unwrap() = kept;
+}
+
+/// read the configuration file again and put it in effect, the configuration in effect
+/// is kept when the new one is invalid, the listener, runtime, log and access log file settings
+/// need a restart
+pub async fn reload() -> Result<(), Vec<ConfigError>> {
+    let _guard = RELOADING.lock().await;
+    let (path, current) = match LOADED.lock().unwrap().clone() {
+        Some(l) => l,
+        None => return Ok(()),
+    };
+    *FINGERPRINT.lock().unwrap() = fingerprint(&current.sources);
+    let mut next = read_config(&path)?;
+    let (s, c) = (&next.server, &current.server);
+    if s.address != c.address
+        || s.port != c.port
+        || s.proxy_protocol != c.proxy_protocol
+        || s.workers != c.workers
+        || s.pid_file != c.pid_file
+        || next.log != current.log
+    {
+        warn!("[server] address, port, proxy-protocol, workers, pid-file and [log] changes take effect after a restart");
+        next.server = ServerConfig {
+            watch_config: next.server.watch_config,
+            max_body_size: next.server.max_body_size,
+            ..current.server.clone()
+        };
+        next.log = current.log.clone();
+    }
+    let (a, c) = (&next.access_log, &current.access_log);
+    if a.file != c.file || a.directory != c.directory || a.rotation != c.rotation || a.max_files != c.max_files {
+        warn!("[access-log] file, directory, rotation and max-files changes take effect after a restart");
+        next.access_log = AccessLogConfig {
+            file: c.file.clone(),
+            directory: c.directory.clone(),
+            rotation: c.rotation.clone(),
+            max_files: c.max_files,
+            ..next.access_log
+        };
+    }
+    apply_config(&next, Some(&current));
+    update_tasks(&Handle::current()).await;
+    *FINGERPRINT.lock().unwrap() = fingerprint(&next.sources);
+    *LOADED.lock().unwrap() = Some((path.clone(), next));
+    info!("configuration {} reloaded", path.display());
+    Ok(())
+}
+
+/// reload and report the problems of an invalid configuration
+async fn reload_logged() {
+    if let Err(errors) = reload().await {
+        for e in errors.iter() {
+            error!("{}", e);
+        }
+        error!("configuration not reloaded, the one in effect is kept");
+    }
+}
+
+/// reload the configuration on every `SIGHUP`
+#[cfg(unix)]
+pub async fn on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("SIGHUP handler not installed: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading the configuration");
+        reload_logged().await;
+    }
+}
+
+/// reload the configuration whenever one of its files changes or a file is added to or
+/// removed from an include directory, as long as `watch-config` is set
+pub async fn watch() {
+    let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if !watching() {
+            continue;
+        }
+        let (path, sources) = match LOADED.lock().unwrap().as_ref() {
+            Some((p, c)) => (p.clone(), c.sources.clone()),
+            None => return,
+        };
+        let now = fingerprint(&sources);
+        // the configuration file may be missing for a moment while an editor replaces it
+        if now.first().is_some_and(|f| f.is_some()) && now != *FINGERPRINT.lock().unwrap() {
+            info!("configuration {} changed, reloading", path.display());
+            reload_logged().await;
+        }
+    }
+}
+
+/// modification time and length of files and directories
+fn fingerprint(paths: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    paths
+        .iter()
+        .map(|p| {
+            let m = fs::metadata(p).ok()?;
+            Some((m.modified().ok()?, m.len()))
+        })
+        .collect()
+}
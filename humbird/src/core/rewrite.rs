@@ -0,0 +1,121 @@
+/// rewriting rules of a proxy route, path rewrites, redirects and header changes
+/// adapting a backend without changing it
+use regex::Regex;
+
+use crate::protocol::http::Request;
+
+/// status codes of redirect rules
+pub const REDIRECT_STATUS: [&str; 4] = ["301", "302", "307", "308"];
+
+/// rewrite of the path sent to the upstream
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    /// replacement, `$1` and `${name}` refer to the capture groups of the pattern
+    pub replace: String,
+}
+
+/// redirect answered without asking the upstream
+#[derive(Debug, Clone)]
+pub struct RedirectRule {
+    pub pattern: Regex,
+    /// location, `$1` and `${name}` refer to the capture groups of the pattern
+    pub to: String,
+    /// one of 301, 302, 307 and 308
+    pub status: &'static str,
+}
+
+/// header changes, applied as removals, then replacements, then additions
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    /// headers removed
+    pub remove: Vec<String>,
+    /// headers replaced, added when missing
+    pub set: Vec<(String, String)>,
+    /// headers added next to present ones of the same name
+    pub add: Vec<(String, String)>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.set.is_empty() && self.add.is_empty()
+    }
+    /// change the headers of a request, an added header present already gets the value appended
+    pub fn apply_request(&self, request: &mut Request) {
+        for k in self.remove.iter() {
+            request.remove_head(k);
+        }
+        for (k, v) in self.set.iter() {
+            request.set_head(k, v);
+        }
+        for (k, v) in self.add.iter() {
+            let value = match request.heads().iter().find(|(name, _)| name.eq_ignore_ascii_case(k)) {
+                Some((_, present)) => format!("{}, {}", present, v),
+                None => v.to_string(),
+            };
+            request.set_head(k, &value);
+        }
+    }
+    /// change the headers of a response head
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::rewrite::HeaderRules;
+    /// let rules = HeaderRules {
+    ///     remove: vec!["Server".to_string()],
+    ///     set: vec![("Cache-Control".to_string(), "no-store".to_string())],
+    ///     add: vec![("Vary".to_string(), "Origin".to_string())],
+    /// };
+    /// let mut head = vec![
+    ///     ("server".to_string(), "legacy".to_string()),
+    ///     ("Vary".to_string(), "Accept".to_string()),
+    /// ];
+    /// rules.apply(&mut head);
+    /// assert_eq!(head.len(), 3);
+    /// assert_eq!(head[1], ("Cache-Control".to_string(), "no-store".to_string()));
+    /// ```
+    pub fn apply(&self, head: &mut Vec<(String, String)>) {
+        head.retain(|(name, _)| !self.remove.iter().any(|k| k.eq_ignore_ascii_case(name)));
+        for (k, v) in self.set.iter() {
+            head.retain(|(name, _)| !name.eq_ignore_ascii_case(k));
+            head.push((k.to_string(), v.to_string()));
+        }
+        for (k, v) in self.add.iter() {
+            head.push((k.to_string(), v.to_string()));
+        }
+    }
+}
+
+/// rewriting rules of a proxy route
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRules {
+    /// path rewrites, the first matching one applies
+    pub rewrite: Vec<RewriteRule>,
+    /// redirects, the first matching one applies
+    pub redirect: Vec<RedirectRule>,
+    /// changes of the request sent to the upstream
+    pub request_headers: HeaderRules,
+    /// changes of the response sent to the client
+    pub response_headers: HeaderRules,
+}
+
+impl RewriteRules {
+    /// path sent to the upstream, a replacement containing `?` replaces the query string as well
+    pub fn path(&self, path: &str) -> String {
+        match self.rewrite.iter().find(|r| r.pattern.is_match(path)) {
+            Some(r) => r.pattern.replace(path, r.replace.as_str()).into_owned(),
+            None => path.to_string(),
+        }
+    }
+    /// status and location of the redirect a request matches, the query string is kept
+    /// unless the location has one
+    pub fn redirect(&self, request: &Request) -> Option<(&'static str, String)> {
+        let rule = self.redirect.iter().find(|r| r.pattern.is_match(request.path()))?;
+        let mut location = rule.pattern.replace(request.path(), rule.to.as_str()).into_owned();
+        if !location.contains('?') && !request.query().is_empty() {
+            location.push('?');
+            location.push_str(request.query());
+        }
+        Some((rule.status, location))
+    }
+}
@@ -1,22 +1,47 @@
 /// core network service module, providing core network functions
-use crate::protocol::http::Http;
-use chrono::Local;
+use crate::{
+    core::{
+        forward_proxy::ForwardProxy,
+        access_log, log, mirror, pid, pool,
+        proxy::{Forward, Proxy},
+        reload,
+    },
+    protocol::{
+        http::{Http, Request},
+        proxy_protocol::{self, Header},
+    },
+};
 use lazy_static::lazy_static;
-use mio::{Events, Interest, Poll, Token};
-use std::{collections::HashMap, sync::Mutex};
+use mio::{net::TcpStream, Events, Interest, Poll, Registry, Token};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 use tokio::runtime::Runtime;
-use tracing::Level;
-use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
-/// server listening address
-pub const SERVER_LISTENING_ADDR: &'static str = "0.0.0.0";
+/// server listening default address
+pub const DEFAULT_SERVER_LISTENING_ADDR: &str = "0.0.0.0";
 /// server listening default port
 pub const DEFAULT_SERVER_LISTENING_PORT: &'static str = "9999";
+/// default number of worker threads
+pub const DEFAULT_WORKERS: usize = 10;
+/// default file the process id is written to
+pub const DEFAULT_PID_FILE: &str = "humbird.pid";
+/// default largest request body accepted, 16 MiB
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
 /// global constants related to services
 lazy_static! {
+   /// server listening address,default 0.0.0.0
+   pub static ref SERVER_LISTENING_ADDR: Mutex<String> = Mutex::new(DEFAULT_SERVER_LISTENING_ADDR.to_string());
    /// server listening port,default 9999
    pub static ref SERVER_LISTENING_PORT: Mutex<String> = Mutex::new(String::from(DEFAULT_SERVER_LISTENING_PORT.to_string()));
-   /// local static resources root path
-   pub static ref ROOT_PATH: Mutex<String> = Mutex::new(String::default());
+   /// worker threads of the runtime, default 10
+   pub static ref WORKERS: Mutex<usize> = Mutex::new(DEFAULT_WORKERS);
+   /// file the process id is written to, empty for none
+   pub static ref PID_FILE: Mutex<String> = Mutex::new(DEFAULT_PID_FILE.to_string());
+   /// whether connections open with a PROXY protocol header, default false
+   pub static ref PROXY_PROTOCOL: Mutex<bool> = Mutex::new(false);
 }
 // humbird server token
 const HUMBIRD_SERVER_TOKEN: Token = Token(0);
@@ -49,7 +74,20 @@ impl Server {
         match Server::new() {
             Some(s) => {
                 // initialize the log system
-                init_log();
+                log::init();
+                access_log::init();
+                let pid_file = PID_FILE.lock().unwrap().clone();
+                if !pid_file.is_empty() {
+                    if let Err(e) = pid::write(&pid_file) {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                s.rt.spawn(pid::on_terminate(pid_file));
+                s.rt.spawn(pool::log_metrics());
+                s.rt.spawn(mirror::log_metrics());
+                reload::start_tasks(s.rt.handle());
+                s.reload();
                 s.event_poll();
             }
             None => {
@@ -57,14 +95,20 @@ impl Server {
             }
         }
     }
-    /// handle server global configurable constants, based on configuration files
+    /// handle server global configurable constants, based on configuration files,
+    /// the process exits when the configuration is invalid
     fn config(config_file_path: Option<String>) {
-        load_config(config_file_path);
+        if let Err(errors) = load_config(config_file_path) {
+            for e in errors.iter() {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
     }
     /// create a network service core abstraction instance
     fn new() -> Option<Server> {
         let r = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(10)
+            .worker_threads(*WORKERS.lock().unwrap())
             .enable_all()
             .build();
         match r {
@@ -82,13 +126,10 @@ impl Server {
         match Poll::new() {
             Ok(mut poll) => {
                 let mut events = Events::with_capacity(EVENT_POOL_COUNT);
-                let address = format!(
-                    "{}:{}",
-                    SERVER_LISTENING_ADDR,
-                    SERVER_LISTENING_PORT.lock().unwrap()
-                )
-                .parse()
-                .unwrap();
+                let address = SocketAddr::new(
+                    SERVER_LISTENING_ADDR.lock().unwrap().parse().unwrap(),
+                    SERVER_LISTENING_PORT.lock().unwrap().parse().unwrap(),
+                );
                 let mut server = mio::net::TcpListener::bind(address).unwrap();
                 match poll.registry().register(
                     &mut server,
@@ -98,56 +139,135 @@ impl Server {
                     Ok(_) => {
                         // connection pool mapping
                         let mut connections = HashMap::new();
+                        // responses waiting for the socket to become writable
+                        let mut pending: HashMap<Token, Outbound> = HashMap::new();
+                        // connections with a pending response that became readable meanwhile, the
+                        // edge does not come again and the request is decoded after the response
+                        let mut readable: HashSet<Token> = HashSet::new();
+                        // connections whose PROXY protocol header has not been read yet
+                        let proxy_protocol = *PROXY_PROTOCOL.lock().unwrap();
+                        let mut awaiting: HashSet<Token> = HashSet::new();
+                        // client addresses announced by PROXY protocol headers
+                        let mut clients: HashMap<Token, SocketAddr> = HashMap::new();
                         let mut unique_token = Token(HUMBIRD_SERVER_TOKEN.0 + 1);
                         // launch info
                         println!("{}", boot_info_string(true));
                         loop {
-                            let _ = poll.poll(&mut events, None).unwrap();
+                            match poll.poll(&mut events, None) {
+                                Ok(_) => {}
+                                // a signal handled by the runtime, e.g. SIGHUP reloading the configuration
+                                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                                Err(e) => panic!("{}", e),
+                            }
                             for event in events.iter() {
                                 match event.token() {
                                     // a new connection
                                     HUMBIRD_SERVER_TOKEN => {
-                                        let (mut connection, _address) = match server.accept() {
-                                            Ok((connection, address)) => (connection, address),
-                                            Err(e)
-                                                if e.kind() == std::io::ErrorKind::WouldBlock =>
+                                        // accept every waiting connection, the listener is edge triggered
+                                        loop {
+                                            let (mut connection, _address) = match server.accept() {
+                                                Ok((connection, address)) => (connection, address),
+                                                Err(e)
+                                                    if e.kind() == std::io::ErrorKind::WouldBlock =>
+                                                {
+                                                    break;
+                                                }
+                                                Err(_e) => {
+                                                    break;
+                                                }
+                                            };
+                                            // the unique token of the tcp link
+                                            let token = {
+                                                let next = unique_token.0;
+                                                unique_token.0 += 1;
+                                                Token(next)
+                                            };
+                                            poll.registry()
+                                                .register(
+                                                    &mut connection,
+                                                    token,
+                                                    Interest::READABLE.add(Interest::WRITABLE),
+                                                )
+                                                .unwrap();
+                                            connections.insert(token, connection);
+                                            if proxy_protocol {
+                                                awaiting.insert(token);
+                                                if !proxy_header(&mut connections, &mut awaiting, &mut clients, token) {
+                                                    continue;
+                                                }
+                                            }
+                                            let client = clients.get(&token).copied();
+                                            match Http::new(event, &connections, &token, &mut pending, client)
                                             {
-                                                break;
+                                                Ok(http) => {
+                                                    self.dispatch(
+                                                        poll.registry(),
+                                                        &mut connections,
+                                                        &mut clients,
+                                                        token,
+                                                        http,
+                                                    );
+                                                    continue;
+                                                }
+                                                Err(_) => {
+                                                    continue;
+                                                }
                                             }
-                                            Err(_e) => {
-                                                break;
+                                        }
+                                    }
+                                    // reuse
+                                    token => {
+                                        // continue an unfinished response first
+                                        if let Some(outbound) = pending.get_mut(&token) {
+                                            if event.is_readable() {
+                                                readable.insert(token);
                                             }
-                                        };
-                                        // the unique token of the tcp link
-                                        let token = {
-                                            let next = unique_token.0;
-                                            unique_token.0 += 1;
-                                            Token(next)
-                                        };
-                                        poll.registry()
-                                            .register(
-                                                &mut connection,
-                                                token,
-                                                Interest::READABLE.add(Interest::WRITABLE),
-                                            )
-                                            .unwrap();
-                                        connections.insert(token, connection);
-                                        match Http::new(&event, &connections, &token) {
-                                            Ok(_http) => {
+                                            if !event.is_writable() {
                                                 continue;
                                             }
-                                            Err(_) => {
-                                                continue;
+                                            let flushed = match connections.get(&token) {
+                                                Some(stream) => outbound.flush(stream),
+                                                None => Ok(true),
+                                            };
+                                            match flushed {
+                                                Ok(true) => {
+                                                    pending.remove(&token);
+                                                    // a follow-up request that arrived during the response
+                                                    if !readable.remove(&token) {
+                                                        continue;
+                                                    }
+                                                }
+                                                Ok(false) => continue,
+                                                Err(_) => {
+                                                    pending.remove(&token);
+                                                    readable.remove(&token);
+                                                    connections.remove(&token);
+                                                    clients.remove(&token);
+                                                    continue;
+                                                }
                                             }
                                         }
-                                    }
-                                    // reuse
-                                    token => {
+                                        if !proxy_header(&mut connections, &mut awaiting, &mut clients, token) {
+                                            continue;
+                                        }
                                         if connections.contains_key(&token) {
                                             match connections.get(&token) {
                                                 Some(_stream) => {
-                                                    match Http::new(&event, &connections, &token) {
-                                                        Ok(_http) => {
+                                                    match Http::new(
+                                                        event,
+                                                        &connections,
+                                                        &token,
+                                                        &mut pending,
+                                                        clients.get(&token).copied(),
+                                                    ) {
+                                                        Ok(http) => {
+                                                            self.dispatch(
+                                                                poll.registry(),
+                                                                &mut connections,
+                                                                &mut clients,
+                                                                token,
+                                                                http,
+                                                            );
                                                             continue;
                                                         }
                                                         Err(_) => {
@@ -179,33 +299,220 @@ impl Server {
     }
 }
 
-pub fn init_log() {
-    // log time
-    struct LocalTimer;
-    impl FormatTime for LocalTimer {
-        fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
-            write!(w, "{}", Local::now().format("%FT%T%.3f"))
+impl Server {
+    /// reload the configuration on `SIGHUP`, and when its file changes if `watch-config` is set
+    fn reload(&self) {
+        if reload::config_file().is_none() {
+            return;
         }
+        #[cfg(unix)]
+        self.rt.spawn(reload::on_hangup());
+        self.rt.spawn(reload::watch());
     }
-    // log init
-    let file_appender = tracing_appender::rolling::daily("", "numbird.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    let format = tracing_subscriber::fmt::format()
-        .with_level(true)
-        .with_target(true)
-        .with_timer(LocalTimer);
-    tracing_subscriber::fmt()
-        .with_max_level(Level::TRACE)
-        .with_writer(std::io::stdout)
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .event_format(format)
-        .init();
+    /// hand a connection over to the runtime when its request is not answered by the event
+    /// loop, the reverse and the forward proxy serve it from a task of their own, as does a
+    /// request whose body is still on the connection
+    fn dispatch(
+        &self,
+        registry: &Registry,
+        connections: &mut HashMap<Token, TcpStream>,
+        clients: &mut HashMap<Token, SocketAddr>,
+        token: Token,
+        http: Http,
+    ) {
+        if let Some(forward) = http.forward {
+            self.forward(registry, connections, token, http.request, forward);
+        } else if let Some(p) = http.forward_proxy {
+            self.forward_proxy(registry, connections, token, http.request, p);
+        } else if http.unread_body {
+            self.answer(registry, connections, token, http.request);
+        } else {
+            return;
+        }
+        clients.remove(&token);
+    }
+    /// hand a connection over to the reverse proxy, it leaves the event poll and is
+    /// served by a task of the runtime until the upstream response has been relayed
+    fn forward(
+        &self,
+        registry: &Registry,
+        connections: &mut HashMap<Token, TcpStream>,
+        token: Token,
+        request: Request,
+        forward: Forward,
+    ) {
+        let stream = match detach(registry, connections, token) {
+            Some(s) => s,
+            None => return,
+        };
+        self.rt.spawn(async move {
+            match tokio::net::TcpStream::from_std(stream) {
+                Ok(client) => {
+                    let _ = Proxy::forward(forward, request, client).await;
+                }
+                Err(e) => {
+                    tracing::error!("proxy connection handover failed: {}", e);
+                }
+            }
+        });
+    }
+    /// hand a connection over to the forward proxy, served by a task of the runtime
+    /// until the target response has been relayed or the tunnel closes
+    fn forward_proxy(
+        &self,
+        registry: &Registry,
+        connections: &mut HashMap<Token, TcpStream>,
+        token: Token,
+        request: Request,
+        proxy: Arc<ForwardProxy>,
+    ) {
+        let stream = match detach(registry, connections, token) {
+            Some(s) => s,
+            None => return,
+        };
+        self.rt.spawn(async move {
+            match tokio::net::TcpStream::from_std(stream) {
+                Ok(client) => {
+                    let _ = proxy.serve(request, client).await;
+                }
+                Err(e) => {
+                    tracing::error!("forward proxy connection handover failed: {}", e);
+                }
+            }
+        });
+    }
+    /// hand a connection over to a task reading the body of its request, the request is
+    /// answered once the body has been read and the connection is closed afterwards
+    fn answer(
+        &self,
+        registry: &Registry,
+        connections: &mut HashMap<Token, TcpStream>,
+        token: Token,
+        request: Request,
+    ) {
+        let stream = match detach(registry, connections, token) {
+            Some(s) => s,
+            None => return,
+        };
+        self.rt.spawn(async move {
+            match tokio::net::TcpStream::from_std(stream) {
+                Ok(client) => {
+                    let _ = Http::serve(request, client).await;
+                }
+                Err(e) => {
+                    tracing::error!("request body connection handover failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// take a connection out of the event poll
+fn detach(
+    registry: &Registry,
+    connections: &mut HashMap<Token, TcpStream>,
+    token: Token,
+) -> Option<std::net::TcpStream> {
+    let mut connection = connections.remove(&token)?;
+    let _ = registry.deregister(&mut connection);
+    Some(into_std(connection))
+}
+
+/// read the PROXY protocol header of a connection awaiting one, returns whether the
+/// connection is ready for http, a connection opening with anything else is closed
+fn proxy_header(
+    connections: &mut HashMap<Token, TcpStream>,
+    awaiting: &mut HashSet<Token>,
+    clients: &mut HashMap<Token, SocketAddr>,
+    token: Token,
+) -> bool {
+    if !awaiting.contains(&token) {
+        return true;
+    }
+    let result = match connections.get_mut(&token) {
+        Some(connection) => read_proxy_header(connection),
+        None => return false,
+    };
+    match result {
+        // the rest of the header arrives with a following readable event
+        Ok(None) => false,
+        Ok(Some(header)) => {
+            awaiting.remove(&token);
+            if let Some(source) = header.source {
+                clients.insert(token, source);
+            }
+            true
+        }
+        Err(e) => {
+            tracing::warn!("connection rejected: {}", e);
+            awaiting.remove(&token);
+            connections.remove(&token);
+            false
+        }
+    }
+}
+
+/// consume the PROXY protocol header opening a connection, `Ok(None)` while it is incomplete,
+/// a version 2 header longer than a version 1 line is peeked at the length it declares
+fn read_proxy_header(connection: &mut TcpStream) -> Result<Option<Header>, String> {
+    let mut prefix = [0u8; proxy_protocol::V1_MAX_LENGTH];
+    let n = match peek_proxy_header(connection, &mut prefix)? {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let header = match proxy_protocol::parse(&prefix[..n])? {
+        Some(h) => h,
+        None => match proxy_protocol::v2_length(&prefix[..n]) {
+            Some(length) if length > prefix.len() => {
+                let mut buf = vec![0u8; length];
+                let n = match peek_proxy_header(connection, &mut buf)? {
+                    Some(n) => n,
+                    None => return Ok(None),
+                };
+                match proxy_protocol::parse(&buf[..n])? {
+                    Some(h) => h,
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        },
+    };
+    // the peeked header has arrived, reading it does not block
+    match std::io::copy(&mut (&*connection).take(header.length as u64), &mut std::io::sink()) {
+        Ok(n) if n == header.length as u64 => Ok(Some(header)),
+        Ok(_) => Err("read PROXY protocol header: connection closed".to_string()),
+        Err(e) => Err(format!("read PROXY protocol header: {}", e)),
+    }
+}
+
+/// look at the bytes received on a connection without consuming them, `Ok(None)` when
+/// there are none yet
+fn peek_proxy_header(connection: &TcpStream, buf: &mut [u8]) -> Result<Option<usize>, String> {
+    match connection.peek(buf) {
+        Ok(0) => Err("connection closed before the PROXY protocol header".to_string()),
+        Ok(n) => Ok(Some(n)),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// convert an event poll connection into a standard library one
+#[cfg(unix)]
+fn into_std(connection: TcpStream) -> std::net::TcpStream {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    unsafe { std::net::TcpStream::from_raw_fd(connection.into_raw_fd()) }
+}
+
+/// convert an event poll connection into a standard library one
+#[cfg(windows)]
+fn into_std(connection: TcpStream) -> std::net::TcpStream {
+    use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+    unsafe { std::net::TcpStream::from_raw_socket(connection.into_raw_socket()) }
 }
 
 use prettytable::{row, Table};
 
-use super::config::load_config;
+use super::{config::load_config, writer::Outbound};
 
 pub fn boot_info_string(status: bool) -> String {
     let logo: &str = "
@@ -233,8 +540,8 @@ pub fn boot_info_string(status: bool) -> String {
     ]);
     table.add_row(row!["Address", "Port", "", "", "", ""]);
     table.add_row(row![
-        SERVER_LISTENING_ADDR,
-        DEFAULT_SERVER_LISTENING_PORT,
+        SERVER_LISTENING_ADDR.lock().unwrap(),
+        SERVER_LISTENING_PORT.lock().unwrap(),
         "",
         " ",
         "",
@@ -0,0 +1,112 @@
+/// settings of the configuration in effect, built as a whole and published at once when
+/// the configuration is reloaded, a request reads one snapshot and never mixes the routes
+/// of a configuration with the upstream groups of another
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::core::{
+    access_log::AccessLogSettings,
+    balancer::UpstreamGroup,
+    cache::CacheSettings,
+    compression::Compression,
+    directory::Directory,
+    forward_proxy::ForwardProxy,
+    pool::PoolSettings,
+    proxy::{ProxyRoute, DEFAULT_UPSTREAM},
+    server::DEFAULT_MAX_BODY_SIZE,
+    stream::StreamProxy,
+    vhost::VirtualHost,
+};
+
+lazy_static! {
+    /// settings in effect
+    static ref SETTINGS: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
+}
+
+/// hot reloadable settings
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// largest request body accepted, larger ones are answered with 413
+    pub max_body_size: u64,
+    /// server wide `[directory]` entries, the working directory is served until configured
+    pub directories: Vec<Directory>,
+    /// settings of the `[compression]` table
+    pub compression: Compression,
+    /// error page files of `[error-page]`, keyed by status code
+    pub error_pages: HashMap<String, String>,
+    /// proxy target upstream group of the `[proxy]` table
+    pub proxy_target: Arc<UpstreamGroup>,
+    /// named upstream groups of the `[upstream.<name>]` tables
+    pub upstream_groups: HashMap<String, Arc<UpstreamGroup>>,
+    /// `[[proxy.route]]` entries
+    pub proxy_routes: Vec<ProxyRoute>,
+    /// `[[vhost]]` entries in configuration order
+    pub virtual_hosts: Vec<VirtualHost>,
+    /// settings of the `[proxy.pool]` table
+    pub pool: PoolSettings,
+    /// settings of the `[proxy.cache]` table
+    pub cache: CacheSettings,
+    /// `[[stream]]` entries
+    pub streams: Vec<Arc<StreamProxy>>,
+    /// the `[forward-proxy]` table when it is enabled
+    pub forward_proxy: Option<Arc<ForwardProxy>>,
+    /// settings of the `[access-log]` table
+    pub access_log: AccessLogSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            directories: vec![Directory::default()],
+            compression: Compression::default(),
+            error_pages: HashMap::new(),
+            proxy_target: Arc::new(UpstreamGroup::default()),
+            upstream_groups: HashMap::new(),
+            proxy_routes: vec![],
+            virtual_hosts: vec![],
+            pool: PoolSettings::default(),
+            cache: CacheSettings::default(),
+            streams: vec![],
+            forward_proxy: None,
+            access_log: AccessLogSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// the upstream group of a name, `default` is the `[proxy]` target list
+    pub fn upstream_group(&self, name: &str) -> Option<Arc<UpstreamGroup>> {
+        if name.is_empty() || name == DEFAULT_UPSTREAM {
+            return Some(self.proxy_target.clone());
+        }
+        self.upstream_groups.get(name).cloned()
+    }
+    /// every upstream group, each listed once
+    pub fn all_upstream_groups(&self) -> Vec<Arc<UpstreamGroup>> {
+        let mut groups = vec![self.proxy_target.clone()];
+        groups.extend(self.upstream_groups.values().cloned());
+        groups.extend(self.virtual_hosts.iter().filter_map(|h| h.proxy.clone()));
+        groups.extend(self.streams.iter().map(|s| s.group.clone()));
+        let mut unique: Vec<Arc<UpstreamGroup>> = vec![];
+        for g in groups {
+            if !unique.iter().any(|u| Arc::ptr_eq(u, &g)) {
+                unique.push(g);
+            }
+        }
+        unique
+    }
+}
+
+/// the settings in effect
+pub fn current() -> Arc<Settings> {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// put settings in effect, requests being served keep the snapshot they started with
+pub fn publish(settings: Settings) {
+    *SETTINGS.write().unwrap() = Arc::new(settings);
+}
@@ -0,0 +1,76 @@
+/// traffic splitting of a proxy route between upstream groups, canary releases send a
+/// small share of the requests to a new group, clients may force a group with a header
+/// or a cookie, the weights are changed by reloading the configuration
+use std::sync::Arc;
+
+use crate::{core::balancer::random_u64, protocol::http::Request};
+
+/// an upstream group of a split and its share of the requests
+#[derive(Debug)]
+pub struct SplitGroup {
+    /// upstream group name
+    pub upstream: String,
+    /// relative weight, no request is sent to a group of weight 0 unless it is forced
+    pub weight: u32,
+}
+
+/// split settings of a proxy route
+#[derive(Debug, Clone, Default)]
+pub struct TrafficSplit {
+    /// groups, shared by the clones of the route
+    pub groups: Arc<Vec<SplitGroup>>,
+    /// header naming the group a request is sent to
+    pub header: Option<String>,
+    /// cookie naming the group a request is sent to
+    pub cookie: Option<String>,
+}
+
+impl TrafficSplit {
+    /// name of the upstream group serving a request, a group named by the override header
+    /// or cookie is taken first, otherwise one is drawn by weight
+    ///
+    /// Example
+    /// ```rust
+    /// use humbird::core::split::{SplitGroup, TrafficSplit};
+    /// use std::sync::Arc;
+    /// let split = TrafficSplit {
+    ///     groups: Arc::new(vec![
+    ///         SplitGroup { upstream: "stable".to_string(), weight: 100 },
+    ///         SplitGroup { upstream: "canary".to_string(), weight: 0 },
+    ///     ]),
+    ///     header: Some("X-Upstream-Group".to_string()),
+    ///     cookie: None,
+    /// };
+    /// assert_eq!(split.choose(None, None), Some("stable"));
+    /// assert_eq!(split.choose(Some("canary"), None), Some("canary"));
+    /// assert_eq!(split.choose(Some("unknown"), None), Some("stable"));
+    /// ```
+    pub fn choose(&self, header: Option<&str>, cookie: Option<&str>) -> Option<&str> {
+        let forced = [header, cookie]
+            .into_iter()
+            .flatten()
+            .find_map(|name| self.groups.iter().find(|g| g.upstream == name.trim()));
+        if let Some(g) = forced {
+            return Some(&g.upstream);
+        }
+        let total: u64 = self.groups.iter().map(|g| g.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut r = random_u64() % total;
+        for g in self.groups.iter() {
+            let w = g.weight as u64;
+            if r < w {
+                return Some(&g.upstream);
+            }
+            r -= w;
+        }
+        None
+    }
+    /// name of the upstream group serving a request
+    pub fn choose_for(&self, request: &Request) -> Option<&str> {
+        let header = self.header.as_ref().and_then(|h| request.head(h));
+        let cookie = self.cookie.as_ref().and_then(|c| request.cookie(c));
+        self.choose(header.map(|h| h.as_str()), cookie.map(|c| c.as_str()))
+    }
+}
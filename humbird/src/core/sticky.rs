@@ -0,0 +1,134 @@
+/// session affinity, pins the requests of a client to one upstream of a group
+/// on top of its balancing mode
+use std::time::Duration;
+
+use crate::{
+    core::balancer::{fnv1a, mix, UpstreamGroup},
+    protocol::http::Request,
+};
+
+/// default name of the affinity cookie
+pub const DEFAULT_AFFINITY_COOKIE: &str = "HUMBIRD_AFFINITY";
+
+/// what a client is recognized by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    /// a cookie issued by the proxy naming the upstream
+    COOKIE,
+    /// hash of the client address
+    IP,
+    /// hash of a request header value
+    HEADER(String),
+}
+
+impl Affinity {
+    pub fn new(m: &str) -> Option<Self> {
+        match m.split_once(':') {
+            Some((k, h)) if k.trim().eq_ignore_ascii_case("header") => {
+                Some(Affinity::HEADER(h.trim().to_string()))
+            }
+            _ => match m.trim().to_ascii_lowercase().as_str() {
+                "cookie" => Some(Affinity::COOKIE),
+                "ip" | "source-ip" => Some(Affinity::IP),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// sticky session settings, the `sticky` table of an upstream group
+#[derive(Debug, Clone)]
+pub struct StickySession {
+    pub affinity: Affinity,
+    /// name of the affinity cookie
+    pub cookie_name: String,
+    /// path of the affinity cookie
+    pub cookie_path: String,
+    /// lifetime of the affinity cookie, `None` makes it a session cookie
+    pub cookie_max_age: Option<Duration>,
+}
+
+impl StickySession {
+    pub fn new(affinity: Affinity) -> Self {
+        StickySession {
+            affinity,
+            cookie_name: DEFAULT_AFFINITY_COOKIE.to_string(),
+            cookie_path: "/".to_string(),
+            cookie_max_age: None,
+        }
+    }
+    /// the upstream a request is pinned to, `None` when the request carries no affinity
+    /// or its upstream is unavailable, the balancing mode decides then
+    pub fn pinned(&self, group: &UpstreamGroup, request: &Request) -> Option<usize> {
+        match self.affinity {
+            Affinity::COOKIE => {
+                let value = request.cookie(&self.cookie_name)?;
+                (0..group.upstreams.len())
+                    .find(|&i| token(&group.upstreams[i].address) == *value)
+                    .filter(|&i| group.is_available(i))
+            }
+            Affinity::IP => rendezvous(group, &request.client()?.ip().to_string()),
+            Affinity::HEADER(ref h) => rendezvous(group, request.head(h)?),
+        }
+    }
+    /// `Set-Cookie` value pinning a client to an upstream, `None` unless the affinity
+    /// is a cookie the request does not already carry
+    pub fn cookie(&self, request: &Request, address: &str) -> Option<String> {
+        if self.affinity != Affinity::COOKIE {
+            return None;
+        }
+        let value = token(address);
+        if request.cookie(&self.cookie_name) == Some(&value) {
+            return None;
+        }
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            self.cookie_name, value, self.cookie_path
+        );
+        if let Some(age) = self.cookie_max_age {
+            cookie.push_str(&format!("; Max-Age={}", age.as_secs()));
+        }
+        Some(cookie)
+    }
+}
+
+/// affinity cookie value of an upstream, does not reveal its address
+pub fn token(address: &str) -> String {
+    format!("{:016x}", mix(fnv1a(address.as_bytes())))
+}
+
+/// weighted rendezvous hashing over the available upstreams, a key keeps its upstream
+/// while that one is available and only the keys of an unavailable upstream move
+///
+/// Example
+/// ```rust
+/// use humbird::core::{
+///     balancer::{HashKey, Upstream, UpstreamGroup},
+///     breaker::CircuitBreaker,
+///     proxy::BalancingMode,
+///     sticky::{rendezvous, token, Affinity},
+/// };
+/// assert_eq!(Affinity::new("header: X-User"), Some(Affinity::HEADER("X-User".to_string())));
+/// assert_ne!(token("10.0.0.1:80"), token("10.0.0.2:80"));
+/// let upstreams = vec![Upstream::new("10.0.0.1:80", 1), Upstream::new("10.0.0.2:80", 1)];
+/// let mut group = UpstreamGroup::new(BalancingMode::POLLING, upstreams, HashKey::PATH);
+/// let breaker = CircuitBreaker { failure_threshold: 1, ..Default::default() };
+/// group.circuit_breaker = Some(breaker.clone());
+/// let pinned = rendezvous(&group, "192.168.1.7").unwrap();
+/// assert_eq!(rendezvous(&group, "192.168.1.7"), Some(pinned));
+/// // a client pinned to an unavailable upstream is pinned to another one
+/// group.circuit(pinned).record(false, &breaker);
+/// assert_eq!(rendezvous(&group, "192.168.1.7"), Some(1 - pinned));
+/// ```
+pub fn rendezvous(group: &UpstreamGroup, key: &str) -> Option<usize> {
+    let score = |i: usize| {
+        let u = &group.upstreams[i];
+        let h = mix(fnv1a(format!("{}#{}", u.address, key).as_bytes()));
+        // uniform in (0, 1)
+        let r = (h >> 11) as f64 / (1u64 << 53) as f64 + f64::EPSILON;
+        -(u.weight as f64) / r.ln()
+    };
+    (0..group.upstreams.len())
+        .filter(|&i| group.is_available(i))
+        .max_by(|&a, &b| score(a).total_cmp(&score(b)))
+}
@@ -0,0 +1,282 @@
+/// layer 4 proxying, relays raw tcp connections and udp datagrams between a listen
+/// address and a balanced upstream group
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    core::balancer::{Lease, UpstreamGroup},
+    protocol::proxy_protocol::ProxyProtocol,
+};
+
+/// largest udp datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// default number of udp sessions open at once
+pub const DEFAULT_MAX_SESSIONS: usize = 1024;
+
+/// transport of a stream proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamProtocol {
+    #[default]
+    TCP,
+    UDP,
+}
+
+impl StreamProtocol {
+    pub fn new(m: &str) -> Self {
+        match m.trim().to_ascii_uppercase().as_str() {
+            "UDP" => StreamProtocol::UDP,
+            _ => StreamProtocol::TCP,
+        }
+    }
+}
+
+/// a listen address proxied to an upstream group
+#[derive(Debug, Clone)]
+pub struct StreamProxy {
+    /// `host:port` to listen on
+    pub listen: String,
+    pub protocol: StreamProtocol,
+    pub group: Arc<UpstreamGroup>,
+    /// PROXY protocol header sent to the upstream, version 1 is tcp only
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// time allowed to connect to an upstream
+    pub connect_timeout: Duration,
+    /// udp sessions without traffic for this long are closed
+    pub idle_timeout: Duration,
+    /// udp sessions open at once, datagrams of further clients are dropped
+    pub max_sessions: usize,
+    /// further upstreams tried when a tcp connection attempt fails
+    pub retries: u32,
+}
+
+impl StreamProxy {
+    pub fn new(listen: &str, protocol: StreamProtocol, group: Arc<UpstreamGroup>) -> Self {
+        StreamProxy {
+            listen: listen.to_string(),
+            protocol,
+            group,
+            proxy_protocol: None,
+            connect_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(60),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            retries: 1,
+        }
+    }
+    /// listen and relay until the task is aborted
+    pub async fn serve(self: Arc<Self>) {
+        let result = match self.protocol {
+            StreamProtocol::TCP => self.clone().serve_tcp().await,
+            StreamProtocol::UDP => self.clone().serve_udp().await,
+        };
+        if let Err(e) = result {
+            error!("stream proxy {} {:?} stopped: {}", self.listen, self.protocol, e);
+        }
+    }
+    async fn serve_tcp(self: Arc<Self>) -> Result<(), String> {
+        let listener = match TcpListener::bind(&self.listen).await {
+            Ok(l) => l,
+            Err(e) => return Err(format!("bind: {}", e)),
+        };
+        info!("stream proxy listening on tcp {}", self.listen);
+        loop {
+            match listener.accept().await {
+                Ok((client, peer)) => {
+                    let stream = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = stream.relay_tcp(client, peer).await {
+                            warn!("stream {} from {}: {}", stream.listen, peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("stream {} accept failed: {}", self.listen, e),
+            }
+        }
+    }
+    /// connect the client to an upstream and copy both directions until either side closes
+    async fn relay_tcp(&self, mut client: TcpStream, peer: SocketAddr) -> Result<(), String> {
+        let (lease, mut upstream) = self.connect(peer).await?;
+        if let Some(p) = self.proxy_protocol {
+            let local = match client.local_addr() {
+                Ok(a) => a,
+                Err(e) => return Err(format!("local address: {}", e)),
+            };
+            if let Err(e) = upstream.write_all(&p.header(peer, local, false)).await {
+                return Err(format!("send to {}: {}", lease.upstream().address, e));
+            }
+        }
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("relay with {}: {}", lease.upstream().address, e)),
+        }
+    }
+    /// connect to an upstream, trying others when an attempt fails
+    async fn connect(&self, peer: SocketAddr) -> Result<(Lease, TcpStream), String> {
+        let mut attempt = 0;
+        loop {
+            let lease = match self.group.acquire_for(peer) {
+                Some(l) => l,
+                None => return Err("no upstream available".to_string()),
+            };
+            let address = lease.upstream().address.clone();
+            let e = match tokio::time::timeout(self.connect_timeout, TcpStream::connect(&address)).await {
+                Ok(Ok(upstream)) => {
+                    lease.report(true);
+                    return Ok((lease, upstream));
+                }
+                Ok(Err(e)) => format!("connect {}: {}", address, e),
+                Err(_) => format!("connect {}: timed out after {:?}", address, self.connect_timeout),
+            };
+            lease.report(false);
+            if attempt >= self.retries {
+                return Err(e);
+            }
+            warn!("stream {} attempt {} failed, retrying: {}", self.listen, attempt + 1, e);
+            attempt += 1;
+        }
+    }
+    async fn serve_udp(self: Arc<Self>) -> Result<(), String> {
+        let socket = match UdpSocket::bind(&self.listen).await {
+            Ok(s) => Arc::new(s),
+            Err(e) => return Err(format!("bind: {}", e)),
+        };
+        let local = match socket.local_addr() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("local address: {}", e)),
+        };
+        info!("stream proxy listening on udp {}", self.listen);
+        let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>> = Arc::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        // set while new clients are refused, so that the limit is reported once
+        let mut full = false;
+        loop {
+            let (n, peer) = match socket.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("stream {} receive failed: {}", self.listen, e);
+                    continue;
+                }
+            };
+            let (existing, open) = match sessions.lock() {
+                Ok(s) => (s.get(&peer).cloned(), s.len()),
+                Err(_) => (None, 0),
+            };
+            let session = match existing {
+                Some(s) => s,
+                None if open >= self.max_sessions => {
+                    if !full {
+                        warn!("stream {} has {} udp sessions open, new clients are refused", self.listen, open);
+                        full = true;
+                    }
+                    continue;
+                }
+                None => match self.open_udp(peer, socket.clone(), sessions.clone()).await {
+                    Ok(s) => {
+                        full = false;
+                        s
+                    }
+                    Err(e) => {
+                        warn!("stream {} from {}: {}", self.listen, peer, e);
+                        continue;
+                    }
+                },
+            };
+            session.touch();
+            let sent = match self.proxy_protocol {
+                Some(p) => {
+                    let mut datagram = p.header(peer, local, true);
+                    datagram.extend_from_slice(&buf[..n]);
+                    session.upstream.send(&datagram).await
+                }
+                None => session.upstream.send(&buf[..n]).await,
+            };
+            if let Err(e) = sent {
+                warn!("stream {} send for {} failed: {}", self.listen, peer, e);
+            }
+        }
+    }
+    /// open the session of a new udp client, its replies are relayed by a task that
+    /// ends once the session has been idle for the idle timeout
+    async fn open_udp(
+        &self,
+        peer: SocketAddr,
+        socket: Arc<UdpSocket>,
+        sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>,
+    ) -> Result<Arc<UdpSession>, String> {
+        let lease = match self.group.acquire_for(peer) {
+            Some(l) => l,
+            None => return Err("no upstream available".to_string()),
+        };
+        let address = lease.upstream().address.clone();
+        let bind = if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" };
+        let upstream = match UdpSocket::bind(bind).await {
+            Ok(u) => u,
+            Err(e) => return Err(format!("bind: {}", e)),
+        };
+        if let Err(e) = upstream.connect(&address).await {
+            lease.report(false);
+            return Err(format!("connect {}: {}", address, e));
+        }
+        let session = Arc::new(UdpSession {
+            upstream,
+            last: Mutex::new(Instant::now()),
+        });
+        if let Ok(mut s) = sessions.lock() {
+            s.insert(peer, session.clone());
+        }
+        let idle_timeout = self.idle_timeout;
+        let reply = session.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                match tokio::time::timeout(idle_timeout, reply.upstream.recv(&mut buf)).await {
+                    Ok(Ok(n)) => {
+                        reply.touch();
+                        let _ = socket.send_to(&buf[..n], peer).await;
+                    }
+                    Ok(Err(e)) => {
+                        // an icmp port unreachable of the upstream
+                        lease.report(false);
+                        warn!("stream upstream {} for {}: {}", address, peer, e);
+                        break;
+                    }
+                    Err(_) if reply.idle() < idle_timeout => continue,
+                    Err(_) => break,
+                }
+            }
+            if let Ok(mut s) = sessions.lock() {
+                s.remove(&peer);
+            }
+        });
+        Ok(session)
+    }
+}
+
+/// the upstream socket of a udp client
+#[derive(Debug)]
+struct UdpSession {
+    upstream: UdpSocket,
+    last: Mutex<Instant>,
+}
+
+impl UdpSession {
+    fn touch(&self) {
+        if let Ok(mut l) = self.last.lock() {
+            *l = Instant::now();
+        }
+    }
+    fn idle(&self) -> Duration {
+        match self.last.lock() {
+            Ok(l) => l.elapsed(),
+            Err(_) => Duration::ZERO,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+/// name based virtual hosts, selected by the `Host` request header
+use std::sync::Arc;
+
+use crate::core::{
+    balancer::UpstreamGroup,
+    directory::Directory,
+    proxy::ProxyRoute,
+    settings::{self, Settings},
+};
+
+/// virtual host abstract
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    /// host names served by the virtual host, `*.example.com` matches any subdomain
+    pub server_names: Vec<String>,
+    /// static resource directories, the server wide ones are used when empty
+    pub directories: Vec<Directory>,
+    /// proxy target upstream group, the server wide one is used when absent
+    pub proxy: Option<Arc<UpstreamGroup>>,
+    /// proxy routes, the server wide ones are used when empty
+    pub proxy_routes: Vec<ProxyRoute>,
+}
+
+impl VirtualHost {
+    /// whether the virtual host serves the host name
+    pub fn matches(&self, host: &str) -> bool {
+        self.server_names.iter().any(|n| match n.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => n.eq_ignore_ascii_case(host),
+        })
+    }
+}
+
+/// host name of a `Host` header value, without port and in lower case
+pub fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        // ipv6 literal
+        match host.find(']') {
+            Some(i) => &host[..=i],
+            None => host,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((n, _)) => n,
+            None => host,
+        }
+    };
+    name.to_ascii_lowercase()
+}
+
+/// the virtual host of the settings serving a `Host` header value, the first match wins
+pub fn find(settings: &Settings, host: Option<&String>) -> Option<VirtualHost> {
+    let name = host_name(host?);
+    settings.virtual_hosts.iter().find(|h| h.matches(&name)).cloned()
+}
+
+/// static resource directories serving a `Host` header value
+pub fn directories(host: Option<&String>) -> Vec<Directory> {
+    let settings = settings::current();
+    match find(&settings, host) {
+        Some(h) if !h.directories.is_empty() => h.directories,
+        _ => settings.directories.clone(),
+    }
+}
@@ -0,0 +1,112 @@
+/// non-blocking response writer, drains buffered bytes and file bodies on writable events
+use std::{
+    fs,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use mio::net::TcpStream;
+
+/// static files at least this large are sent straight from the file descriptor
+pub const SENDFILE_MIN_SIZE: u64 = 64 * 1024;
+/// the largest number of file bytes moved by a single transfer call
+const TRANSFER_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// a response body that is still on disk
+#[derive(Debug, Clone)]
+pub struct FileBody {
+    /// opened file
+    pub file: Arc<fs::File>,
+    /// offset of the next byte to send
+    pub offset: u64,
+    /// number of bytes left to send
+    pub length: u64,
+}
+
+/// pending outbound data of a connection
+#[derive(Debug)]
+pub struct Outbound {
+    buf: Vec<u8>,
+    written: usize,
+    file: Option<FileBody>,
+}
+
+impl Outbound {
+    pub fn new(buf: Vec<u8>, file: Option<FileBody>) -> Self {
+        Outbound {
+            buf,
+            written: 0,
+            file,
+        }
+    }
+    /// write as much as the socket accepts without blocking,
+    /// returns true once everything has been sent
+    pub fn flush(&mut self, mut stream: &TcpStream) -> io::Result<bool> {
+        loop {
+            while self.written < self.buf.len() {
+                match stream.write(&self.buf[self.written..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.written += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            match self.file {
+                Some(ref f) if f.length > 0 => match self.transfer(stream) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(_) => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                },
+                _ => return Ok(true),
+            }
+        }
+    }
+    /// move the next chunk of the file body to the socket with sendfile(2)
+    #[cfg(target_os = "linux")]
+    fn transfer(&mut self, stream: &TcpStream) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        let body = match self.file.as_mut() {
+            Some(b) => b,
+            None => return Ok(0),
+        };
+        let mut offset = body.offset as libc::off_t;
+        let count = body.length.min(TRANSFER_CHUNK_SIZE) as usize;
+        let n = unsafe {
+            libc::sendfile(
+                stream.as_raw_fd(),
+                body.file.as_raw_fd(),
+                &mut offset,
+                count,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        body.offset += n as u64;
+        body.length -= n as u64;
+        Ok(n as usize)
+    }
+    /// read the next chunk of the file body into the buffer,
+    /// it is written out by the next round of `flush`
+    #[cfg(not(target_os = "linux"))]
+    fn transfer(&mut self, _stream: &TcpStream) -> io::Result<usize> {
+        use std::io::{Read, Seek, SeekFrom};
+        let body = match self.file.as_mut() {
+            Some(b) => b,
+            None => return Ok(0),
+        };
+        let count = body.length.min(TRANSFER_CHUNK_SIZE) as usize;
+        let mut f = &*body.file;
+        f.seek(SeekFrom::Start(body.offset))?;
+        self.buf.resize(count, 0);
+        let n = f.read(&mut self.buf)?;
+        self.buf.truncate(n);
+        self.written = 0;
+        body.offset += n as u64;
+        body.length -= n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,200 @@
+/// directory listing pages for static resource directories
+use std::{
+    cmp::Ordering,
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+
+use crate::core::directory::IndexSort;
+
+/// a directory listing entry
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// read the entries of a directory
+pub fn listing(dir: &Path, hide_dotfiles: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for e in fs::read_dir(dir)? {
+        let e = match e {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        let name = e.file_name().to_string_lossy().to_string();
+        if hide_dotfiles && name.starts_with('.') {
+            continue;
+        }
+        // follow symbolic links, broken ones are left out
+        let meta = match fs::metadata(e.path()) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified: meta.modified().unwrap_or(UNIX_EPOCH),
+        });
+    }
+    Ok(entries)
+}
+
+/// sort entries, directories always come first
+pub fn sort(entries: &mut [Entry], key: IndexSort, desc: bool) {
+    entries.sort_by(|a, b| {
+        if a.is_dir != b.is_dir {
+            return b.is_dir.cmp(&a.is_dir);
+        }
+        let o = match key {
+            IndexSort::NAME => Ordering::Equal,
+            IndexSort::SIZE => a.size.cmp(&b.size),
+            IndexSort::MTIME => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        if desc {
+            o.reverse()
+        } else {
+            o
+        }
+    });
+}
+
+/// render the listing as an html page
+pub fn html(request_path: &str, entries: &[Entry]) -> String {
+    let base = with_slash(request_path);
+    let title = html_escape(&base);
+    let mut h = String::default();
+    h.push_str(&format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<table>\n",
+        title, title
+    ));
+    h.push_str("<tr><th><a href=\"?sort=name\">Name</a></th><th><a href=\"?sort=size&order=desc\">Size</a></th><th><a href=\"?sort=mtime&order=desc\">Modified</a></th></tr>\n");
+    if let Some((parent, _)) = base.trim_end_matches('/').rsplit_once('/') {
+        h.push_str(&format!(
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+            url_encode(parent)
+        ));
+    }
+    for e in entries {
+        let suffix = if e.is_dir { "/" } else { "" };
+        h.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            url_encode(&base),
+            url_encode(&e.name),
+            suffix,
+            html_escape(&e.name),
+            suffix,
+            if e.is_dir {
+                "-".to_string()
+            } else {
+                e.size.to_string()
+            },
+            DateTime::<Local>::from(e.modified).format("%F %T")
+        ));
+    }
+    h.push_str("</table>\n</body>\n</html>\n");
+    h
+}
+
+/// render the listing as a json document
+pub fn json(request_path: &str, entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"mtime\":\"{}\"}}",
+                json_escape(&e.name),
+                if e.is_dir { "directory" } else { "file" },
+                e.size,
+                DateTime::<Utc>::from(e.modified).to_rfc3339_opts(SecondsFormat::Secs, true)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":\"{}\",\"entries\":[{}]}}",
+        json_escape(&with_slash(request_path)),
+        items.join(",")
+    )
+}
+
+fn with_slash(p: &str) -> String {
+    if p.ends_with('/') {
+        p.to_string()
+    } else {
+        format!("{}/", p)
+    }
+}
+
+/// escape text for html element content and attribute values
+pub fn html_escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r.push_str("&amp;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '"' => r.push_str("&quot;"),
+            '\'' => r.push_str("&#39;"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+/// escape text for a json string literal
+pub fn json_escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c if (c as u32) < 0x20 => r.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+/// percent-encode a path, `/` is kept as the segment separator
+pub fn url_encode(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                r.push(b as char)
+            }
+            _ => r.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    r
+}
+
+/// decode a percent-encoded path, invalid escapes are kept as they are
+pub fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut r: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                r.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        r.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&r).to_string()
+}
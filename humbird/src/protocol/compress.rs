@@ -0,0 +1,113 @@
+/// `Accept-Encoding` negotiation and body compression
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::core::compression::{Compression, Encoding};
+
+/// choose a coding from an `Accept-Encoding` header value, the client weight
+/// decides first and the server preference order breaks ties
+///
+/// Example
+/// ```rust
+/// use humbird::core::compression::{Encoding, Compression};
+/// use humbird::protocol::compress::negotiate;
+/// let algorithms = Compression::default().algorithms;
+/// assert_eq!(negotiate("gzip, br;q=0.5", &algorithms), Some(Encoding::GZIP));
+/// assert_eq!(negotiate("identity", &algorithms), None);
+/// ```
+pub fn negotiate(accept_encoding: &str, algorithms: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard: Option<f32> = None;
+    let mut weights: Vec<(Encoding, f32)> = vec![];
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = match parts.next() {
+            Some(n) => n.trim(),
+            None => continue,
+        };
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else if let Some(e) = Encoding::new(name) {
+            weights.push((e, q));
+        }
+    }
+    let mut best: Option<(Encoding, f32)> = None;
+    for e in algorithms {
+        let q = match weights.iter().find(|(w, _)| w == e) {
+            Some((_, q)) => *q,
+            None => match wildcard {
+                Some(q) => q,
+                None => continue,
+            },
+        };
+        if q <= 0.0 {
+            continue;
+        }
+        match best {
+            Some((_, b)) if b >= q => {}
+            _ => best = Some((*e, q)),
+        }
+    }
+    best.map(|(e, _)| e)
+}
+
+/// compress a body with a coding
+pub fn encode(body: &[u8], encoding: Encoding, level: Option<u32>) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::GZIP => {
+            let level = flate2::Compression::new(level.unwrap_or(6).min(9));
+            let mut e = flate2::write::GzEncoder::new(Vec::with_capacity(body.len() / 2), level);
+            e.write_all(body)?;
+            e.finish()
+        }
+        Encoding::DEFLATE => {
+            // the `deflate` coding is the zlib format
+            let level = flate2::Compression::new(level.unwrap_or(6).min(9));
+            let mut e = flate2::write::ZlibEncoder::new(Vec::with_capacity(body.len() / 2), level);
+            e.write_all(body)?;
+            e.finish()
+        }
+        Encoding::BROTLI => {
+            let mut out = Vec::with_capacity(body.len() / 2);
+            {
+                let mut e =
+                    brotli::CompressorWriter::new(&mut out, 4096, level.unwrap_or(5).min(11), 22);
+                e.write_all(body)?;
+            }
+            Ok(out)
+        }
+        Encoding::ZSTD => zstd::stream::encode_all(body, level.unwrap_or(3).min(22) as i32),
+    }
+}
+
+/// find a precompressed sibling of a static file accepted by the client
+pub fn precompressed(
+    path: &Path,
+    accept_encoding: &str,
+    compression: &Compression,
+) -> Option<(PathBuf, Encoding)> {
+    let available: Vec<Encoding> = compression
+        .algorithms
+        .iter()
+        .filter(|e| match e.extension() {
+            Some(ext) => sibling(path, ext).is_file(),
+            None => false,
+        })
+        .copied()
+        .collect();
+    let e = negotiate(accept_encoding, &available)?;
+    Some((sibling(path, e.extension()?), e))
+}
+
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".");
+    p.push(ext);
+    PathBuf::from(p)
+}
@@ -1,19 +1,39 @@
 use std::{
     collections::HashMap,
     fs,
-    io::{self, BufRead, Read, Write},
+    io::{self, BufRead, Read, Seek, SeekFrom},
+    net::SocketAddr,
     path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
+use chrono::{DateTime, Utc};
+
 use mio::{event::Event, net::TcpStream, Token};
 use regex::Regex;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
     net::tcp::OwnedReadHalf,
 };
 use tracing::{error, instrument};
 
-use crate::core::{plugins::ROUTER_TABLE, server::ROOT_PATH};
+use crate::{
+    core::{
+        access_log,
+        directory::{self, AutoIndex, Directory, IndexSort},
+        error_page,
+        forward_proxy::{self, ForwardProxy},
+        plugins,
+        proxy::{self, Forward},
+        settings, vhost,
+        writer::{FileBody, Outbound, SENDFILE_MIN_SIZE},
+    },
+    protocol::{
+        autoindex, compress, mime,
+        range::{self, ByteRange, RangeSpec},
+    },
+};
 
 /// http request process
 pub type HttpRequestProcess = fn(Request, Response) -> Response;
@@ -30,6 +50,13 @@ pub enum Delimiter {
 pub struct Http {
     pub request: Request,
     pub response: Response,
+    /// set when the request is forwarded by the reverse proxy instead of answered here
+    pub forward: Option<Forward>,
+    /// set when the request is relayed or tunneled to the target it names
+    pub forward_proxy: Option<Arc<ForwardProxy>>,
+    /// set when the body is still on the connection, the request is answered by a task
+    /// once its body has been read
+    pub unread_body: bool,
 }
 
 impl Http {
@@ -38,24 +65,76 @@ impl Http {
         event: &Event,
         m: &HashMap<Token, TcpStream>,
         token: &Token,
+        pending: &mut HashMap<Token, Outbound>,
+        client: Option<SocketAddr>,
     ) -> Result<Http, String> {
         match m.get(token) {
-            Some(mut stream) => {
+            Some(stream) => {
                 match Request::decode(stream) {
-                    Ok(request) => {
-                        let res = Response::new(&request);
+                    Ok(mut request) => {
+                        request.client = client;
+                        // forward proxy, the connection is handed over to the server
+                        if let Some(p) = forward_proxy::find(&request) {
+                            return Ok(Http {
+                                response: Response::blank(&request),
+                                request,
+                                forward: None,
+                                forward_proxy: Some(p),
+                                unread_body: false,
+                            });
+                        }
+                        // reverse proxy, the connection is handed over to the server
+                        let settings = settings::current();
+                        let vhost = vhost::find(&settings, request.head.get("Host"));
+                        let plugin = plugins::route(vhost.as_ref(), &request.path).is_some();
+                        if !plugin {
+                            if let Some(f) = proxy::find(&settings, vhost.as_ref(), &request) {
+                                return Ok(Http {
+                                    response: Response::blank(&request),
+                                    request,
+                                    forward: Some(f),
+                                    forward_proxy: None,
+                                    unread_body: false,
+                                });
+                            }
+                        }
+                        // a body still on the connection is read by a task before the request
+                        // is answered, the connection is handed over to the server
+                        if request.rest != BodyRest::Complete {
+                            return Ok(Http {
+                                response: Response::blank(&request),
+                                request,
+                                forward: None,
+                                forward_proxy: None,
+                                unread_body: true,
+                            });
+                        }
                         let mut http = Http {
-                            request: request,
-                            response: res,
+                            response: Http::answer(&request),
+                            request,
+                            forward: None,
+                            forward_proxy: None,
+                            unread_body: false,
                         };
-                        // exec plugin
-                        match http.router() {
-                            Ok(res) => http.response = res,
-                            Err(_) => {}
-                        }
                         // reponse
                         http.response.make_raw();
-                        let _ = stream.write_all(&http.response.raw[..]);
+                        let bytes = http.response.raw.len() as u64
+                            + http.response.file_body.as_ref().map_or(0, |f| f.length);
+                        access_log::log(&http.request, http.response.status_code(), Some(bytes), None);
+                        let mut outbound = Outbound::new(
+                            std::mem::take(&mut http.response.raw),
+                            http.response.file_body.take(),
+                        );
+                        match outbound.flush(stream) {
+                            Ok(true) => {}
+                            // the rest is sent on the following writable events
+                            Ok(false) => {
+                                pending.insert(*token, outbound);
+                            }
+                            Err(e) => {
+                                error!("http response writing failed: {}", e);
+                            }
+                        }
                         return Ok(http);
                     }
                     Err(_e) => {
@@ -71,24 +150,52 @@ impl Http {
     }
     // is http protocol
     pub fn is(c: String) -> bool {
-        let re = Regex::new(r"^(GET|HEAD|POST|PUT|DELETE|CONNECT|OPTIONS|TRACE)\s(([/0-9a-zA-Z.]+)?(\?[0-9a-zA-Z&=]+)?)\s(HTTP/1.0|HTTP/1.1|HTTP/2.0)\r\n$").unwrap();
+        let re = Regex::new(r"^(GET|HEAD|POST|PUT|DELETE|CONNECT|OPTIONS|TRACE|PURGE)\s(([/0-9a-zA-Z._~%:@\[\]-]+)?(\?[0-9a-zA-Z&=._~%+-]*)?)\s(HTTP/1.0|HTTP/1.1|HTTP/2.0)\r\n$").unwrap();
         re.is_match(&c)
     }
-    /// execute plugin
-    fn router(&mut self) -> Result<Response, ()> {
-        match ROUTER_TABLE.lock() {
-            Ok(t) => {
-                if t.len() > 0 && t.contains_key(&self.request.path) {
-                    Ok(t.get(&self.request.path).unwrap()(
-                        self.request.clone(),
-                        self.response.clone(),
-                    ))
-                } else {
-                    Err(())
-                }
+    /// answer a request whose body has been read, plugin handlers start from an empty 200
+    /// response and the static lookup only answers requests no plugin routes
+    fn answer(request: &Request) -> Response {
+        let vhost = vhost::find(&settings::current(), request.head.get("Host"));
+        let mut response = match plugins::route(vhost.as_ref(), &request.path) {
+            Some(process) => process(request.clone(), Response::blank(request)),
+            None => Response::new(request),
+        };
+        // custom error page
+        if response.error {
+            response = error_page::apply(request, response);
+        }
+        response.compress(request);
+        response
+    }
+    /// read the body of a request answered here once the connection is served by a task,
+    /// a body that is too large or unreadable is answered with its error, the client
+    /// connection is closed afterwards
+    pub async fn serve(mut request: Request, client: tokio::net::TcpStream) -> Result<(), String> {
+        let mut client = access_log::Counted::new(client);
+        let mut response = match request.read_body(&mut client).await {
+            Ok(_) => Http::answer(&request),
+            Err(code) => {
+                let mut response = Response::blank(&request);
+                response.error(code);
+                error_page::apply(&request, response)
+            }
+        };
+        response.set_head("Connection", "close");
+        response.make_raw();
+        let result = async {
+            client.write_all(&response.raw).await?;
+            if let Some(f) = response.file_body.take() {
+                let mut file = tokio::fs::File::from_std(f.file.try_clone()?);
+                file.seek(SeekFrom::Start(f.offset)).await?;
+                tokio::io::copy(&mut file.take(f.length), &mut client).await?;
             }
-            Err(_) => Err(()),
+            client.flush().await
         }
+        .await;
+        let _ = client.shutdown().await;
+        access_log::log(&request, response.status_code(), Some(client.written()), None);
+        result.map_err(|e| e.to_string())
     }
 }
 
@@ -104,6 +211,8 @@ pub enum Method {
     CONNECT,
     OPTIONS,
     TRACE,
+    /// removes the cached responses of a url
+    PURGE,
 }
 
 impl Method {
@@ -111,9 +220,41 @@ impl Method {
         match m {
             "GET" => Method::GET,
             "POST" => Method::POST,
+            "HEAD" => Method::HEAD,
+            "PUT" => Method::PUT,
+            "DELETE" => Method::DELETE,
+            "CONNECT" => Method::CONNECT,
+            "OPTIONS" => Method::OPTIONS,
+            "TRACE" => Method::TRACE,
+            "PURGE" => Method::PURGE,
             _ => Method::DEFAULT,
         }
     }
+    /// method token of the request line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET | Method::DEFAULT => "GET",
+            Method::POST => "POST",
+            Method::HEAD => "HEAD",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::CONNECT => "CONNECT",
+            Method::OPTIONS => "OPTIONS",
+            Method::TRACE => "TRACE",
+            Method::PURGE => "PURGE",
+        }
+    }
+    /// whether the request only reads the target, rfc 9110
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+    }
+    /// whether repeating the request has the same effect as sending it once, rfc 9110
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+        )
+    }
 }
 
 // generic request wrapper
@@ -121,7 +262,10 @@ impl Method {
 pub struct Request {
     method: Method,
     path: String,
+    query: String,
     protocol: String,
+    peer: Option<SocketAddr>,
+    client: Option<SocketAddr>,
     params: HashMap<String, String>,
     cookie: HashMap<String, String>,
     head: HashMap<String, String>,
@@ -129,6 +273,23 @@ pub struct Request {
     body: Vec<u8>,
     raw: Vec<u8>,
     file: Option<File>,
+    /// when the request line was read
+    received: Instant,
+    /// the part of the body not read yet
+    rest: BodyRest,
+}
+
+/// the part of a request body still on the connection once the head is decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BodyRest {
+    /// the body is complete
+    Complete,
+    /// bytes of a `Content-Length` body not received yet
+    Length(u64),
+    /// a chunked body, with the encoded bytes received so far
+    Chunked(Vec<u8>),
+    /// a body that is not read, the request is answered with the status code
+    Rejected(&'static str),
 }
 
 impl Request {
@@ -146,7 +307,10 @@ impl Request {
         let mut req = Request {
             method: Method::new(items[0]),
             path: items[1].to_string(),
+            query: String::default(),
             protocol: items[2].to_string().replace("\r\n", ""),
+            peer: stream.peer_addr().ok(),
+            client: None,
             params: HashMap::default(),
             cookie: HashMap::default(),
             head: HashMap::default(),
@@ -154,7 +318,10 @@ impl Request {
             body: vec![],
             raw: vec![],
             file: None,
+            received: Instant::now(),
+            rest: BodyRest::Complete,
         };
+        req.handle_params();
         loop {
             match delimiter {
                 Delimiter::HEAD => {
@@ -180,7 +347,8 @@ impl Request {
                     }
                 }
                 Delimiter::BODY => {
-                    if (|| -> bool {
+                    // multipart boundary of the content type
+                    let _ = (|| -> bool {
                         let ct: Vec<&str> = match req.head.get("Content-Type") {
                             Some(t) => t,
                             None => "",
@@ -219,55 +387,82 @@ impl Request {
                             None => return false,
                         };
                         return false;
-                    })() {
-                        // multipart data handle
-                        break;
-                    } else {
-                        match req.head.get("Content-Length") {
-                            Some(length_str) => {
-                                match length_str.parse::<u64>() {
-                                    Ok(length) => {
-                                        let mut buf = vec![
-                                            0u8;
-                                            match length.try_into() {
-                                                Ok(l) => l,
-                                                Err(_) => 0,
-                                            }
-                                        ];
-                                        match r_buf.read(&mut buf) {
-                                            Ok(0) => break,
-                                            Ok(_s) => {
-                                                // save request body
-                                                req.body = buf;
-                                                break;
-                                            }
-                                            Err(ref err)
-                                                if err.kind() == io::ErrorKind::WouldBlock =>
-                                            {
-                                                break
-                                            }
-                                            Err(ref err)
-                                                if err.kind() == io::ErrorKind::Interrupted =>
-                                            {
-                                                break
-                                            }
-                                            Err(_) => break,
-                                        }
-                                    }
-                                    Err(_) => break,
-                                };
-                            }
-                            None => break,
-                        }
-                    }
+                    })();
+                    // the part of the body that has arrived, the rest is read once the
+                    // connection is handed over to a task
+                    req.rest = req.read_available(&mut r_buf);
+                    break;
                 }
             }
         }
         Ok(req)
     }
+    /// read the part of the body that has arrived without waiting for the rest
+    fn read_available<R: BufRead>(&mut self, r_buf: &mut R) -> BodyRest {
+        let max = settings::current().max_body_size;
+        if let Some(te) = self.head.get("Transfer-Encoding") {
+            match te.rsplit(',').next() {
+                Some(c) if c.trim().eq_ignore_ascii_case("chunked") => {}
+                _ => return BodyRest::Rejected("501"),
+            }
+            let mut encoded = vec![];
+            let _ = r_buf.by_ref().take(max).read_to_end(&mut encoded);
+            return BodyRest::Chunked(encoded);
+        }
+        let length = match self.head.get("Content-Length").map(|l| l.trim().parse::<u64>()) {
+            Some(Ok(l)) => l,
+            Some(Err(_)) => return BodyRest::Rejected("400"),
+            None => return BodyRest::Complete,
+        };
+        if length > max {
+            return BodyRest::Rejected("413");
+        }
+        // the bytes read before the socket would block are kept
+        let _ = r_buf.by_ref().take(length).read_to_end(&mut self.body);
+        match length - self.body.len() as u64 {
+            0 => BodyRest::Complete,
+            n => BodyRest::Length(n),
+        }
+    }
+    /// read the rest of the body from the connection once it is served by a task, a chunked
+    /// body is decoded and sent on with a `Content-Length`, the error is the status code
+    /// answering the request
+    pub async fn read_body<R: AsyncRead + Unpin>(&mut self, r: &mut R) -> Result<(), &'static str> {
+        match std::mem::replace(&mut self.rest, BodyRest::Complete) {
+            BodyRest::Complete => Ok(()),
+            BodyRest::Rejected(code) => {
+                self.rest = BodyRest::Rejected(code);
+                Err(code)
+            }
+            BodyRest::Length(n) => {
+                let start = self.body.len();
+                self.body.resize(start + n as usize, 0);
+                match r.read_exact(&mut self.body[start..]).await {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("400"),
+                }
+            }
+            BodyRest::Chunked(encoded) => {
+                let max = settings::current().max_body_size;
+                let mut r_buf = BufReader::new(AsyncReadExt::chain(&encoded[..], r));
+                self.body = read_chunked(&mut r_buf, max).await?;
+                self.remove_head("Transfer-Encoding");
+                self.set_head("Content-Length", &self.body.len().to_string());
+                Ok(())
+            }
+        }
+    }
+    /// status code answering a request whose body is not read, e.g. 413 for a too large one
+    pub fn rejected(&self) -> Option<&'static str> {
+        match self.rest {
+            BodyRest::Rejected(code) => Some(code),
+            _ => None,
+        }
+    }
     pub fn append_head_info(&mut self, item: String) {
-        let item_split: Vec<&str> = item.split(":").collect();
-        if item_split.len() == 0 {
+        // header values such as dates and host ports contain colons
+        let item_split: Vec<&str> = item.splitn(2, ":").collect();
+        if item_split.len() < 2 {
             return;
         }
         let k = item_split[0].trim().to_string();
@@ -282,21 +477,74 @@ impl Request {
                 .collect(),
         );
         // cookies
-        if k.clone().eq("Cookie") {
-            let cookies: Vec<&str> = v.split(";").collect();
-            let _ = cookies.iter().map(|&e| {
-                let cookie_split: Vec<&str> = e.split("=").collect();
-                if cookie_split.len() > 0 {
+        if k.eq_ignore_ascii_case("Cookie") {
+            for e in v.split(';') {
+                if let Some((name, value)) = e.split_once('=') {
                     self.cookie
-                        .insert(cookie_split[0].to_owned(), cookie_split[1].to_owned());
+                        .insert(name.trim().to_owned(), value.trim().to_owned());
                 }
-            });
+            }
         }
     }
     /// request method
     pub fn method(&self) -> Method {
         self.method
     }
+    /// request path
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// request header value
+    pub fn head(&self, k: &str) -> Option<&String> {
+        self.head.get(k)
+    }
+    /// cookie value
+    pub fn cookie(&self, name: &str) -> Option<&String> {
+        self.cookie.get(name)
+    }
+    /// query string parameter value
+    pub fn param(&self, k: &str) -> Option<&String> {
+        self.params.get(k)
+    }
+    /// raw query string, without `?`
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+    /// request header information
+    pub fn heads(&self) -> &HashMap<String, String> {
+        &self.head
+    }
+    /// set a request header, replacing one of the same name whatever its case
+    pub fn set_head(&mut self, k: &str, v: &str) {
+        self.remove_head(k);
+        self.head.insert(k.to_string(), v.to_string());
+    }
+    /// remove a request header whatever the case of its name
+    pub fn remove_head(&mut self, k: &str) {
+        self.head.retain(|name, _| !name.eq_ignore_ascii_case(k));
+    }
+    /// request body
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+    /// address of the connected peer, a load balancer in front of the server
+    /// when the listener accepts the PROXY protocol
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+    /// address of the client, taken from the PROXY protocol header when the listener
+    /// accepts one, otherwise the peer address
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.client.or(self.peer)
+    }
+    /// when the request line was read
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+    /// request protocol version, `HTTP/1.1`
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
     /// convert request body structure to http protocol request structure string
     ///
     /// Example
@@ -312,19 +560,27 @@ impl Request {
     }
     /// determine whether it is an http request
     fn is(r: String) -> bool {
-        let re = Regex::new(r"^(GET|HEAD|POST|PUT|DELETE|CONNECT|OPTIONS|TRACE)\s(([/0-9a-zA-Z.]+)?(\?[0-9a-zA-Z&=]+)?)\s(HTTP/1.0|HTTP/1.1|HTTP/2.0)\r\n$").unwrap();
+        let re = Regex::new(r"^(GET|HEAD|POST|PUT|DELETE|CONNECT|OPTIONS|TRACE|PURGE)\s(([/0-9a-zA-Z._~%:@\[\]-]+)?(\?[0-9a-zA-Z&=._~%+-]*)?)\s(HTTP/1.0|HTTP/1.1|HTTP/2.0)\r\n$").unwrap();
         re.is_match(&r)
     }
-    /// request parameter handle
+    /// request parameter handle, splits the query string off the path
     fn handle_params(&mut self) {
-        let path_array: Vec<&str> = self.path.split("?").collect();
-        if path_array.len() > 0 {
-            let p: Vec<&str> = path_array[1].split("&").collect();
-            if p.len() > 0 {
-                let _ = p.iter().map(|&e| {
-                    let i: Vec<&str> = e.split("=").collect();
-                    self.params.insert(i[0].to_string(), i[1].to_string());
-                });
+        let (path, query) = match self.path.split_once("?") {
+            Some((p, q)) => (p.to_string(), q.to_string()),
+            None => return,
+        };
+        self.path = path;
+        self.query = query.clone();
+        for e in query.split("&") {
+            match e.split_once("=") {
+                Some((k, v)) => {
+                    self.params
+                        .insert(autoindex::url_decode(k), autoindex::url_decode(v));
+                }
+                None if !e.is_empty() => {
+                    self.params.insert(autoindex::url_decode(e), String::default());
+                }
+                None => {}
             }
         }
     }
@@ -337,9 +593,13 @@ pub struct Response {
     status_code: String,
     status_msg: String,
     head: HashMap<String, String>,
+    /// `Set-Cookie` lines sent besides the one of `head`, each cookie needs a line of its own
+    cookies: Vec<String>,
     body: Vec<u8>,
     content_length: u64,
     raw: Vec<u8>,
+    file_body: Option<FileBody>,
+    error: bool,
     req_method: Method,
     req_path: String,
 }
@@ -347,44 +607,257 @@ pub struct Response {
 impl Response {
     #[instrument]
     pub fn new(request: &Request) -> Self {
-        let mut response = Response {
+        let mut response = Response::blank(request);
+        // GET request default processing
+        if Method::GET.eq(&request.method) {
+            let directories = vhost::directories(request.head.get("Host"));
+            match directory::find(&directories, &request.path) {
+                // static resource
+                Some(d) => response.static_resource(request, d),
+                None => response.not_found(),
+            }
+        }
+        response
+    }
+    /// an empty 200 response to the request
+    pub fn blank(request: &Request) -> Self {
+        Response {
             protocol: String::default(),
-            status_code: String::default(),
-            status_msg: String::default(),
+            status_code: "200".to_string(),
+            status_msg: "OK".to_string(),
             head: HashMap::default(),
+            cookies: vec![],
             body: vec![],
             raw: vec![],
+            file_body: None,
+            error: false,
             req_method: request.method,
             req_path: String::default(),
             content_length: 0,
+        }
+    }
+    /// map the request path into a static resource directory
+    fn static_resource(&mut self, request: &Request, directory: &Directory) {
+        let r_path = autoindex::url_decode(&request.path);
+        let path = match directory
+            .relative(&r_path)
+            .and_then(|r| directory.local_path(r))
+        {
+            Some(p) => p,
+            None => return self.not_found(),
         };
-        // GET request default processing
-        if Method::GET.eq(&request.method) {
-            match ROOT_PATH.lock() {
-                Ok(p) => {
-                    let r_path = &request.path;
-                    let s_file = p.to_string() + &r_path[1..r_path.len()];
-                    // static file
-                    match fs::File::open(Path::new(&s_file)) {
-                        Ok(mut f) => match f.metadata() {
-                            Ok(meta) => {
-                                let mut sf_buf = vec![0u8; meta.len().try_into().unwrap()];
-                                let _ = f.read_to_end(&mut sf_buf);
-                                response.body = sf_buf;
-                            }
-                            Err(_) => {
-                                response.body = "<h1>404 Not Found</h1>".as_bytes().to_vec();
-                            }
-                        },
-                        Err(_) => {
-                            response.body = "<h1>404 Not Found</h1>".as_bytes().to_vec();
+        // single page application fallback
+        if !path.exists() && !directory.fallback.is_empty() {
+            if let Some(f) = directory.local_path(&directory.fallback) {
+                return self.static_file(request, &f);
+            }
+        }
+        if path.is_dir() {
+            let index = path.join("index.html");
+            if index.is_file() {
+                return self.static_file(request, &index);
+            }
+            match directory.autoindex {
+                AutoIndex::OFF => self.not_found(),
+                _ => self.autoindex(request, &path, directory),
+            }
+        } else {
+            self.static_file(request, &path);
+        }
+    }
+    /// respond with a directory listing
+    fn autoindex(&mut self, request: &Request, dir: &Path, directory: &Directory) {
+        let mut entries = match autoindex::listing(dir, directory.hide_dotfiles) {
+            Ok(e) => e,
+            Err(_) => return self.not_found(),
+        };
+        let sort = match request.params.get("sort") {
+            Some(s) => IndexSort::new(s),
+            None => directory.autoindex_sort,
+        };
+        let desc = match request.params.get("order") {
+            Some(o) => o.eq_ignore_ascii_case("desc"),
+            None => directory.autoindex_desc,
+        };
+        autoindex::sort(&mut entries, sort, desc);
+        let format = match request.params.get("format").map(|f| AutoIndex::new(f)) {
+            Some(AutoIndex::OFF) | None => directory.autoindex,
+            Some(f) => f,
+        };
+        match format {
+            AutoIndex::JSON => {
+                self.set_head("Content-Type", "application/json");
+                self.body = autoindex::json(&request.path, &entries).into_bytes();
+            }
+            _ => {
+                self.set_head("Content-Type", "text/html; charset=utf-8");
+                self.body = autoindex::html(&request.path, &entries).into_bytes();
+            }
+        }
+    }
+    /// respond with a local static file, honoring `Range` and `If-Range`
+    fn static_file(&mut self, request: &Request, path: &Path) {
+        // precompressed sibling
+        let compression = settings::current().compression.clone();
+        let mut file_path = path.to_path_buf();
+        if compression.precompressed {
+            let accept = request.head.get("Accept-Encoding").map_or("", |a| a.as_str());
+            if let Some((p, e)) = compress::precompressed(path, accept, &compression) {
+                file_path = p;
+                self.set_head("Content-Encoding", e.token());
+            }
+            self.vary("Accept-Encoding");
+        }
+        let mut f = match fs::File::open(&file_path) {
+            Ok(f) => f,
+            Err(_) => return self.not_found(),
+        };
+        let meta = match f.metadata() {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return self.not_found(),
+        };
+        let size = meta.len();
+        let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+        let etag = entity_tag(size, modified);
+        let last_modified = http_date(modified);
+        let content_type = mime::from_path(path);
+        self.set_head("Accept-Ranges", "bytes");
+        self.set_head("ETag", &etag);
+        self.set_head("Last-Modified", &last_modified);
+        let spec = match request.head.get("Range") {
+            Some(r) if range::if_range_matches(request.head.get("If-Range"), &etag, &last_modified) => {
+                range::parse(r, size)
+            }
+            _ => RangeSpec::Full,
+        };
+        match spec {
+            RangeSpec::Full => {
+                if size >= SENDFILE_MIN_SIZE {
+                    self.set_head("Content-Type", content_type);
+                    self.send_file(f, 0, size);
+                    return;
+                }
+                let mut sf_buf = Vec::with_capacity(size.try_into().unwrap_or(0));
+                match f.read_to_end(&mut sf_buf) {
+                    Ok(_) => {
+                        self.set_head("Content-Type", content_type);
+                        self.body = sf_buf;
+                    }
+                    Err(_) => self.not_found(),
+                }
+            }
+            RangeSpec::Partial(ranges) => {
+                let mut body: Vec<u8> = vec![];
+                if ranges.len() == 1 && ranges[0].length() >= SENDFILE_MIN_SIZE {
+                    self.set_head("Content-Type", content_type);
+                    self.set_head("Content-Range", &ranges[0].content_range(size));
+                    self.set_status("206", "Partial Content");
+                    self.send_file(f, ranges[0].start, ranges[0].length());
+                    return;
+                } else if ranges.len() == 1 {
+                    if read_range(&mut f, &ranges[0], &mut body).is_err() {
+                        return self.not_found();
+                    }
+                    self.set_head("Content-Type", content_type);
+                    self.set_head("Content-Range", &ranges[0].content_range(size));
+                } else {
+                    let boundary = range::boundary();
+                    for r in ranges.iter() {
+                        body.extend(range::part_head(&boundary, content_type, r, size).as_bytes());
+                        if read_range(&mut f, r, &mut body).is_err() {
+                            return self.not_found();
                         }
+                        body.extend(b"\r\n");
                     }
+                    body.extend(range::closing(&boundary).as_bytes());
+                    self.set_head(
+                        "Content-Type",
+                        &format!("multipart/byteranges; boundary={}", boundary),
+                    );
                 }
-                Err(_e) => {}
+                self.set_status("206", "Partial Content");
+                self.body = body;
+            }
+            RangeSpec::Unsatisfiable => {
+                self.set_status("416", "Range Not Satisfiable");
+                self.set_head("Content-Range", &format!("bytes */{}", size));
+                self.body = vec![];
             }
         }
-        response
+    }
+    /// send part of a file straight from its descriptor instead of the body buffer
+    fn send_file(&mut self, f: fs::File, offset: u64, length: u64) {
+        self.body = vec![];
+        self.file_body = Some(FileBody {
+            file: Arc::new(f),
+            offset,
+            length,
+        });
+    }
+    /// compress an in-memory body negotiated by `Accept-Encoding`
+    fn compress(&mut self, request: &Request) {
+        let compression = settings::current().compression.clone();
+        if !compression.enable
+            || self.file_body.is_some()
+            || self.body.len() < compression.min_size
+            || self.status_code == "206"
+            || self.head.contains_key("Content-Encoding")
+        {
+            return;
+        }
+        match self.head.get("Content-Type") {
+            Some(t) if compression.compressible(t) => {}
+            _ => return,
+        }
+        self.vary("Accept-Encoding");
+        let encoding = match request.head.get("Accept-Encoding") {
+            Some(a) => match compress::negotiate(a, &compression.algorithms) {
+                Some(e) => e,
+                None => return,
+            },
+            None => return,
+        };
+        match compress::encode(&self.body, encoding, compression.level) {
+            Ok(body) if body.len() < self.body.len() => {
+                self.body = body;
+                self.set_head("Content-Encoding", encoding.token());
+                // the compressed representation needs its own entity tag
+                if let Some(etag) = self.head.get("ETag") {
+                    let etag = format!("{}-{}\"", etag.trim_end_matches('"'), encoding.token());
+                    self.set_head("ETag", &etag);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("response compression failed: {}", e),
+        }
+    }
+    /// add a request header name to `Vary`
+    fn vary(&mut self, name: &str) {
+        match self.head.get_mut("Vary") {
+            Some(v) => {
+                if !v.split(',').any(|n| n.trim().eq_ignore_ascii_case(name)) {
+                    v.push_str(", ");
+                    v.push_str(name);
+                }
+            }
+            None => {
+                self.head.insert("Vary".to_string(), name.to_string());
+            }
+        }
+    }
+    /// default not found response
+    fn not_found(&mut self) {
+        self.error("404");
+    }
+    /// turn the response into an error response with a default body,
+    /// replaced by the configured error page of the status code if there is one
+    pub fn error(&mut self, code: &str) {
+        let msg = reason_phrase(code);
+        self.set_status(code, msg);
+        self.head.clear();
+        self.file_body = None;
+        self.body = format!("<h1>{} {}</h1>", code, msg).into_bytes();
+        self.error = true;
     }
     /// async decode
     #[instrument]
@@ -406,8 +879,11 @@ impl Response {
                 None => "".to_string(),
             },
             head: HashMap::default(),
+            cookies: vec![],
             body: vec![],
             raw: vec![],
+            file_body: None,
+            error: false,
             req_method: Method::DEFAULT,
             req_path: String::default(),
             content_length: 0,
@@ -466,10 +942,14 @@ impl Response {
         }
         Ok(response)
     }
-    fn make_raw(&mut self) {
+    pub(crate) fn make_raw(&mut self) {
         // init content length
+        let length = match self.file_body {
+            Some(ref f) => f.length,
+            None => self.body.len() as u64,
+        };
         self.head
-            .insert("Content-Length".to_string(), self.body.len().to_string());
+            .insert("Content-Length".to_string(), length.to_string());
         // raw data
         let mut raw_data: Vec<u8> = vec![];
         // head
@@ -480,6 +960,9 @@ impl Response {
         for (k, v) in self.head.iter_mut() {
             h.push_str(&format!("{}:{} \r\n", k, v));
         }
+        for c in self.cookies.iter() {
+            h.push_str(&format!("Set-Cookie:{} \r\n", c));
+        }
         // delimiter
         h.push_str("\r\n");
         raw_data.extend(h.as_bytes().to_vec().iter());
@@ -489,8 +972,8 @@ impl Response {
     }
     /// record response header information
     pub fn append_head_info(&mut self, item: String) {
-        let item_split: Vec<&str> = item.split(":").collect();
-        if item_split.len() == 0 {
+        let item_split: Vec<&str> = item.splitn(2, ":").collect();
+        if item_split.len() < 2 {
             return;
         }
         let k = item_split[0].trim().to_string();
@@ -501,6 +984,9 @@ impl Response {
                 Err(_) => 0,
             };
         }
+        if k.eq_ignore_ascii_case("Set-Cookie") {
+            return self.add_cookie(&v);
+        }
         self.head.insert(
             k,
             v.trim()
@@ -511,14 +997,60 @@ impl Response {
                 .collect(),
         );
     }
-    /// set response body
+    /// set response status
+    pub fn set_status(&mut self, code: &str, msg: &str) {
+        self.status_code = code.to_string();
+        self.status_msg = msg.to_string();
+    }
+    /// response header value
+    pub fn head(&self, k: &str) -> Option<&String> {
+        self.head.get(k)
+    }
+    /// set response header
+    pub fn set_head(&mut self, k: &str, v: &str) {
+        self.head.insert(k.to_string(), v.to_string());
+    }
+    /// add a `Set-Cookie` line, the ones already set are kept
+    pub fn add_cookie(&mut self, cookie: &str) {
+        match self.head.keys().any(|k| k.eq_ignore_ascii_case("Set-Cookie")) {
+            true => self.cookies.push(cookie.to_string()),
+            false => self.set_head("Set-Cookie", cookie),
+        }
+    }
+    /// response status code
+    pub fn status_code(&self) -> &str {
+        &self.status_code
+    }
+    /// set response body, an error response becomes a 200 one, set the status
+    /// afterwards to answer an error with a body of its own
     pub fn set_body(&mut self, body: &str) {
-        self.body = body.as_bytes().to_vec();
+        self.set_body_bytes(body.as_bytes().to_vec());
     }
-    /// append head information
+    /// set binary response body, an error response becomes a 200 one
+    pub fn set_body_bytes(&mut self, body: Vec<u8>) {
+        self.clear_error();
+        self.file_body = None;
+        self.body = body;
+    }
+    /// append head information, to an empty 200 response when it is an error response
     pub fn append_body(&mut self, body: &str) {
+        self.clear_error();
         self.body.extend(body.as_bytes().to_vec());
     }
+    /// mark an error response as answered by its error page, its body can then be
+    /// replaced while it keeps its status
+    pub(crate) fn error_handled(&mut self) {
+        self.error = false;
+    }
+    /// drop the default body and status of an error response, which no longer gets an error page
+    fn clear_error(&mut self) {
+        if self.error {
+            self.error = false;
+            self.set_status("200", "OK");
+            self.file_body = None;
+            self.body = vec![];
+        }
+    }
     /// raw data
     pub fn raw(&mut self) -> Vec<u8> {
         self.raw.clone()
@@ -543,3 +1075,103 @@ struct File {
 }
 
 impl File {}
+
+/// reason phrase of a status code
+pub fn reason_phrase(code: &str) -> &'static str {
+    match code {
+        "200" => "OK",
+        "204" => "No Content",
+        "206" => "Partial Content",
+        "301" => "Moved Permanently",
+        "302" => "Found",
+        "304" => "Not Modified",
+        "307" => "Temporary Redirect",
+        "308" => "Permanent Redirect",
+        "400" => "Bad Request",
+        "401" => "Unauthorized",
+        "403" => "Forbidden",
+        "404" => "Not Found",
+        "405" => "Method Not Allowed",
+        "407" => "Proxy Authentication Required",
+        "413" => "Content Too Large",
+        "416" => "Range Not Satisfiable",
+        "500" => "Internal Server Error",
+        "501" => "Not Implemented",
+        "502" => "Bad Gateway",
+        "503" => "Service Unavailable",
+        "504" => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// decode a chunked request body of at most `max` bytes, trailer fields are dropped
+async fn read_chunked<R: AsyncBufRead + Unpin>(r_buf: &mut R, max: u64) -> Result<Vec<u8>, &'static str> {
+    let mut body = vec![];
+    loop {
+        let mut line = String::default();
+        match r_buf.read_line(&mut line).await {
+            Ok(n) if n > 0 => {}
+            _ => return Err("400"),
+        }
+        let size = match u64::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16) {
+            Ok(s) => s,
+            Err(_) => return Err("400"),
+        };
+        if size == 0 {
+            loop {
+                let mut line = String::default();
+                match r_buf.read_line(&mut line).await {
+                    Ok(n) if n > 0 && line.trim_end().is_empty() => return Ok(body),
+                    Ok(n) if n > 0 => {}
+                    _ => return Err("400"),
+                }
+            }
+        }
+        if body.len() as u64 + size > max {
+            return Err("413");
+        }
+        // chunk data and its line break
+        let start = body.len();
+        body.resize(start + size as usize + 2, 0);
+        if r_buf.read_exact(&mut body[start..]).await.is_err() || !body.ends_with(b"\r\n") {
+            return Err("400");
+        }
+        body.truncate(start + size as usize);
+    }
+}
+
+/// read the bytes of a range from a file into the buffer
+fn read_range(f: &mut fs::File, r: &ByteRange, buf: &mut Vec<u8>) -> io::Result<()> {
+    f.seek(SeekFrom::Start(r.start))?;
+    let offset = buf.len();
+    buf.resize(offset + r.length() as usize, 0);
+    f.read_exact(&mut buf[offset..])
+}
+
+/// strong entity tag derived from the file size and modification time
+pub fn entity_tag(size: u64, modified: SystemTime) -> String {
+    let mtime = match modified.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+    format!("\"{:x}-{:x}\"", mtime, size)
+}
+
+/// format a time as an http date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(t: SystemTime) -> String {
+    DateTime::<Utc>::from(t)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// parse an http date, `None` when it is invalid
+///
+/// Example
+/// ```rust
+/// use humbird::protocol::http::{http_date, parse_http_date};
+/// let t = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+/// assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(s.trim()).ok().map(SystemTime::from)
+}
@@ -1 +1,6 @@
-pub mod http;
\ No newline at end of file
+pub mod autoindex;
+pub mod compress;
+pub mod http;
+pub mod mime;
+pub mod proxy_protocol;
+pub mod range;
\ No newline at end of file
@@ -0,0 +1,221 @@
+/// PROXY protocol headers, which pass the client address over a proxied connection
+use std::net::SocketAddr;
+
+/// signature opening a version 2 header
+pub const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// PROXY protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// human readable, stream transports only
+    V1,
+    /// binary
+    V2,
+}
+
+impl ProxyProtocol {
+    pub fn new(m: &str) -> Option<Self> {
+        match m.trim().to_ascii_lowercase().as_str() {
+            "v1" | "1" => Some(ProxyProtocol::V1),
+            "v2" | "2" => Some(ProxyProtocol::V2),
+            _ => None,
+        }
+    }
+    /// header announcing a connection from `source` to `destination`, `datagram`
+    /// selects the udp transport of version 2
+    pub fn header(&self, source: SocketAddr, destination: SocketAddr, datagram: bool) -> Vec<u8> {
+        match self {
+            ProxyProtocol::V1 => v1(source, destination),
+            ProxyProtocol::V2 => v2(source, destination, datagram),
+        }
+    }
+}
+
+/// version 1 header, `UNKNOWN` when the addresses are of different families
+pub fn v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let family = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+/// version 2 header, a `LOCAL` one when the addresses are of different families
+pub fn v2(source: SocketAddr, destination: SocketAddr, datagram: bool) -> Vec<u8> {
+    let transport = if datagram { 0x02 } else { 0x01 };
+    let mut header = V2_SIGNATURE.to_vec();
+    match (source, destination) {
+        (SocketAddr::V4(s), SocketAddr::V4(d)) => {
+            header.extend([0x21, 0x10 | transport]);
+            header.extend(12u16.to_be_bytes());
+            header.extend(s.ip().octets());
+            header.extend(d.ip().octets());
+        }
+        (SocketAddr::V6(s), SocketAddr::V6(d)) => {
+            header.extend([0x21, 0x20 | transport]);
+            header.extend(36u16.to_be_bytes());
+            header.extend(s.ip().octets());
+            header.extend(d.ip().octets());
+        }
+        _ => {
+            header.extend([0x20, 0x00, 0x00, 0x00]);
+            return header;
+        }
+    }
+    header.extend(source.port().to_be_bytes());
+    header.extend(destination.port().to_be_bytes());
+    header
+}
+
+/// longest version 1 header, which also holds the fixed part of a version 2 one
+pub const V1_MAX_LENGTH: usize = 107;
+
+/// a parsed PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: ProxyProtocol,
+    /// address of the client, `None` for `LOCAL` and `UNKNOWN` connections
+    pub source: Option<SocketAddr>,
+    /// address the client connected to
+    pub destination: Option<SocketAddr>,
+    /// bytes taken by the header
+    pub length: usize,
+}
+
+/// parse the header opening a connection, `Ok(None)` while more bytes are needed
+///
+/// Example
+/// ```rust
+/// use humbird::protocol::proxy_protocol::parse;
+/// let h = parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /").unwrap().unwrap();
+/// assert_eq!(h.source, Some("192.0.2.1:56324".parse().unwrap()));
+/// assert_eq!(h.length, 42);
+/// assert_eq!(parse(b"PROXY TCP4 192.0").unwrap(), None);
+/// assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+/// ```
+pub fn parse(buf: &[u8]) -> Result<Option<Header>, String> {
+    if buf.len() < V2_SIGNATURE.len() && V2_SIGNATURE.starts_with(buf) {
+        return Ok(None);
+    }
+    if buf.starts_with(&V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+    if buf.len() < 6 && b"PROXY ".starts_with(buf) {
+        return Ok(None);
+    }
+    if buf.starts_with(b"PROXY ") {
+        return parse_v1(buf);
+    }
+    Err("missing PROXY protocol header".to_string())
+}
+
+/// length a version 2 header opening the buffer declares, `None` for a version 1 header
+/// or until the fixed part of the header has arrived
+///
+/// Example
+/// ```rust
+/// use humbird::protocol::proxy_protocol::{v2, v2_length};
+/// let header = v2("192.0.2.1:56324".parse().unwrap(), "192.0.2.2:443".parse().unwrap(), false);
+/// assert_eq!(v2_length(&header[..16]), Some(28));
+/// assert_eq!(v2_length(&header[..15]), None);
+/// assert_eq!(v2_length(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"), None);
+/// ```
+pub fn v2_length(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 16 || !buf.starts_with(&V2_SIGNATURE) {
+        return None;
+    }
+    Some(16 + u16::from_be_bytes([buf[14], buf[15]]) as usize)
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, String> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(e) => e,
+        None if buf.len() < V1_MAX_LENGTH => return Ok(None),
+        None => return Err("PROXY v1 header too long".to_string()),
+    };
+    let line = match std::str::from_utf8(&buf[..end]) {
+        Ok(l) => l,
+        Err(_) => return Err("PROXY v1 header is not text".to_string()),
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let mut header = Header {
+        version: ProxyProtocol::V1,
+        source: None,
+        destination: None,
+        length: end + 2,
+    };
+    match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let address = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+                match (ip.parse(), port.parse()) {
+                    (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+                    _ => Err(format!("invalid PROXY v1 address {} {}", ip, port)),
+                }
+            };
+            header.source = Some(address(fields[2], fields[4])?);
+            header.destination = Some(address(fields[3], fields[5])?);
+            Ok(Some(header))
+        }
+        Some(&"UNKNOWN") => Ok(Some(header)),
+        _ => Err(format!("invalid PROXY v1 header {:?}", line)),
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, String> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(format!("unsupported PROXY protocol version {}", buf[12] >> 4));
+    }
+    let length = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let mut header = Header {
+        version: ProxyProtocol::V2,
+        source: None,
+        destination: None,
+        length,
+    };
+    match buf[12] & 0x0F {
+        // LOCAL, a connection of the proxy itself such as a health check
+        0x00 => return Ok(Some(header)),
+        0x01 => {}
+        c => return Err(format!("unsupported PROXY v2 command {}", c)),
+    }
+    let a = &buf[16..length];
+    match buf[13] >> 4 {
+        0x1 if a.len() >= 12 => {
+            let ip = |o: usize| std::net::Ipv4Addr::new(a[o], a[o + 1], a[o + 2], a[o + 3]);
+            header.source = Some(SocketAddr::new(ip(0).into(), u16::from_be_bytes([a[8], a[9]])));
+            header.destination =
+                Some(SocketAddr::new(ip(4).into(), u16::from_be_bytes([a[10], a[11]])));
+        }
+        0x2 if a.len() >= 36 => {
+            let ip = |o: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&a[o..o + 16]);
+                std::net::Ipv6Addr::from(octets)
+            };
+            header.source = Some(SocketAddr::new(ip(0).into(), u16::from_be_bytes([a[32], a[33]])));
+            header.destination =
+                Some(SocketAddr::new(ip(16).into(), u16::from_be_bytes([a[34], a[35]])));
+        }
+        // unix sockets and unspecified families carry no usable address
+        0x0 | 0x3 => {}
+        _ => return Err("truncated PROXY v2 addresses".to_string()),
+    }
+    Ok(Some(header))
+}
@@ -0,0 +1,164 @@
+/// http range request module, handles the `Range` and `If-Range` request headers
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the maximum number of ranges accepted in a single request
+pub const MAX_RANGE_COUNT: usize = 16;
+/// the largest number of bytes covered by the ranges of a `multipart/byteranges` response,
+/// its parts are buffered, requests covering more get the whole resource
+pub const MAX_MULTIPART_SIZE: u64 = 1024 * 1024;
+
+/// a satisfiable byte range, both ends inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// number of bytes covered by the range
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+    /// `Content-Range` header value of the range
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// result of evaluating a `Range` header against a resource
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// the header is absent, malformed or uses an unknown unit, serve the whole resource
+    Full,
+    /// one or more satisfiable ranges, sorted and coalesced
+    Partial(Vec<ByteRange>),
+    /// no range overlaps the resource, respond with 416
+    Unsatisfiable,
+}
+
+/// parse a `Range` header value for a resource of `size` bytes
+///
+/// Example
+/// ```rust
+/// use humbird::protocol::range::{parse, ByteRange, RangeSpec};
+/// assert_eq!(
+///     parse("bytes=0-99", 1000),
+///     RangeSpec::Partial(vec![ByteRange { start: 0, end: 99 }])
+/// );
+/// // several ranges covering most of a large file are not buffered
+/// assert_eq!(parse("bytes=0-0,2-", 64 * 1024 * 1024), RangeSpec::Full);
+/// ```
+pub fn parse(value: &str, size: u64) -> RangeSpec {
+    let specs = match value.trim().strip_prefix("bytes=") {
+        Some(s) => s,
+        None => return RangeSpec::Full,
+    };
+    let mut ranges: Vec<ByteRange> = vec![];
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some(p) => (p.0.trim(), p.1.trim()),
+            None => return RangeSpec::Full,
+        };
+        let range = if first.is_empty() {
+            // suffix range, the last n bytes
+            let n = match last.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => return RangeSpec::Full,
+            };
+            if n == 0 || size == 0 {
+                continue;
+            }
+            ByteRange {
+                start: size.saturating_sub(n),
+                end: size - 1,
+            }
+        } else {
+            let start = match first.parse::<u64>() {
+                Ok(s) => s,
+                Err(_) => return RangeSpec::Full,
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(e) if e >= start => e,
+                    _ => return RangeSpec::Full,
+                }
+            };
+            if start >= size {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(size - 1),
+            }
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() {
+        return RangeSpec::Unsatisfiable;
+    }
+    // coalesce overlapping and adjacent ranges
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = vec![];
+    for r in ranges {
+        match merged.last_mut() {
+            Some(m) if r.start <= m.end.saturating_add(1) => m.end = m.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    if merged.len() > MAX_RANGE_COUNT {
+        return RangeSpec::Full;
+    }
+    if merged.len() > 1 && merged.iter().map(|r| r.length()).sum::<u64>() > MAX_MULTIPART_SIZE {
+        return RangeSpec::Full;
+    }
+    RangeSpec::Partial(merged)
+}
+
+/// evaluate an `If-Range` precondition, the range is honored only when the
+/// validator still matches the current representation
+pub fn if_range_matches(if_range: Option<&String>, etag: &str, last_modified: &str) -> bool {
+    match if_range {
+        Some(v) => {
+            let v = v.trim();
+            if v.starts_with('"') {
+                // strong comparison, weak entity tags never match
+                v == etag
+            } else if v.starts_with("W/") {
+                false
+            } else {
+                v == last_modified
+            }
+        }
+        None => true,
+    }
+}
+
+/// generate a boundary for `multipart/byteranges` responses
+pub fn boundary() -> String {
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos(),
+        Err(_) => 0,
+    };
+    format!("humbird_{:x}", nanos)
+}
+
+/// part header of a `multipart/byteranges` body
+pub fn part_head(boundary: &str, content_type: &str, range: &ByteRange, size: u64) -> String {
+    format!(
+        "--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+        boundary,
+        content_type,
+        range.content_range(size)
+    )
+}
+
+/// closing delimiter of a `multipart/byteranges` body
+pub fn closing(boundary: &str) -> String {
+    format!("--{}--\r\n", boundary)
+}